@@ -0,0 +1,39 @@
+//! Runs a signaling server for p2pvr on its own, `p2pvr-signaling [port]`.
+//! Point clients at it with `--signaling ws://<this machine>:<port>`.
+
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
+use p2pvr::networking::signaling::{
+    LocalSignalingServer, SignalingServerPlugin, DEFAULT_SIGNALING_PORT,
+};
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "usage: p2pvr-signaling [port]";
+
+fn main() -> ExitCode {
+    let port = match std::env::args().nth(1) {
+        Some(port) => match port.parse() {
+            Ok(port) => port,
+            Err(_) => {
+                eprintln!("not a port: {port}\n{USAGE}");
+                return ExitCode::from(2);
+            }
+        },
+        None => DEFAULT_SIGNALING_PORT,
+    };
+    let mut app = App::new();
+    app.add_plugins((
+        // the server runs on its own task, the app only has to stay alive
+        MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_millis(100))),
+        LogPlugin::default(),
+        SignalingServerPlugin { port },
+    ));
+    // the plugin already said why
+    if !app.world().contains_resource::<LocalSignalingServer>() {
+        return ExitCode::FAILURE;
+    }
+    app.run();
+    ExitCode::SUCCESS
+}
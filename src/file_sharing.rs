@@ -1,9 +1,16 @@
+use crate::networking::index::{PeerIndex, PlayerIndex};
+use crate::networking::codec::MessageKind;
+use crate::networking::connection::Departed;
+use crate::networking::handshake::HandshakeComplete;
+use crate::networking::rpc::{Rpc, RpcAppExt, RpcDispatch, RpcRequest, RpcResult, Rpcs};
+use crate::networking::transport::NetworkSocket;
 use crate::networking::{ExternalPlayer, Message, PlayerUuid, SocketSendMessage};
 use bevy::asset::io::embedded::EmbeddedAssetRegistry;
 use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
 use bevy_vrm::loader::Vrm;
 use serde::{Deserialize, Serialize};
-use std::ops::{Deref, DerefMut};
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use bevy::asset::AsyncReadExt;
 use bevy::asset::io::ErasedAssetReader;
@@ -11,8 +18,6 @@ use bevy::tasks::futures_lite::AsyncRead;
 use bevy_blob_loader::path::deserialize_path;
 use bevy_health_bar3d::plugin::HealthBarPlugin;
 use bevy_health_bar3d::prelude::Percentage;
-use bevy_matchbox::MatchboxSocket;
-use bevy_matchbox::prelude::MultipleChannels;
 use futures::channel::mpsc::{channel, Receiver, SendError, Sender};
 use futures::SinkExt;
 use unavi_player::LocalPlayer;
@@ -31,12 +36,22 @@ impl Plugin for FileSharingPlugin {
         app.add_plugins(HealthBarPlugin::<LoadingBar>::default());
         app.add_event::<NewLocalAvatar>();
         app.add_event::<AvatarPartEnum>();
+        app.add_rpc::<GetAvatar>();
+        app.init_resource::<AvatarUploads>();
+        app.init_resource::<AvatarDownloads>();
         app.add_systems(Update, read_dropped_files);
         app.add_systems(Update, set_local_avatar);
-        app.add_systems(Update, handle_avatar_part);
+        app.add_systems(Update, handle_avatar_part.after(handle_avatar_answers));
         app.add_systems(Startup, setup);
         app.add_systems(Update, other_system);
         app.add_systems(Update, loading_bar_handler);
+        app.add_systems(
+            Update,
+            (
+                request_avatars,
+                (answer_avatar_requests, handle_avatar_answers).after(RpcDispatch),
+            ),
+        );
         app.add_systems(Startup, || {
             prevent_default_drop().unwrap();
         });
@@ -64,29 +79,129 @@ fn prevent_default_drop() -> Result<(), JsValue> {
     Ok(())
 }
 
+/// Tells apart the uploads of the same avatar, a peer that joins while it
+/// is being sent to everyone gets its own copy on top of the rest of that.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UploadId(u64);
+
+#[cfg(test)]
+impl UploadId {
+    pub(crate) fn new(id: u64) -> Self {
+        Self(id)
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AvatarPart {
     uuid: PlayerUuid,
+    upload: UploadId,
     avatar_name: String,
     data: Vec<u8>,
 }
 
+impl AvatarPart {
+    pub fn new(uuid: PlayerUuid, upload: UploadId, avatar_name: String, data: Vec<u8>) -> Self {
+        Self {
+            uuid,
+            upload,
+            avatar_name,
+            data,
+        }
+    }
+
+    pub fn uuid(&self) -> &PlayerUuid {
+        &self.uuid
+    }
+}
+
 #[derive(Event, Clone, Debug, Serialize, Deserialize)]
 pub enum AvatarPartEnum {
-    Len(PlayerUuid, usize),
+    /// A new avatar on its way to everyone, with its length in bytes.
+    Len(PlayerUuid, UploadId, usize),
     AvatarPart(AvatarPart),
-    Done,
+    /// Whose avatar is complete, several can be on their way at once.
+    Done(PlayerUuid, UploadId),
 }
 
+/// Asks a peer for the avatar they dropped in, so players who join later see
+/// it too. The answer is the upload it comes in and its length in bytes, if
+/// they have one, and the avatar itself follows as `AvatarPart`s.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GetAvatar;
 
-struct Thing(pub Vec<u8>, pub PlayerUuid);
+impl Rpc for GetAvatar {
+    type Response = Option<(UploadId, usize)>;
+    const NAME: &'static str = "get_avatar";
+}
 
-impl Default for Thing {
-    fn default() -> Self {
-        Thing {
-            0: vec![],
-            1: PlayerUuid("".to_string()),
+struct AvatarDownload {
+    /// Parts of any other upload are left out.
+    upload: UploadId,
+    /// Bytes they announced, parts past it are dropped.
+    len: usize,
+    data: Vec<u8>,
+}
+
+/// Avatars of other players still on their way to us. Parts only go
+/// somewhere once their length was announced, with `AvatarPartEnum::Len` or
+/// in the answer to `GetAvatar`.
+#[derive(Resource, Default)]
+pub struct AvatarDownloads(HashMap<PlayerUuid, AvatarDownload>);
+
+impl AvatarDownloads {
+    /// Whatever came of an earlier upload from `player` is dropped.
+    fn start(&mut self, player: PlayerUuid, upload: UploadId, len: usize) {
+        self.0.insert(
+            player,
+            AvatarDownload {
+                upload,
+                len,
+                data: Vec::new(),
+            },
+        );
+    }
+
+    fn is_downloading(&self, player: &PlayerUuid) -> bool {
+        self.0.contains_key(player)
+    }
+
+    /// Adds `part` to the download it belongs to, returns how many bytes of
+    /// it we have so far. A part past the announced length drops the whole
+    /// download.
+    fn receive(&mut self, part: &AvatarPart) -> Option<usize> {
+        let download = self
+            .0
+            .get_mut(&part.uuid)
+            .filter(|download| download.upload == part.upload)?;
+        if download.data.len() + part.data.len() > download.len {
+            warn!(
+                "avatar of {} is longer than the {} bytes announced, dropping it",
+                part.uuid.0, download.len
+            );
+            self.0.remove(&part.uuid);
+            return None;
+        }
+        download.data.extend_from_slice(&part.data);
+        Some(download.data.len())
+    }
+
+    /// The whole avatar, if `upload` is what we are downloading from
+    /// `player` and all of it arrived.
+    fn finish(&mut self, player: &PlayerUuid, upload: UploadId) -> Option<Vec<u8>> {
+        if self.0.get(player)?.upload != upload {
+            return None;
         }
+        let AvatarDownload { len, data, .. } = self.0.remove(player)?;
+        if data.len() != len {
+            warn!(
+                "avatar of {} ended after {} of {} bytes",
+                player.0,
+                data.len(),
+                len
+            );
+            return None;
+        }
+        Some(data)
     }
 }
 
@@ -94,58 +209,60 @@ pub fn handle_avatar_part(
     asset_server: Res<AssetServer>,
     mut event_reader: EventReader<AvatarPartEnum>,
     mut commands: Commands,
-    mut external_players: Query<(Entity, Option<&mut LoadingBar>, &Children, &PlayerUuid), With<ExternalPlayer>>,
+    player_index: Res<PlayerIndex>,
+    mut external_players: Query<(Option<&mut LoadingBar>, &Children), With<ExternalPlayer>>,
     mut vrm: Query<&mut Handle<Vrm>>,
     mut embedded_asset_registry: ResMut<EmbeddedAssetRegistry>,
-    mut local: Local<Thing>
+    mut downloads: ResMut<AvatarDownloads>,
+    departed: Query<(), With<Departed>>,
 ) {
     for event in event_reader.read() {
         match event {
             AvatarPartEnum::AvatarPart(part) => {
-                if local.0.len() == 0 {
-                    let part = part.clone();
-                    local.0 = part.data;
-                    local.1 = part.uuid;
-                } else {
-                    let mut part = part.clone();
-                    info!("getting part");
-                    local.0.append(&mut part.data);
-                }
-                for (entity, mut loading_bar, children, player) in external_players.iter_mut() {
-                    if *player != local.1 {
-                        continue;
-                    }
-                    if let Some(loading_bar) = loading_bar.as_mut() {
-                        loading_bar.current = local.0.len();
+                info!("getting part");
+                let Some(entity) = player_index.get(&part.uuid) else {
+                    continue;
+                };
+                let Some(received) = downloads.receive(part) else {
+                    if !downloads.is_downloading(&part.uuid) {
+                        commands.entity(entity).remove::<LoadingBar>();
                     }
+                    continue;
+                };
+                if let Ok((Some(mut loading_bar), _)) = external_players.get_mut(entity) {
+                    loading_bar.current = received;
                 }
             }
-            AvatarPartEnum::Done => {
+            AvatarPartEnum::Done(player_uuid, upload) => {
                 info!("getting done");
-                for (entity, mut loading_bar, children, player) in external_players.iter_mut() {
-                    if *player != local.1 {
-                        continue;
-                    }
-                    for child in children.iter() {
-                        if let Ok(mut awa) = vrm.get_mut(*child) {
-                            let uuid = Uuid::new_v4();
-                            let f = format!("{}.vrm", uuid);
-                            embedded_asset_registry.insert_asset(
-                                f.parse().unwrap(),
-                                f.as_ref(),
-                                local.0.clone(),
-                            );
-                            local.0.clear();
-                            *awa = asset_server.load(format!("embedded://{}", f));
-                        }
+                let Some(data) = downloads.finish(player_uuid, *upload) else {
+                    continue;
+                };
+                let Some(entity) = player_index.get(player_uuid) else {
+                    continue;
+                };
+                let Ok((_, children)) = external_players.get(entity) else {
+                    continue;
+                };
+                for child in children.iter() {
+                    if let Ok(mut awa) = vrm.get_mut(*child) {
+                        let uuid = Uuid::new_v4();
+                        let f = format!("{}.vrm", uuid);
+                        embedded_asset_registry.insert_asset(
+                            f.parse().unwrap(),
+                            f.as_ref(),
+                            data.clone(),
+                        );
+                        *awa = asset_server.load(format!("embedded://{}", f));
                     }
                 }
             }
-            AvatarPartEnum::Len(player_uuid, len) => {
-                for (entity, mut loading_bar, children, player) in external_players.iter_mut() {
-                    if *player != *player_uuid {
-                        continue;
-                    }
+            AvatarPartEnum::Len(player_uuid, upload, len) => {
+                let Some(entity) = player_index.get(player_uuid) else {
+                    continue;
+                };
+                if external_players.contains(entity) {
+                    downloads.start(player_uuid.clone(), *upload, *len);
                     commands.entity(entity).insert(LoadingBar {
                         len: *len,
                         current: 0,
@@ -154,6 +271,13 @@ pub fn handle_avatar_part(
             }
         }
     }
+    // nobody finishes sending an avatar once they're gone, and they announce
+    // it again if they come back
+    downloads.0.retain(|player, _| {
+        player_index
+            .get(player)
+            .is_some_and(|entity| !departed.contains(entity))
+    });
 }
 
 #[derive(Component, Reflect)]
@@ -197,64 +321,128 @@ impl Default for Thing2 {
 }
 
 
+/// Bytes of avatar parts we let pile up in the send queue.
+const AVATAR_PART_BACKLOG: usize = 100_000;
+
+struct AvatarUpload {
+    id: UploadId,
+    /// Everyone if `None`.
+    to: Option<PeerId>,
+    parts: VecDeque<AvatarPart>,
+}
+
+/// Our own avatar, kept for peers that ask for it later, and the uploads of
+/// it still going out one after another.
+#[derive(Resource, Default)]
+pub struct AvatarUploads {
+    avatar: Option<(Vec<u8>, PlayerUuid)>,
+    queue: VecDeque<AvatarUpload>,
+    next_id: u64,
+}
+
+impl AvatarUploads {
+    /// Queues the whole avatar for `to`, returns the upload it goes out in and
+    /// its length if we have one.
+    fn upload(&mut self, to: Option<PeerId>) -> Option<(UploadId, usize)> {
+        let (data, uuid) = self.avatar.as_ref()?;
+        let id = UploadId(self.next_id);
+        self.next_id += 1;
+        let parts = data
+            .chunks(10_000)
+            .map(|chunk| {
+                AvatarPart::new(uuid.clone(), id, "suzah.vrm".to_string(), chunk.to_vec())
+            })
+            .collect();
+        self.queue.push_back(AvatarUpload { id, to, parts });
+        Some((id, data.len()))
+    }
+}
+
+fn send_avatar_message(socket: &mut NetworkSocket, to: Option<PeerId>, message: AvatarPartEnum) {
+    match to {
+        Some(peer) => socket.send_msg_reliable(peer, &Message::AvatarPart(message)),
+        None => socket.send_msg_all_reliable(&Message::AvatarPart(message)),
+    }
+}
+
 fn other_system(
-    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut socket: ResMut<NetworkSocket>,
     mut trying_things: ResMut<TryingThings>,
-    mut avatar_parts: Local<Option<Vec<AvatarPart>>>,
+    mut uploads: ResMut<AvatarUploads>,
 ) {
-    if avatar_parts.is_none() {
-        if let Ok(Some(awa)) = trying_things.0.try_next() {
-            socket.send_msg_all_reliable(&Message::AvatarPart(AvatarPartEnum::Len(awa.1.clone(), awa.0.len())));
-            avatar_parts.replace(vec![]);
-            for i in awa.0.chunks(10_000) {
-                let part = AvatarPart {
-                    uuid: awa.1.clone(),
-                    avatar_name: "suzah.vrm".to_string(),
-                    data: i.to_vec(),
-                };
-                avatar_parts.as_mut().unwrap().push(part);
-            }
+    if let Ok(Some(awa)) = trying_things.0.try_next() {
+        let uuid = awa.1.clone();
+        // a new avatar replaces whatever was still going out of the old one
+        uploads.queue.clear();
+        uploads.avatar = Some(awa);
+        if let Some((upload, len)) = uploads.upload(None) {
+            socket.send_msg_all_reliable(&Message::AvatarPart(AvatarPartEnum::Len(
+                uuid, upload, len,
+            )));
         }
     }
 
-    for _ in 0..10 {
-        let number_left = avatar_parts.as_ref().map(|a| a.len());
-        if let Some(number_left) = number_left {
-            if number_left == 0 {
-                info!("sending done");
-                socket.send_msg_all_reliable(&Message::AvatarPart(AvatarPartEnum::Done));
-                avatar_parts.take();
-            }
-        }
+    // parts wait in the send scheduler at the lowest priority, only keep
+    // enough queued that it always has something to send
+    while socket.scheduler().backlog(MessageKind::AvatarPart) < AVATAR_PART_BACKLOG {
+        let Some(upload) = uploads.queue.front_mut() else {
+            break;
+        };
+        let (id, to) = (upload.id, upload.to);
+        let Some(avatar_part) = upload.parts.pop_front() else {
+            info!("sending done");
+            let uuid = uploads.avatar.as_ref().unwrap().1.clone();
+            send_avatar_message(&mut socket, to, AvatarPartEnum::Done(uuid, id));
+            uploads.queue.pop_front();
+            continue;
+        };
+        info!("sending part");
+        info!("number left is: {}", upload.parts.len());
+        send_avatar_message(&mut socket, to, AvatarPartEnum::AvatarPart(avatar_part));
+    }
+}
 
-        if let Some(avatar_parts) = avatar_parts.deref_mut().as_mut() {
-            let avatar_part = avatar_parts.remove(0);
-            let message = Message::AvatarPart(AvatarPartEnum::AvatarPart(avatar_part));
-
-            let number_left = number_left.unwrap();
-
-            info!("sending part");
-            info!("number left is: {}", number_left);
-
-            match socket.try_send_msg_all_reliable(&message) {
-                Ok(_) => {}
-                Err(err) => {
-                    if err.is_full() {
-                        let avatar_part = match message {
-                            Message::AvatarPart(avatar_part) => {
-                                match avatar_part {
-                                    AvatarPartEnum::AvatarPart(avatar_part) => avatar_part,
-                                    _ => unreachable!()
-                                }
-                            }
-                            _ => unreachable!()
-                        };
-                        info!("channel full, retrying later");
-                        avatar_parts.insert(0, avatar_part);
-                        return;
-                    }
+/// Everyone we meet might have dropped in an avatar before we got here.
+fn request_avatars(mut rpcs: Rpcs, mut complete: EventReader<HandshakeComplete>) {
+    for HandshakeComplete(peer) in complete.read() {
+        rpcs.request(*peer, &GetAvatar);
+    }
+}
+
+fn answer_avatar_requests(
+    mut rpcs: Rpcs,
+    mut uploads: ResMut<AvatarUploads>,
+    mut requests: EventReader<RpcRequest<GetAvatar>>,
+) {
+    for RpcRequest { peer, id, .. } in requests.read() {
+        let upload = uploads.upload(Some(*peer));
+        rpcs.respond::<GetAvatar>(*peer, *id, Ok(upload));
+    }
+}
+
+fn handle_avatar_answers(
+    mut commands: Commands,
+    peer_index: Res<PeerIndex>,
+    players: Query<&PlayerUuid, With<ExternalPlayer>>,
+    mut downloads: ResMut<AvatarDownloads>,
+    mut results: EventReader<RpcResult<GetAvatar>>,
+) {
+    for RpcResult { peer, result, .. } in results.read() {
+        match result {
+            Ok(Some((upload, len))) => {
+                let Some(entity) = peer_index.get(*peer) else {
+                    continue;
+                };
+                if let Ok(player_uuid) = players.get(entity) {
+                    downloads.start(player_uuid.clone(), *upload, *len);
+                    commands.entity(entity).insert(LoadingBar {
+                        len: *len,
+                        current: 0,
+                    });
                 }
             }
+            Ok(None) => {}
+            Err(err) => warn!("couldn't get the avatar of {}: {}", peer, err),
         }
     }
 }
@@ -346,3 +534,57 @@ fn js_value_to_err<'a>(context: &'a str) -> impl FnOnce(JsValue) -> std::io::Err
         std::io::Error::new(std::io::ErrorKind::Other, message)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(
+        uploads: &mut AvatarUploads,
+        to: Option<PeerId>,
+    ) -> (UploadId, usize, Vec<AvatarPart>) {
+        let (id, len) = uploads.upload(to).unwrap();
+        let upload = uploads.queue.pop_back().unwrap();
+        (id, len, upload.parts.into())
+    }
+
+    #[test]
+    fn joining_mid_broadcast_still_gets_the_avatar() {
+        let alice = PlayerUuid("alice".to_string());
+        let avatar = (0..45_000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut uploads = AvatarUploads {
+            avatar: Some((avatar.clone(), alice.clone())),
+            ..default()
+        };
+        let (broadcast, _, broadcast_parts) = parts(&mut uploads, None);
+        // we joined after the broadcast's `Len` and first part went out, and
+        // asked for the avatar ourselves
+        let (ours, len, our_parts) = parts(&mut uploads, Some(PeerId(Uuid::from_u128(1))));
+        let mut downloads = AvatarDownloads::default();
+        downloads.start(alice.clone(), ours, len);
+
+        for (theirs, ours) in broadcast_parts[1..].iter().zip(&our_parts) {
+            assert_eq!(downloads.receive(theirs), None);
+            assert!(downloads.receive(ours).is_some());
+        }
+        assert_eq!(downloads.finish(&alice, broadcast), None);
+        assert!(downloads.is_downloading(&alice));
+        for part in &our_parts[broadcast_parts.len() - 1..] {
+            assert!(downloads.receive(part).is_some());
+        }
+        assert_eq!(downloads.finish(&alice, ours), Some(avatar));
+    }
+
+    #[test]
+    fn parts_past_the_announced_length_drop_the_download() {
+        let alice = PlayerUuid("alice".to_string());
+        let upload = UploadId(0);
+        let mut downloads = AvatarDownloads::default();
+        downloads.start(alice.clone(), upload, 10);
+        let part = |len| AvatarPart::new(alice.clone(), upload, "a.vrm".to_string(), vec![0; len]);
+        assert_eq!(downloads.receive(&part(6)), Some(6));
+        assert_eq!(downloads.receive(&part(6)), None);
+        assert!(!downloads.is_downloading(&alice));
+        assert_eq!(downloads.finish(&alice, upload), None);
+    }
+}
@@ -0,0 +1,10 @@
+pub mod custom_audio;
+pub mod file_sharing;
+pub mod networking;
+pub mod props;
+pub mod voice_chat;
+
+use bevy::math::Vec3;
+use unavi_avatar::PLAYER_HEIGHT;
+
+pub const SPAWN: Vec3 = Vec3::new(0.0, PLAYER_HEIGHT * 2.0, 0.0);
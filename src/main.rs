@@ -1,17 +1,20 @@
-mod custom_audio;
-mod file_sharing;
-mod networking;
-mod voice_chat;
-
-use crate::custom_audio::audio_output::AudioOutputPlugin;
-use crate::custom_audio::microphone::MicrophonePlugin;
-use crate::custom_audio::spatial_audio::{SpatialAudioListener, SpatialAudioPlugin};
-use crate::file_sharing::FileSharingPlugin;
-use crate::networking::message::SpawnCube;
-use crate::networking::{
-    Authority, Message, NetworkingPlugin, PlayerUuid, PropUuid, SocketSendMessage,
+use p2pvr::custom_audio::audio_output::AudioOutputPlugin;
+use p2pvr::custom_audio::microphone::MicrophonePlugin;
+use p2pvr::custom_audio::spatial_audio::{SpatialAudioListener, SpatialAudioPlugin};
+use p2pvr::file_sharing::FileSharingPlugin;
+#[cfg(not(target_family = "wasm"))]
+use p2pvr::networking::connection::{host_signaling_if_asked, record_if_asked, replay_if_asked};
+use p2pvr::networking::conditions::{LinkConditions, NetworkConditions};
+use p2pvr::networking::connection::ConnectionSettings;
+use p2pvr::networking::deletion::{PropDeleted, RequestDeleteProp};
+use p2pvr::networking::message::SpawnProp;
+use p2pvr::networking::ownership::{AuthorityChanged, Held};
+use p2pvr::networking::transport::NetworkSocket;
+use p2pvr::networking::{
+    Authority, DisplayName, Message, NetworkingPlugin, PlayerUuid, PropUuid, SocketSendMessage,
 };
-use crate::voice_chat::VoiceChatPlugin;
+use p2pvr::props::{PropSpec, PropsPlugin, GREEN_CUBE, RED_CUBE};
+use p2pvr::voice_chat::VoiceChatPlugin;
 use avian3d::prelude::*;
 use avian3d::prelude::{Collider, RigidBody};
 use avian3d::PhysicsPlugins;
@@ -25,86 +28,133 @@ use bevy::asset::AssetMetaCheck;
 use bevy::prelude::*;
 use bevy::time::run_fixed_main_schedule;
 use bevy_embedded_assets::EmbeddedAssetPlugin;
-use bevy_matchbox::prelude::*;
 use bevy_tnua_physics_integration_layer::data_for_backends::TnuaProximitySensor;
 use serde::{Deserialize, Serialize};
-use unavi_avatar::PLAYER_HEIGHT;
 use unavi_player::layers::LAYER_PROPS;
 use unavi_player::{LocalPlayer, PlayerCamera, PlayerPlugin};
 use uuid::Uuid;
 
 fn main() {
-    App::new()
-        .add_plugins((
-            EmbeddedAssetPlugin::default(),
-            bevy_web_file_drop::WebFileDropPlugin,
-            DefaultPlugins.set(AssetPlugin {
-                meta_check: AssetMetaCheck::Never,
-                ..AssetPlugin::default()
-            }),
-            PhysicsPlugins::default(),
-            PlayerPlugin,
-            AvianPickupPlugin::default(),
-            // Add interpolation
-            AvianInterpolationPlugin::default(),
-        ))
-        .add_plugins((
-            NetworkingPlugin,
-            AudioOutputPlugin,
-            MicrophonePlugin,
-            VoiceChatPlugin,
-            SpatialAudioPlugin,
-            FileSharingPlugin,
-        ))
-        .add_systems(Startup, setup_scene)
-        .add_systems(Update, player_add_pickup)
-        .add_systems(Update, add_uuid)
-        .add_systems(Update, handle_spawn_cube)
-        .add_systems(
-            FixedPreUpdate,
-            (handle_input).before(run_fixed_main_schedule),
-        )
-        .add_systems(Update, update_prop_authority)
-        .add_systems(Startup, start_socket)
-        .run();
+    let mut app = App::new();
+    app.add_plugins((
+        EmbeddedAssetPlugin::default(),
+        bevy_web_file_drop::WebFileDropPlugin,
+        DefaultPlugins.set(AssetPlugin {
+            meta_check: AssetMetaCheck::Never,
+            ..AssetPlugin::default()
+        }),
+        PhysicsPlugins::default(),
+        PlayerPlugin,
+        AvianPickupPlugin::default(),
+        // Add interpolation
+        AvianInterpolationPlugin::default(),
+    ))
+    .add_plugins((
+        NetworkingPlugin,
+        PropsPlugin,
+        AudioOutputPlugin,
+        MicrophonePlugin,
+        VoiceChatPlugin,
+        SpatialAudioPlugin,
+        FileSharingPlugin,
+    ))
+    .add_systems(Startup, setup_scene)
+    .add_systems(Update, player_add_pickup)
+    .add_systems(Update, add_uuid)
+    .add_systems(
+        FixedPreUpdate,
+        (handle_input).before(run_fixed_main_schedule),
+    )
+    .add_systems(Update, (mark_held_props, drop_lost_props, drop_deleted_props))
+    .add_systems(Update, toggle_bad_network);
+    let mut connection_settings = ConnectionSettings::from_environment();
+    #[cfg(not(target_family = "wasm"))]
+    {
+        host_signaling_if_asked(&mut app, &mut connection_settings);
+        record_if_asked(&app);
+        if replay_if_asked(&mut app) {
+            // the recording stands in for the socket, there is nothing to
+            // connect to
+            app.run();
+            return;
+        }
+    }
+    app.insert_resource(connection_settings).run();
 }
 
-#[derive(Component)]
-pub struct LocalProp;
+/// F9 pretends we are on bad Wi-Fi, for seeing how sync holds up.
+fn toggle_bad_network(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    conditions: Res<NetworkConditions>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::F9) {
+        return;
+    }
+    if conditions.get().enabled {
+        info!("network conditions back to normal");
+        conditions.set(LinkConditions::default());
+    } else {
+        info!("simulating bad Wi-Fi");
+        conditions.set(LinkConditions::bad_wifi());
+    }
+}
 
-fn update_prop_authority(
-    actors: Query<(Entity, &AvianPickupActorState)>,
-    mut prop: Query<&mut Authority, Without<LocalProp>>,
-    changed_prop: Query<(Entity, &Authority), (Changed<Authority>, With<LocalProp>)>,
-    uuid: Query<&PlayerUuid, With<LocalPlayer>>,
+/// Tags whatever our actors are pulling or holding, the networking side asks
+/// for authority over those.
+fn mark_held_props(
     mut commands: Commands,
+    actors: Query<&AvianPickupActorState>,
+    held: Query<Entity, With<Held>>,
+    props: Query<(), With<Authority>>,
+) {
+    let in_hand = actors
+        .iter()
+        .filter_map(|actor| match actor {
+            AvianPickupActorState::Pulling(e) | AvianPickupActorState::Holding(e) => Some(*e),
+            AvianPickupActorState::Idle => None,
+        })
+        .filter(|e| props.contains(*e))
+        .collect::<Vec<_>>();
+    for prop in held.iter() {
+        if !in_hand.contains(&prop) {
+            commands.entity(prop).remove::<Held>();
+        }
+    }
+    for prop in in_hand {
+        if !held.contains(prop) {
+            commands.entity(prop).insert(Held);
+        }
+    }
+}
+
+/// Lets go of props someone else took from us.
+fn drop_lost_props(
+    mut commands: Commands,
+    mut changed: EventReader<AuthorityChanged>,
+    actors: Query<(Entity, &AvianPickupActorState)>,
+    local_player: Query<&PlayerUuid, With<LocalPlayer>>,
     mut avian_pickup_input_writer: EventWriter<AvianPickupInput>,
 ) {
-    let Ok(uuid) = uuid.get_single() else {
+    let Ok(local_player) = local_player.get_single() else {
         return;
     };
-    for (actor_e, actor) in actors.iter() {
-        match actor {
-            AvianPickupActorState::Idle => {}
-            AvianPickupActorState::Pulling(e) | AvianPickupActorState::Holding(e) => {
-                if let Ok((prop, authority)) = changed_prop.get(*e) {
-                    if authority.player != uuid.clone() {
-                        println!("no longer in charge of prop");
-                        avian_pickup_input_writer.send(AvianPickupInput {
-                            action: AvianPickupAction::Drop,
-                            actor: actor_e,
-                        });
-                        commands.entity(actor_e).insert(AvianPickupActorState::Idle);
-                        commands.entity(prop).remove::<LocalProp>();
-                        return;
-                    }
-                }
-                if let Ok(mut prop) = prop.get_mut(*e) {
-                    if prop.player != uuid.clone() {
-                        prop.counter += 1;
-                        prop.player = uuid.clone();
-                        commands.entity(*e).insert(LocalProp);
-                    }
+    for change in changed.read() {
+        if change.previous.player() != local_player || change.current.player() == local_player {
+            continue;
+        }
+        for (actor, state) in actors.iter() {
+            if let AvianPickupActorState::Pulling(e) | AvianPickupActorState::Holding(e) = state {
+                if *e == change.prop {
+                    info!(
+                        "lost prop {} to {}",
+                        change.prop_uuid.0,
+                        change.current.player().0
+                    );
+                    avian_pickup_input_writer.send(AvianPickupInput {
+                        action: AvianPickupAction::Drop,
+                        actor,
+                    });
+                    commands.entity(actor).insert(AvianPickupActorState::Idle);
                 }
             }
         }
@@ -115,12 +165,20 @@ fn handle_input(
     mut avian_pickup_input_writer: EventWriter<AvianPickupInput>,
     key_input: Res<ButtonInput<MouseButton>>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
-    actors: Query<Entity, With<AvianPickupActor>>,
-    mut spawn_cube: EventWriter<SpawnCube>,
+    actors: Query<(Entity, Option<&AvianPickupActorState>), With<AvianPickupActor>>,
+    mut spawn_prop: EventWriter<SpawnProp>,
+    mut delete_prop: EventWriter<RequestDeleteProp>,
     local_player: Query<&PlayerUuid, With<LocalPlayer>>,
-    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut socket: ResMut<NetworkSocket>,
 ) {
-    for actor in &actors {
+    for (actor, state) in &actors {
+        if keyboard_input.just_pressed(KeyCode::KeyX) {
+            if let Some(AvianPickupActorState::Pulling(prop) | AvianPickupActorState::Holding(prop)) =
+                state
+            {
+                delete_prop.send(RequestDeleteProp(*prop));
+            }
+        }
         if key_input.just_pressed(MouseButton::Left) {
             avian_pickup_input_writer.send(AvianPickupInput {
                 action: AvianPickupAction::Throw,
@@ -146,20 +204,20 @@ fn handle_input(
     };
 
     if keyboard_input.just_pressed(KeyCode::KeyC) {
-        let cube = SpawnCube {
-            authority: Authority {
-                player: local_player.clone(),
-                counter: 0,
-            },
+        let cube = SpawnProp {
+            authority: Authority::new(local_player.clone()),
             prop_uuid: PropUuid(Uuid::new_v4().to_string()),
+            prop: PropSpec::Kind(GREEN_CUBE.to_string()),
             position: Position::new(Vec3::new(0.0, 2.0, 0.0)),
+            rotation: Rotation::default(),
         };
-        socket.send_msg_all_reliable(&Message::SpawnCube(cube.clone()));
-        spawn_cube.send(cube.clone());
+        socket.send_msg_all_reliable(&Message::SpawnProp(cube.clone()));
+        spawn_prop.send(cube);
     }
 }
 
 const GROUND_SIZE: f32 = 30.0;
+const SCENE_CUBE_UUID: &str = "scene-red-cube";
 const GROUND_THICK: f32 = 0.2;
 const MIRROR_H: f32 = 3.0;
 
@@ -187,9 +245,11 @@ fn add_uuid(
     local_player: Query<Entity, (With<LocalPlayer>, Without<PlayerUuid>)>,
 ) {
     for e in local_player.iter() {
+        let uuid = Uuid::new_v4().to_string();
         commands
             .entity(e)
-            .insert(PlayerUuid(Uuid::new_v4().to_string()))
+            .insert(DisplayName(format!("Player {}", &uuid[..8])))
+            .insert(PlayerUuid(uuid))
             .insert(SpatialAudioListener);
     }
 }
@@ -229,32 +289,25 @@ fn other_thing(
     }
 }
 
-fn handle_spawn_cube(
+/// Lets go of props their holder deleted.
+fn drop_deleted_props(
     mut commands: Commands,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut event_reader: EventReader<SpawnCube>,
+    mut deleted: EventReader<PropDeleted>,
+    actors: Query<(Entity, &AvianPickupActorState)>,
+    mut avian_pickup_input_writer: EventWriter<AvianPickupInput>,
 ) {
-    for cube in event_reader.read() {
-        let cube_material = materials.add(Color::linear_rgb(0.0, 1.0, 0.0));
-
-        let box_shape = Cuboid::from_size(Vec3::splat(0.5));
-        let box_mesh = meshes.add(box_shape);
-        commands.spawn((
-            Name::new("Light Box"),
-            PbrBundle {
-                mesh: box_mesh.clone(),
-                material: cube_material.clone(),
-                transform: Transform::from_xyz(cube.position.x, cube.position.y, cube.position.z),
-                ..default()
-            },
-            cube.authority.clone(),
-            cube.prop_uuid.clone(),
-            // All `RigidBody::Dynamic` entities are able to be picked up.
-            RigidBody::Dynamic,
-            Collider::from(box_shape),
-            CollisionLayers::new(LAYER_PROPS, LayerMask::ALL),
-        ));
+    for PropDeleted { prop, .. } in deleted.read() {
+        for (actor, state) in actors.iter() {
+            if let AvianPickupActorState::Pulling(e) | AvianPickupActorState::Holding(e) = state {
+                if e == prop {
+                    avian_pickup_input_writer.send(AvianPickupInput {
+                        action: AvianPickupAction::Drop,
+                        actor,
+                    });
+                    commands.entity(actor).insert(AvianPickupActorState::Idle);
+                }
+            }
+        }
     }
 }
 
@@ -264,6 +317,7 @@ fn setup_scene(
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut meshes: ResMut<Assets<Mesh>>,
     asset_server: Res<AssetServer>,
+    mut spawn_prop: EventWriter<SpawnProp>,
 ) {
     let floor_texture = asset_server.load("grass.ktx2");
     let floor_normal_texture = asset_server.load("grass_normal.ktx2");
@@ -275,23 +329,15 @@ fn setup_scene(
         transform: Transform::from_xyz(4.5, 10.0, -7.0).looking_at(Vec3::ZERO, Vec3::Y),
         ..default()
     });
-    let cube_material = materials.add(Color::linear_rgb(1.0, 0.0, 0.0));
-
-    let box_shape = Cuboid::from_size(Vec3::splat(0.5));
-    let box_mesh = meshes.add(box_shape);
-    commands.spawn((
-        Name::new("Light Box"),
-        PbrBundle {
-            mesh: box_mesh.clone(),
-            material: cube_material.clone(),
-            transform: Transform::from_xyz(0.0, 2.0, 3.5),
-            ..default()
-        },
-        // All `RigidBody::Dynamic` entities are able to be picked up.
-        RigidBody::Dynamic,
-        Collider::from(box_shape),
-        CollisionLayers::new(LAYER_PROPS, LayerMask::ALL),
-    ));
+    // every peer spawns this one itself, the fixed uuid is what lets them
+    // agree it's the same prop
+    spawn_prop.send(SpawnProp {
+        authority: Authority::unowned(),
+        prop_uuid: PropUuid(SCENE_CUBE_UUID.to_string()),
+        prop: PropSpec::Kind(RED_CUBE.to_string()),
+        position: Position::new(Vec3::new(0.0, 2.0, 3.5)),
+        rotation: Rotation::default(),
+    });
     const FLOOR_TILING: i32 = 20;
 
     for x in -FLOOR_TILING..FLOOR_TILING {
@@ -339,15 +385,3 @@ fn setup_scene(
     let mut transform = Transform::from_xyz(0.0, 3.0, -10.0);
     transform.look_at(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, 1.0, 0.0));
 }
-
-fn start_socket(mut commands: Commands) {
-    let matchbox = MatchboxSocket::from(
-        WebRtcSocketBuilder::new("wss://mb.v-sekai.cloud/hello5")
-            .add_reliable_channel()
-            .add_unreliable_channel()
-            .build(),
-    );
-    commands.insert_resource(matchbox);
-}
-
-pub const SPAWN: Vec3 = Vec3::new(0.0, PLAYER_HEIGHT * 2.0, 0.0);
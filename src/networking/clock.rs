@@ -0,0 +1,282 @@
+use crate::networking::connection::Departed;
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+/// A clock every peer agrees on, for stamping state messages. Each peer's
+/// `Time` starts whenever its app did, so on top of it we keep an offset that
+/// only ever moves forward, towards the median of how far ahead the peers
+/// are. Everyone ends up on the clock of the longest running peers and stays
+/// on it when they leave, while a single peer with a broken or lying clock
+/// can't drag the rest along.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct NetworkTime {
+    offset: f64,
+    now: f64,
+    synced: bool,
+}
+
+impl NetworkTime {
+    /// Network time at the start of this frame.
+    pub fn now(&self) -> f64 {
+        self.now
+    }
+
+    /// Network time minus our own `Time::elapsed_seconds_f64`.
+    pub fn offset(&self) -> f64 {
+        self.offset
+    }
+
+    /// Whether we measured every peer and agree with the median one, within
+    /// `ClockSettings::synced_within`.
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    pub fn from_local(&self, local: f64) -> f64 {
+        local + self.offset
+    }
+
+    pub fn to_local(&self, network: f64) -> f64 {
+        network - self.offset
+    }
+
+    /// When something stamped `sent_at` happened on our own `Time`. Nothing
+    /// arrives before it was sent, and until we are synced the stamp can't be
+    /// trusted at all, so both fall back to `local_now`.
+    pub fn local_time_of(&self, sent_at: f64, local_now: f64) -> f64 {
+        if !self.synced {
+            return local_now;
+        }
+        self.to_local(sent_at).min(local_now)
+    }
+
+    /// How long ago something stamped `sent_at` happened, if we are synced.
+    pub fn age(&self, sent_at: f64) -> Option<f64> {
+        self.synced.then(|| (self.now - sent_at).max(0.0))
+    }
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct ClockSettings {
+    /// Samples kept per peer, the one with the shortest round trip is used.
+    pub samples: usize,
+    /// Peers less than this far ahead, in seconds, are left alone so noise
+    /// doesn't keep pushing everyone's clock forward.
+    pub tolerance: f64,
+    /// Differences above this are jumped over at once, anything smaller is
+    /// caught up with at `slew_rate`.
+    pub step_threshold: f64,
+    /// Seconds gained per second while catching up.
+    pub slew_rate: f64,
+    /// The most a single frame jumps by, so a peer claiming to be years
+    /// ahead can't drag everyone there at once.
+    pub max_step: f64,
+    pub synced_within: f64,
+}
+
+impl Default for ClockSettings {
+    fn default() -> Self {
+        Self {
+            samples: 8,
+            tolerance: 0.002,
+            step_threshold: 0.25,
+            slew_rate: 0.05,
+            max_step: 5.0,
+            synced_within: 0.02,
+        }
+    }
+}
+
+/// One NTP style measurement, from a ping sent at `t0` and answered at `t1`
+/// on their clock, with the answer sent at `t2` and received at `t3` on ours.
+/// Returns how far their clock is ahead of ours and how long the round trip
+/// took without their side. The offset is only off by half the difference
+/// between the two directions, a symmetric path gives it exactly.
+pub fn ntp_sample(t0: f64, t1: f64, t2: f64, t3: f64) -> (f64, f64) {
+    let offset = ((t1 - t0) + (t2 - t3)) / 2.0;
+    let delay = (t3 - t0) - (t2 - t1);
+    (offset, delay)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ClockSample {
+    offset: f64,
+    delay: f64,
+}
+
+/// How a peer's network time compares to ours, on their `ExternalPlayer`.
+#[derive(Component, Clone, Debug, Default)]
+pub struct PeerClock {
+    samples: VecDeque<ClockSample>,
+}
+
+impl PeerClock {
+    /// Samples with a stamp that isn't a number are left out.
+    pub fn add_sample(&mut self, settings: &ClockSettings, offset: f64, delay: f64) {
+        if !offset.is_finite() || !delay.is_finite() {
+            return;
+        }
+        self.samples.push_back(ClockSample { offset, delay });
+        while self.samples.len() > settings.samples.max(1) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Their network time minus ours. Taken from the sample with the shortest
+    /// round trip, the one least likely to have sat in a queue on the way.
+    pub fn offset(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .min_by(|a, b| a.delay.total_cmp(&b.delay))
+            .map(|sample| sample.offset)
+    }
+
+    /// Our network time moved forward by `by`, so they are that much less
+    /// ahead in every sample we kept.
+    fn shift(&mut self, by: f64) {
+        for sample in self.samples.iter_mut() {
+            sample.offset -= by;
+        }
+    }
+}
+
+pub fn sync_network_time(
+    time: Res<Time>,
+    settings: Res<ClockSettings>,
+    mut network_time: ResMut<NetworkTime>,
+    mut clocks: Query<&mut PeerClock, Without<Departed>>,
+) {
+    let mut offsets = clocks
+        .iter()
+        .filter_map(PeerClock::offset)
+        .collect::<Vec<_>>();
+    offsets.sort_by(f64::total_cmp);
+    // the lower median, so with two peers the one further ahead doesn't get
+    // its way on its own
+    let ahead = offsets
+        .get(offsets.len().saturating_sub(1) / 2)
+        .copied()
+        .unwrap_or(0.0);
+    let step = if ahead > settings.step_threshold {
+        ahead.min(settings.max_step)
+    } else if ahead > settings.tolerance {
+        ahead.min(settings.slew_rate * time.delta_seconds_f64())
+    } else {
+        0.0
+    };
+    if step > 0.0 {
+        network_time.offset += step;
+        for mut clock in clocks.iter_mut() {
+            clock.shift(step);
+        }
+    }
+    network_time.synced = clocks.iter().all(|clock| clock.offset().is_some())
+        && (ahead - step).abs() <= settings.synced_within;
+    network_time.now = time.elapsed_seconds_f64() + network_time.offset;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::test_app::FRAME;
+    use bevy::time::TimeUpdateStrategy;
+    use std::time::Duration;
+
+    fn clock_app(offset: f64) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+                FRAME,
+            )))
+            .init_resource::<ClockSettings>()
+            .insert_resource(NetworkTime {
+                offset,
+                ..default()
+            })
+            .add_systems(Update, sync_network_time);
+        app.world_mut().spawn(PeerClock::default());
+        app.update();
+        app
+    }
+
+    fn network_now(app: &App) -> f64 {
+        let network_time = app.world().resource::<NetworkTime>();
+        let local = app.world().resource::<Time>().elapsed_seconds_f64();
+        network_time.from_local(local)
+    }
+
+    fn add_sample(app: &mut App, offset: f64, delay: f64) {
+        let settings = app.world().resource::<ClockSettings>().clone();
+        let mut clocks = app.world_mut().query::<&mut PeerClock>();
+        let mut clock = clocks.single_mut(app.world_mut());
+        clock.add_sample(&settings, offset, delay);
+    }
+
+    /// `from` pings `to`, the ping takes `there` seconds and the pong `back`.
+    fn ping(from: &mut App, to: &App, there: f64, back: f64) {
+        let t0 = network_now(from);
+        let t1 = network_now(to) + there;
+        let t3 = t0 + there + back;
+        let (offset, delay) = ntp_sample(t0, t1, t1, t3);
+        add_sample(from, offset, delay);
+    }
+
+    #[test]
+    fn converges_under_asymmetric_latency() {
+        let (there, back) = (0.01, 0.05);
+        let mut young = clock_app(0.0);
+        let mut old = clock_app(100.0);
+        for frame in 0..60 * 30 {
+            if frame % 60 == 0 {
+                ping(&mut young, &old, there, back);
+                ping(&mut old, &young, back, there);
+            }
+            young.update();
+            old.update();
+        }
+        let settings = ClockSettings::default();
+        // NTP can't see the asymmetry, it splits the round trip evenly
+        let error = (back - there) / 2.0 + settings.tolerance;
+        assert!((network_now(&young) - network_now(&old)).abs() <= error + 1e-6);
+        // the younger clock caught up, the older one stayed put
+        assert!((old.world().resource::<NetworkTime>().offset() - 100.0).abs() <= error);
+        assert!(young.world().resource::<NetworkTime>().is_synced());
+        assert!(old.world().resource::<NetworkTime>().is_synced());
+    }
+
+    #[test]
+    fn bogus_samples_dont_throw_us_off() {
+        let mut app = clock_app(0.0);
+        add_sample(&mut app, f64::NAN, 0.01);
+        add_sample(&mut app, f64::INFINITY, 0.01);
+        add_sample(&mut app, 1.0, f64::NAN);
+        app.update();
+        assert_eq!(app.world().resource::<NetworkTime>().offset(), 0.0);
+
+        // a peer claiming to be a year ahead, and saying so again with every
+        // pong, next to two we agree with
+        let settings = app.world().resource::<ClockSettings>().clone();
+        let liar = app
+            .world_mut()
+            .query_filtered::<Entity, With<PeerClock>>()
+            .single(app.world());
+        let honest = [
+            app.world_mut().spawn(PeerClock::default()).id(),
+            app.world_mut().spawn(PeerClock::default()).id(),
+        ];
+        for frame in 0..60 * 30 {
+            if frame % 60 == 0 {
+                let world = app.world_mut();
+                let mut clock = world.get_mut::<PeerClock>(liar).unwrap();
+                clock.add_sample(&settings, 365.0 * 24.0 * 3600.0, 0.01);
+                for peer in honest {
+                    let mut clock = world.get_mut::<PeerClock>(peer).unwrap();
+                    clock.add_sample(&settings, 0.0, 0.01);
+                }
+            }
+            app.update();
+            assert_eq!(app.world().resource::<NetworkTime>().offset(), 0.0);
+        }
+        assert!(app.world().resource::<NetworkTime>().is_synced());
+    }
+}
@@ -0,0 +1,337 @@
+use crate::networking::Message;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// Bumped whenever `Message` changes in a way older clients can't decode.
+///
+/// 1: this header in front of bincode instead of bare json.
+/// 2: `PlayerPosition` carries a `sequence`.
+/// 3: `SpawnCube` became `SpawnProp`.
+/// 4: `Authority` carries a `priority`.
+/// 5: state messages and heartbeats carry `NetworkTime` stamps.
+/// 6: prop updates became `Replication` messages.
+/// 7: world snapshots and avatars are fetched through `Rpc` messages.
+/// 8: avatar parts carry the upload they belong to.
+/// 9: world snapshot pages start after a prop instead of at a page number.
+pub const PROTOCOL_VERSION: u8 = 9;
+
+/// version, format, kind
+pub const HEADER_LEN: usize = 3;
+
+/// Length in front of every packet in a batch.
+pub const BATCH_LEN_PREFIX: usize = 4;
+
+/// How the body of a packet is encoded. Binary is what we actually want to
+/// ship, json is there so packets can be read by a human when debugging.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireFormat {
+    #[default]
+    Binary,
+    Json,
+}
+
+impl WireFormat {
+    fn to_byte(self) -> u8 {
+        match self {
+            WireFormat::Binary => 0,
+            WireFormat::Json => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(WireFormat::Binary),
+            1 => Some(WireFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Which `Message` variant a packet holds, readable from the header without
+/// decoding the body.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    SpawnProp,
+    Replication,
+    DeleteProp,
+    PlayerPosition,
+    VoiceChat,
+    AvatarPart,
+    Handshake,
+    Rpc,
+    Authority,
+    /// Several packets for the same peer sent as one, see `encode_batch`.
+    /// Never the kind of a `Message`.
+    Batch,
+    Heartbeat,
+}
+
+impl MessageKind {
+    pub const ALL: [MessageKind; 11] = [
+        MessageKind::SpawnProp,
+        MessageKind::Replication,
+        MessageKind::DeleteProp,
+        MessageKind::PlayerPosition,
+        MessageKind::VoiceChat,
+        MessageKind::AvatarPart,
+        MessageKind::Handshake,
+        MessageKind::Rpc,
+        MessageKind::Authority,
+        MessageKind::Batch,
+        MessageKind::Heartbeat,
+    ];
+
+    pub fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+
+    /// Handshakes have to be readable by peers on any protocol version, so
+    /// they are always json, never version checked, and their kind byte must
+    /// never move.
+    pub fn is_handshake(self) -> bool {
+        self == MessageKind::Handshake
+    }
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Message::SpawnProp(_) => MessageKind::SpawnProp,
+            Message::Replication(_) => MessageKind::Replication,
+            Message::DeleteProp(_) => MessageKind::DeleteProp,
+            Message::PlayerPosition(_) => MessageKind::PlayerPosition,
+            Message::VoiceChat(_) => MessageKind::VoiceChat,
+            Message::AvatarPart(_) => MessageKind::AvatarPart,
+            Message::Handshake(_) => MessageKind::Handshake,
+            Message::Rpc(_) => MessageKind::Rpc,
+            Message::Authority(_) => MessageKind::Authority,
+            Message::Heartbeat(_) => MessageKind::Heartbeat,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    TooShort(usize),
+    UnsupportedVersion(u8),
+    UnknownFormat(u8),
+    UnknownKind(u8),
+    TruncatedBatch,
+    KindMismatch {
+        header: MessageKind,
+        body: MessageKind,
+    },
+    Binary(bincode::Error),
+    Json(serde_json::Error),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::TooShort(len) => write!(f, "packet of {len} bytes is too short"),
+            CodecError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported protocol version {version}, we speak {PROTOCOL_VERSION}"
+            ),
+            CodecError::UnknownFormat(format) => write!(f, "unknown wire format {format}"),
+            CodecError::UnknownKind(kind) => write!(f, "unknown message kind {kind}"),
+            CodecError::TruncatedBatch => write!(f, "batch ends in the middle of a packet"),
+            CodecError::KindMismatch { header, body } => write!(
+                f,
+                "header says {header:?} but the body decoded to {body:?}"
+            ),
+            CodecError::Binary(err) => write!(f, "binary decode failed: {err}"),
+            CodecError::Json(err) => write!(f, "json decode failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+pub fn encode(message: &Message, format: WireFormat) -> Vec<u8> {
+    let format = if message.kind().is_handshake() {
+        WireFormat::Json
+    } else {
+        format
+    };
+    let mut bytes = vec![
+        PROTOCOL_VERSION,
+        format.to_byte(),
+        message.kind().to_byte(),
+    ];
+    match format {
+        WireFormat::Binary => {
+            bincode::serialize_into(&mut bytes, message).expect("Message is always serializable")
+        }
+        WireFormat::Json => {
+            serde_json::to_writer(&mut bytes, message).expect("Message is always serializable")
+        }
+    }
+    bytes
+}
+
+/// Decodes a packet in whichever format its header says it is in, so peers
+/// using different formats can still talk to each other.
+pub fn decode(bytes: &[u8]) -> Result<Message, CodecError> {
+    let (header, body) = split_header(bytes)?;
+    let message = match header.format {
+        WireFormat::Binary => bincode::deserialize::<Message>(body).map_err(CodecError::Binary)?,
+        WireFormat::Json => serde_json::from_slice::<Message>(body).map_err(CodecError::Json)?,
+    };
+    if message.kind() != header.kind {
+        return Err(CodecError::KindMismatch {
+            header: header.kind,
+            body: message.kind(),
+        });
+    }
+    Ok(message)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub format: WireFormat,
+    pub kind: MessageKind,
+}
+
+/// Packs already encoded packets into one, each prefixed with its length as a
+/// little endian u32.
+pub fn encode_batch<'a>(packets: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut bytes = vec![
+        PROTOCOL_VERSION,
+        WireFormat::Binary.to_byte(),
+        MessageKind::Batch.to_byte(),
+    ];
+    for packet in packets {
+        bytes.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(packet);
+    }
+    bytes
+}
+
+/// The packets in a batch, each still needing `decode`.
+pub fn split_batch(bytes: &[u8]) -> Result<Vec<&[u8]>, CodecError> {
+    let (_, mut body) = split_header(bytes)?;
+    let mut packets = vec![];
+    while !body.is_empty() {
+        let (len, rest) = body
+            .split_first_chunk::<BATCH_LEN_PREFIX>()
+            .ok_or(CodecError::TruncatedBatch)?;
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len {
+            return Err(CodecError::TruncatedBatch);
+        }
+        let (packet, rest) = rest.split_at(len);
+        packets.push(packet);
+        body = rest;
+    }
+    Ok(packets)
+}
+
+/// The message type of a packet without decoding or checking anything else.
+pub fn peek_kind(bytes: &[u8]) -> Option<MessageKind> {
+    MessageKind::from_byte(*bytes.get(2)?)
+}
+
+pub fn split_header(bytes: &[u8]) -> Result<(Header, &[u8]), CodecError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(CodecError::TooShort(bytes.len()));
+    }
+    let version = bytes[0];
+    let format = WireFormat::from_byte(bytes[1]).ok_or(CodecError::UnknownFormat(bytes[1]))?;
+    let kind = MessageKind::from_byte(bytes[2]).ok_or(CodecError::UnknownKind(bytes[2]))?;
+    if version != PROTOCOL_VERSION && !kind.is_handshake() {
+        return Err(CodecError::UnsupportedVersion(version));
+    }
+    Ok((
+        Header {
+            version,
+            format,
+            kind,
+        },
+        &bytes[HEADER_LEN..],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::test_app::sample_messages;
+    use std::collections::HashSet;
+
+    #[test]
+    fn every_message_round_trips() {
+        let messages = sample_messages();
+        let kinds = messages.iter().map(Message::kind).collect::<HashSet<_>>();
+        // everything but `Batch`, so a new variant can't be left out here
+        assert_eq!(kinds.len(), MessageKind::ALL.len() - 1);
+        for message in messages {
+            for format in [WireFormat::Binary, WireFormat::Json] {
+                let bytes = encode(&message, format);
+                let decoded = decode(&bytes)
+                    .unwrap_or_else(|err| panic!("{message:?} as {format:?}: {err}"));
+                assert_eq!(encode(&decoded, format), bytes, "{message:?} as {format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn handshakes_are_json_and_readable_on_any_version() {
+        let hello = sample_messages()
+            .into_iter()
+            .find(|message| message.kind().is_handshake())
+            .unwrap();
+        let mut bytes = encode(&hello, WireFormat::Binary);
+        assert_eq!(bytes[1], WireFormat::Json.to_byte());
+        bytes[0] = PROTOCOL_VERSION.wrapping_add(1);
+        assert!(decode(&bytes).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_versions_and_garbage() {
+        let message = sample_messages().remove(0);
+        let mut bytes = encode(&message, WireFormat::Binary);
+        bytes[0] = PROTOCOL_VERSION.wrapping_sub(1);
+        assert!(matches!(
+            decode(&bytes),
+            Err(CodecError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(decode(&[PROTOCOL_VERSION]), Err(CodecError::TooShort(1))));
+        assert!(matches!(
+            decode(&[PROTOCOL_VERSION, 9, 0]),
+            Err(CodecError::UnknownFormat(9))
+        ));
+        assert!(matches!(
+            decode(&[PROTOCOL_VERSION, 0, 200]),
+            Err(CodecError::UnknownKind(200))
+        ));
+    }
+
+    #[test]
+    fn header_kind_has_to_match_the_body() {
+        let mut bytes = encode(&sample_messages().remove(0), WireFormat::Binary);
+        bytes[2] = MessageKind::DeleteProp.to_byte();
+        assert!(decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn batches_split_into_their_packets() {
+        let packets = sample_messages()
+            .iter()
+            .map(|message| encode(message, WireFormat::Binary))
+            .collect::<Vec<_>>();
+        let batch = encode_batch(packets.iter().map(Vec::as_slice));
+        assert_eq!(peek_kind(&batch), Some(MessageKind::Batch));
+        let split = split_batch(&batch).unwrap();
+        assert_eq!(split, packets.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        assert!(matches!(
+            split_batch(&batch[..batch.len() - 1]),
+            Err(CodecError::TruncatedBatch)
+        ));
+    }
+}
@@ -0,0 +1,505 @@
+use crate::networking::transport::{Channel, NetworkTransport};
+use bevy::prelude::*;
+use bevy_matchbox::matchbox_socket::{Packet, PeerState};
+use bevy_matchbox::prelude::PeerId;
+use futures::channel::mpsc::SendError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// What happens to packets on one channel, on their way to us and on their
+/// way out alike. Times are in seconds, chances from 0 to 1.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelConditions {
+    pub delay: f32,
+    /// Up to this much extra delay, different for every packet.
+    pub jitter: f32,
+    pub loss: f32,
+    pub duplicate: f32,
+    /// Chance a packet is held back for another `delay + jitter` so the ones
+    /// after it overtake it. Nothing else ever arrives out of order, jitter
+    /// included.
+    pub reorder: f32,
+}
+
+impl ChannelConditions {
+    /// What a reliable channel can actually look like, slow but never losing
+    /// or reordering anything.
+    pub fn reliable(&self) -> Self {
+        Self {
+            loss: 0.0,
+            reorder: 0.0,
+            duplicate: 0.0,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    pub enabled: bool,
+    pub reliable: ChannelConditions,
+    pub unreliable: ChannelConditions,
+}
+
+impl LinkConditions {
+    /// Roughly a busy home Wi-Fi.
+    pub fn bad_wifi() -> Self {
+        let unreliable = ChannelConditions {
+            delay: 0.08,
+            jitter: 0.06,
+            loss: 0.05,
+            duplicate: 0.01,
+            reorder: 0.02,
+        };
+        Self {
+            enabled: true,
+            reliable: unreliable.reliable(),
+            unreliable,
+        }
+    }
+
+    pub fn channel(&self, channel: Channel) -> &ChannelConditions {
+        match channel {
+            Channel::Reliable => &self.reliable,
+            Channel::Unreliable => &self.unreliable,
+        }
+    }
+}
+
+/// Shared with every `ConditionedTransport` the app opens, changing it takes
+/// effect on the next packet. Off by default. Turning it on for one peer is
+/// enough, on both ends every packet goes through it twice.
+#[derive(Resource, Clone, Default)]
+pub struct NetworkConditions {
+    link: Arc<Mutex<LinkConditions>>,
+    /// What delays are measured on, in seconds as `f64` bits. Kept up with
+    /// `Time<Real>` by `tick_network_conditions`, so apps stepped by hand
+    /// hold packets back by steps too.
+    now: Arc<AtomicU64>,
+}
+
+impl NetworkConditions {
+    pub fn get(&self) -> LinkConditions {
+        self.link.lock().unwrap().clone()
+    }
+
+    pub fn set(&self, conditions: LinkConditions) {
+        *self.link.lock().unwrap() = conditions;
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.link.lock().unwrap().enabled = enabled;
+    }
+
+    pub fn now(&self) -> f64 {
+        f64::from_bits(self.now.load(Ordering::Relaxed))
+    }
+
+    pub fn set_now(&self, now: f64) {
+        self.now.store(now.to_bits(), Ordering::Relaxed);
+    }
+}
+
+pub fn tick_network_conditions(time: Res<Time<Real>>, conditions: Res<NetworkConditions>) {
+    conditions.set_now(time.elapsed_seconds_f64());
+}
+
+struct Delayed {
+    deliver_at: f64,
+    /// Who it is from on the way in, who it is for on the way out.
+    peer: PeerId,
+    packet: Packet,
+}
+
+/// xorshift64*, the same seed gives the same losses.
+struct ConditionRng(u64);
+
+impl ConditionRng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck on zero
+        Self(seed.max(1))
+    }
+
+    /// Uniform in `0..1`.
+    fn random(&mut self) -> f32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn chance(&mut self, chance: f32) -> bool {
+        chance > 0.0 && self.random() < chance
+    }
+
+    fn delay(&mut self, conditions: &ChannelConditions) -> f64 {
+        (conditions.delay + conditions.jitter * self.random()) as f64
+    }
+}
+
+/// The packets of one channel going one way.
+#[derive(Default)]
+struct Lane {
+    /// In the order they will be delivered.
+    delayed: Vec<Delayed>,
+    /// When the last packet that wasn't held back gets delivered.
+    in_order: f64,
+}
+
+impl Lane {
+    /// Loses, duplicates or holds back `packet` the way `conditions` say.
+    fn push(
+        &mut self,
+        rng: &mut ConditionRng,
+        conditions: &ChannelConditions,
+        now: f64,
+        peer: PeerId,
+        packet: Packet,
+    ) {
+        if rng.chance(conditions.loss) {
+            return;
+        }
+        if rng.chance(conditions.duplicate) {
+            self.schedule(rng, conditions, now, peer, packet.clone());
+        }
+        self.schedule(rng, conditions, now, peer, packet);
+    }
+
+    fn schedule(
+        &mut self,
+        rng: &mut ConditionRng,
+        conditions: &ChannelConditions,
+        now: f64,
+        peer: PeerId,
+        packet: Packet,
+    ) {
+        let mut deliver_at = now + rng.delay(conditions);
+        let held_back = rng.chance(conditions.reorder);
+        if held_back {
+            deliver_at += rng.delay(conditions);
+        } else {
+            // jitter alone never reorders
+            deliver_at = deliver_at.max(self.in_order);
+            self.in_order = deliver_at;
+        }
+        let index = self
+            .delayed
+            .iter()
+            .rposition(|delayed| delayed.deliver_at <= deliver_at)
+            .map_or(0, |index| index + 1);
+        self.delayed.insert(
+            index,
+            Delayed {
+                deliver_at,
+                peer,
+                packet,
+            },
+        );
+    }
+
+    fn take_due(&mut self, now: f64) -> Vec<(PeerId, Packet)> {
+        let due = self
+            .delayed
+            .iter()
+            .take_while(|delayed| delayed.deliver_at <= now)
+            .count();
+        self.delayed
+            .drain(..due)
+            .map(|delayed| (delayed.peer, delayed.packet))
+            .collect()
+    }
+}
+
+/// Puts `NetworkConditions` between another transport and us, for what we
+/// receive and what we send. Packets we send are handed on whenever the
+/// transport is used next, `update_peers` is called every frame.
+pub struct ConditionedTransport {
+    inner: Box<dyn NetworkTransport>,
+    conditions: NetworkConditions,
+    rng: ConditionRng,
+    /// Per channel.
+    incoming: [Lane; 2],
+    outgoing: [Lane; 2],
+}
+
+impl ConditionedTransport {
+    pub fn new(inner: impl NetworkTransport, conditions: NetworkConditions) -> Self {
+        Self::with_seed(inner, conditions, Uuid::new_v4().as_u64_pair().0)
+    }
+
+    /// The same seed and the same packets at the same times give the same
+    /// losses, for tests.
+    pub fn with_seed(
+        inner: impl NetworkTransport,
+        conditions: NetworkConditions,
+        seed: u64,
+    ) -> Self {
+        Self {
+            inner: Box::new(inner),
+            conditions,
+            rng: ConditionRng::new(seed),
+            incoming: Default::default(),
+            outgoing: Default::default(),
+        }
+    }
+
+    fn now(&self) -> f64 {
+        self.conditions.now()
+    }
+
+    /// What `channel` does to packets right now, `None` when they go straight
+    /// through.
+    fn current(&self, channel: Channel, lane: &Lane) -> Option<ChannelConditions> {
+        let link = self.conditions.get();
+        if link.enabled {
+            Some(link.channel(channel).clone())
+        } else if !lane.delayed.is_empty() {
+            // turned off, whatever is still delayed goes out in order
+            Some(ChannelConditions::default())
+        } else {
+            None
+        }
+    }
+
+    fn flush_outgoing(&mut self) {
+        let now = self.now();
+        for channel in Channel::ALL {
+            for (peer, packet) in self.outgoing[channel.index()].take_due(now) {
+                self.inner.send(channel, packet, peer);
+            }
+        }
+    }
+}
+
+impl NetworkTransport for ConditionedTransport {
+    fn id(&mut self) -> Option<PeerId> {
+        self.inner.id()
+    }
+
+    fn update_peers(&mut self) -> Vec<(PeerId, PeerState)> {
+        self.flush_outgoing();
+        let changes = self.inner.update_peers();
+        for (peer, state) in changes.iter() {
+            if matches!(state, PeerState::Disconnected) {
+                for lane in self.incoming.iter_mut().chain(self.outgoing.iter_mut()) {
+                    lane.delayed.retain(|delayed| delayed.peer != *peer);
+                }
+            }
+        }
+        changes
+    }
+
+    fn connected_peers(&self) -> Vec<PeerId> {
+        self.inner.connected_peers()
+    }
+
+    fn send(&mut self, channel: Channel, packet: Packet, peer: PeerId) {
+        let lane = &self.outgoing[channel.index()];
+        let Some(conditions) = self.current(channel, lane) else {
+            self.inner.send(channel, packet, peer);
+            return;
+        };
+        let now = self.now();
+        self.outgoing[channel.index()].push(&mut self.rng, &conditions, now, peer, packet);
+        self.flush_outgoing();
+    }
+
+    /// Packets that get delayed can't fail here, if the inner transport
+    /// refuses them later they are lost like any other.
+    fn try_send(
+        &mut self,
+        channel: Channel,
+        packet: Packet,
+        peer: PeerId,
+    ) -> Result<(), SendError> {
+        let lane = &self.outgoing[channel.index()];
+        if self.current(channel, lane).is_none() {
+            return self.inner.try_send(channel, packet, peer);
+        }
+        self.send(channel, packet, peer);
+        Ok(())
+    }
+
+    fn receive(&mut self, channel: Channel) -> Vec<(PeerId, Packet)> {
+        let received = self.inner.receive(channel);
+        let lane = &self.incoming[channel.index()];
+        let Some(conditions) = self.current(channel, lane) else {
+            return received;
+        };
+        let now = self.now();
+        let lane = &mut self.incoming[channel.index()];
+        for (from, packet) in received {
+            lane.push(&mut self.rng, &conditions, now, from, packet);
+        }
+        lane.take_due(now)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::index::{PlayerIndex, PropIndex};
+    use crate::networking::interpolation::SnapshotBuffer;
+    use crate::networking::test_app::{conditioned_app, spawn_local_player, update_all};
+    use crate::networking::transport::LoopbackHub;
+    use crate::networking::{Authority, PropUuid};
+    use crate::props::PropDescription;
+    use avian3d::prelude::{AngularVelocity, LinearVelocity, Position, Rotation};
+    use std::collections::HashSet;
+    use unavi_player::LocalPlayer;
+
+    const PACKETS: u32 = 10_000;
+
+    fn bad_link() -> ChannelConditions {
+        ChannelConditions {
+            delay: 0.005,
+            jitter: 0.0,
+            loss: 0.1,
+            duplicate: 0.05,
+            reorder: 0.1,
+        }
+    }
+
+    /// Sends `PACKETS` numbered packets with `unreliable` on the sending or
+    /// on the receiving end, and returns the numbers in the order they
+    /// arrived.
+    fn send_through(unreliable: ChannelConditions, seed: u64, on_sender: bool) -> Vec<u32> {
+        let hub = LoopbackHub::new();
+        let conditions = NetworkConditions::default();
+        conditions.set(LinkConditions {
+            enabled: true,
+            reliable: unreliable.reliable(),
+            unreliable,
+        });
+        let conditioned = ConditionedTransport::with_seed(hub.connect(), conditions.clone(), seed);
+        let (mut sender, mut receiver): (Box<dyn NetworkTransport>, Box<dyn NetworkTransport>) =
+            if on_sender {
+                (Box::new(conditioned), Box::new(hub.connect()))
+            } else {
+                (Box::new(hub.connect()), Box::new(conditioned))
+            };
+        let to = receiver.id().unwrap();
+        for i in 0..PACKETS {
+            sender.send(Channel::Unreliable, i.to_le_bytes().to_vec().into(), to);
+        }
+        let mut arrived = receiver.receive(Channel::Unreliable);
+        // well past `delay` twice over, when the held back ones are due
+        conditions.set_now(0.05);
+        sender.update_peers();
+        arrived.extend(receiver.receive(Channel::Unreliable));
+        arrived
+            .into_iter()
+            .map(|(_, packet)| u32::from_le_bytes(packet[..4].try_into().unwrap()))
+            .collect()
+    }
+
+    /// Distinct packets, extra copies and packets that arrived after a later
+    /// one.
+    fn stats(arrived: &[u32]) -> (usize, usize, usize) {
+        let unique = arrived.iter().collect::<HashSet<_>>().len();
+        let mut latest = None;
+        let mut overtaken = 0;
+        for &i in arrived {
+            if latest.is_some_and(|latest| latest > i) {
+                overtaken += 1;
+            }
+            latest = latest.max(Some(i));
+        }
+        (unique, arrived.len() - unique, overtaken)
+    }
+
+    fn assert_near(what: &str, actual: usize, expected: f32, tolerance: f32) {
+        assert!(
+            (actual as f32 - expected).abs() <= tolerance,
+            "{what}: {actual}, expected about {expected}"
+        );
+    }
+
+    #[test]
+    fn packets_are_lost_duplicated_and_reordered_at_the_odds() {
+        let link = bad_link();
+        let (unique, copies, overtaken) = stats(&send_through(link.clone(), 7, false));
+        let kept = PACKETS as f32 * (1.0 - link.loss);
+        assert_near("kept", unique, kept, 200.0);
+        assert_near("copies", copies, kept * link.duplicate, 100.0);
+        assert_near(
+            "overtaken",
+            overtaken,
+            kept * (1.0 + link.duplicate) * link.reorder,
+            150.0,
+        );
+    }
+
+    #[test]
+    fn same_seed_same_packets() {
+        assert_eq!(
+            send_through(bad_link(), 7, false),
+            send_through(bad_link(), 7, false)
+        );
+        assert_ne!(
+            send_through(bad_link(), 7, false),
+            send_through(bad_link(), 8, false)
+        );
+    }
+
+    #[test]
+    fn outgoing_packets_are_conditioned_too() {
+        let link = bad_link();
+        let (unique, copies, _) = stats(&send_through(link.clone(), 7, true));
+        let kept = PACKETS as f32 * (1.0 - link.loss);
+        assert_near("kept", unique, kept, 200.0);
+        assert_near("copies", copies, kept * link.duplicate, 100.0);
+
+        // a perfect link keeps everything, in order
+        let clean = send_through(ChannelConditions::default(), 7, true);
+        assert_eq!(clean, (0..PACKETS).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn players_and_props_converge_over_bad_wifi() {
+        let hub = LoopbackHub::new();
+        let mut apps = vec![
+            conditioned_app(&hub, LinkConditions::bad_wifi(), 1),
+            conditioned_app(&hub, LinkConditions::bad_wifi(), 2),
+        ];
+        let alice = spawn_local_player(&mut apps[0], "alice");
+        spawn_local_player(&mut apps[1], "bob");
+        let prop_uuid = PropUuid("crate".to_string());
+        let prop = apps[0]
+            .world_mut()
+            .spawn((
+                prop_uuid.clone(),
+                Authority::new(alice.clone()),
+                PropDescription::cube(0.25, [1.0, 0.0, 0.0]),
+                Position::default(),
+                Rotation::default(),
+                LinearVelocity::default(),
+                AngularVelocity::default(),
+            ))
+            .id();
+        update_all(&mut apps, 60 * 3);
+
+        // both move once everyone is connected, then stand still
+        let player_at = Vec3::new(3.0, 0.0, 1.0);
+        let prop_at = Vec3::new(-2.0, 1.0, 0.0);
+        let world = apps[0].world_mut();
+        let mut players = world.query_filtered::<&mut Position, With<LocalPlayer>>();
+        players.single_mut(world).0 = player_at;
+        world.get_mut::<Position>(prop).unwrap().0 = prop_at;
+        update_all(&mut apps, 60 * 5);
+
+        let world = apps[1].world();
+        let player = world.resource::<PlayerIndex>().get(&alice).unwrap();
+        let prop = world.resource::<PropIndex>().get(&prop_uuid).unwrap();
+        for (entity, at) in [(player, player_at), (prop, prop_at)] {
+            let buffer = world.get::<SnapshotBuffer>(entity).unwrap();
+            let latest = buffer.latest().unwrap().position;
+            assert!(latest.distance(at) < 1e-4, "{latest} instead of {at}");
+        }
+    }
+}
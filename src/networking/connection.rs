@@ -0,0 +1,569 @@
+#[cfg(not(target_family = "wasm"))]
+use crate::networking::signaling::{LocalSignalingServer, SignalingServerPlugin};
+use crate::networking::conditions::{ConditionedTransport, NetworkConditions};
+use crate::networking::errors::BlockedPeers;
+#[cfg(not(target_family = "wasm"))]
+use crate::networking::recording::ReplayTransport;
+use crate::networking::recording::SessionRecorder;
+use crate::networking::transport::{NetworkSocket, OfflineTransport, PeerDisconnected};
+use crate::networking::ExternalPlayer;
+use bevy::prelude::*;
+use bevy_matchbox::matchbox_socket::{RtcIceServerConfig, WebRtcSocketBuilder};
+use bevy_matchbox::prelude::PeerId;
+use bevy_matchbox::MatchboxSocket;
+use std::sync::Arc;
+
+const DEFAULT_SIGNALING_URL: &str = "wss://mb.v-sekai.cloud";
+const DEFAULT_ROOM: &str = "hello5";
+
+/// Where to find other players. Read once at startup with
+/// [`ConnectionSettings::from_environment`], changing it later reconnects.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct ConnectionSettings {
+    /// The matchbox signaling server, without the room.
+    pub signaling_url: String,
+    pub room: String,
+    /// STUN and TURN urls, matchbox's default STUN server if empty.
+    pub ice_servers: Vec<String>,
+    pub ice_username: Option<String>,
+    pub ice_credential: Option<String>,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            signaling_url: DEFAULT_SIGNALING_URL.to_string(),
+            room: DEFAULT_ROOM.to_string(),
+            ice_servers: vec![],
+            ice_username: None,
+            ice_credential: None,
+        }
+    }
+}
+
+impl ConnectionSettings {
+    /// The defaults, overridden by `P2PVR_*` environment variables, then by
+    /// command line flags. On the web the page's query string is used instead,
+    /// e.g. `?room=party&signaling=wss://example.com&ice=stun:a,turn:b`.
+    pub fn from_environment() -> Self {
+        let mut settings = Self::default();
+        #[cfg(not(target_family = "wasm"))]
+        {
+            settings.apply(|name| std::env::var(format!("P2PVR_{}", name.to_uppercase())).ok());
+            let args = std::env::args().skip(1).collect::<Vec<_>>();
+            settings.apply(|name| flag(&args, name));
+        }
+        #[cfg(target_family = "wasm")]
+        {
+            let query = web_sys::window()
+                .and_then(|window| window.location().search().ok())
+                .and_then(|search| web_sys::UrlSearchParams::new_with_str(&search).ok());
+            if let Some(query) = query {
+                settings.apply(|name| query.get(name));
+            }
+        }
+        settings
+    }
+
+    /// Overrides whatever `lookup` has a value for. Names are `signaling`,
+    /// `room`, `ice`, `ice_username` and `ice_credential`, `ice` being a comma
+    /// separated list.
+    pub fn apply(&mut self, lookup: impl Fn(&str) -> Option<String>) {
+        if let Some(signaling_url) = lookup("signaling") {
+            self.signaling_url = signaling_url;
+        }
+        if let Some(room) = lookup("room") {
+            self.room = room;
+        }
+        if let Some(ice) = lookup("ice") {
+            self.ice_servers = ice
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(username) = lookup("ice_username") {
+            self.ice_username = Some(username);
+        }
+        if let Some(credential) = lookup("ice_credential") {
+            self.ice_credential = Some(credential);
+        }
+    }
+
+    pub fn room_url(&self) -> String {
+        format!("{}/{}", self.signaling_url.trim_end_matches('/'), self.room)
+    }
+
+    /// Opens a socket behind `conditions`, which let everything through
+    /// untouched unless they are turned on.
+    pub fn open_socket(&self, conditions: &NetworkConditions) -> NetworkSocket {
+        let mut builder = WebRtcSocketBuilder::new(self.room_url())
+            .add_reliable_channel()
+            .add_unreliable_channel();
+        if !self.ice_servers.is_empty() {
+            builder = builder.ice_server(RtcIceServerConfig {
+                urls: self.ice_servers.clone(),
+                username: self.ice_username.clone(),
+                credential: self.ice_credential.clone(),
+            });
+        }
+        NetworkSocket::new(ConditionedTransport::new(
+            MatchboxSocket::from(builder.build()),
+            conditions.clone(),
+        ))
+    }
+}
+
+/// Starts a signaling server in this process and connects to it when asked to
+/// with `--host-signaling <port>` or `P2PVR_HOST_SIGNALING`, port 0 taking any
+/// free one. Others on the network join with `--signaling ws://<host>:<port>`.
+#[cfg(not(target_family = "wasm"))]
+pub fn host_signaling_if_asked(app: &mut App, settings: &mut ConnectionSettings) {
+    let Some(port) = native_option("host_signaling") else {
+        return;
+    };
+    let port = match port.parse() {
+        Ok(port) => port,
+        Err(e) => {
+            error!("not hosting signaling, bad port {port:?}: {e}");
+            return;
+        }
+    };
+    app.add_plugins(SignalingServerPlugin { port });
+    if let Some(server) = app.world().get_resource::<LocalSignalingServer>() {
+        settings.signaling_url = server.url();
+    }
+}
+
+/// Records the session to the file given with `--record <path>` or
+/// `P2PVR_RECORD`.
+#[cfg(not(target_family = "wasm"))]
+pub fn record_if_asked(app: &App) {
+    let Some(path) = native_option("record") else {
+        return;
+    };
+    if let Err(e) = app.world().resource::<SessionRecorder>().start(&path) {
+        error!("couldn't record the session to {path}: {e}");
+    }
+}
+
+/// Plays back the recording given with `--replay <path>` or `P2PVR_REPLAY`
+/// instead of connecting anywhere. Returns whether it did.
+#[cfg(not(target_family = "wasm"))]
+pub fn replay_if_asked(app: &mut App) -> bool {
+    let Some(path) = native_option("replay") else {
+        return false;
+    };
+    match ReplayTransport::open(&path) {
+        Ok(replay) => {
+            info!("replaying {path}");
+            app.insert_resource(NetworkSocket::new(replay));
+            true
+        }
+        Err(e) => {
+            error!("couldn't replay {path}: {e}");
+            false
+        }
+    }
+}
+
+/// A command line flag, or the `P2PVR_*` environment variable if there is no
+/// flag.
+#[cfg(not(target_family = "wasm"))]
+fn native_option(name: &str) -> Option<String> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    flag(&args, name).or_else(|| std::env::var(format!("P2PVR_{}", name.to_uppercase())).ok())
+}
+
+/// `--name value` or `--name=value`, with underscores in `name` written as
+/// dashes.
+#[cfg(not(target_family = "wasm"))]
+fn flag(args: &[String], name: &str) -> Option<String> {
+    let flag = format!("--{}", name.replace('_', "-"));
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if *arg == flag {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg.strip_prefix(&format!("{flag}=")) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Opens the socket for `ConnectionSettings`, a WebRTC one behind
+/// `NetworkConditions` unless something else is put in its place, like a
+/// `LoopbackHub` in tests.
+#[derive(Resource, Clone)]
+pub struct SocketOpener(
+    Arc<dyn Fn(&ConnectionSettings, &NetworkConditions) -> NetworkSocket + Send + Sync>,
+);
+
+impl Default for SocketOpener {
+    fn default() -> Self {
+        Self::new(ConnectionSettings::open_socket)
+    }
+}
+
+impl SocketOpener {
+    pub fn new(
+        open: impl Fn(&ConnectionSettings, &NetworkConditions) -> NetworkSocket
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        Self(Arc::new(open))
+    }
+
+    pub fn open(
+        &self,
+        settings: &ConnectionSettings,
+        conditions: &NetworkConditions,
+    ) -> NetworkSocket {
+        (self.0)(settings, conditions)
+    }
+}
+
+/// Where we are with the signaling server, driven by the socket.
+#[derive(States, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    /// No `ConnectionSettings` yet.
+    #[default]
+    Disconnected,
+    /// Waiting for the signaling server to hand us an id.
+    Connecting,
+    Connected,
+    /// Lost the signaling server, either waiting out the backoff or trying
+    /// again.
+    Reconnecting,
+}
+
+/// Sent alongside every `ConnectionState` change, with what the UI might want
+/// to show about it.
+#[derive(Event, Clone, Debug)]
+pub enum ConnectionEvent {
+    Connecting { url: String },
+    Connected { id: PeerId },
+    Lost,
+    /// The next attempt starts in `delay` seconds.
+    Retrying { attempt: u32, delay: f32 },
+}
+
+/// Sent when a peer turns out to be a player we lost recently, their
+/// `ExternalPlayer` is reused rather than spawning another one.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct PeerResumed {
+    pub peer: PeerId,
+    pub player: Entity,
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct ReconnectSettings {
+    /// Seconds before the first attempt, doubling with every failed one up
+    /// to `max_delay`.
+    pub initial_delay: f32,
+    pub max_delay: f32,
+    pub multiplier: f32,
+    /// How long the player of a peer we lost is kept around waiting for them
+    /// to come back, in seconds.
+    pub resume_grace: f32,
+}
+
+impl Default for ReconnectSettings {
+    fn default() -> Self {
+        Self {
+            initial_delay: 1.0,
+            max_delay: 30.0,
+            multiplier: 2.0,
+            resume_grace: 30.0,
+        }
+    }
+}
+
+impl ReconnectSettings {
+    pub fn delay(&self, attempt: u32) -> f32 {
+        let doublings = attempt.saturating_sub(1).min(31) as i32;
+        (self.initial_delay * self.multiplier.powi(doublings)).min(self.max_delay)
+    }
+}
+
+/// The attempts since we were last connected.
+#[derive(Resource, Default, Debug)]
+pub struct Reconnect {
+    attempt: u32,
+    retry_at: Option<f32>,
+}
+
+impl Reconnect {
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+}
+
+/// An external player whose peer went away. Kept for
+/// `ReconnectSettings::resume_grace` in case they come back as someone new.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Departed {
+    pub since: f32,
+}
+
+/// Opens the socket the first time settings show up and reopens it whenever
+/// they change. Everyone on the old socket is treated as disconnected, so the
+/// usual cleanup runs for them, and every external player goes away with it.
+/// Runs in `PreUpdate` so the socket is there for everything in `Update`.
+pub fn apply_connection_settings(
+    mut commands: Commands,
+    settings: Res<ConnectionSettings>,
+    opener: Res<SocketOpener>,
+    conditions: Res<NetworkConditions>,
+    recorder: Res<SessionRecorder>,
+    blocked: Res<BlockedPeers>,
+    socket: Option<Res<NetworkSocket>>,
+    external_players: Query<Entity, With<ExternalPlayer>>,
+    mut disconnected: EventWriter<PeerDisconnected>,
+    mut reconnect: ResMut<Reconnect>,
+    mut next_state: ResMut<NextState<ConnectionState>>,
+    mut events: EventWriter<ConnectionEvent>,
+) {
+    if let Some(socket) = socket {
+        info!(
+            "connection settings changed, reconnecting to {}",
+            settings.room_url()
+        );
+        for peer in socket.connected_peers() {
+            disconnected.send(PeerDisconnected(peer));
+        }
+        for player in external_players.iter() {
+            commands.entity(player).despawn_recursive();
+        }
+    } else {
+        info!("connecting to {}", settings.room_url());
+    }
+    commands.insert_resource(
+        opener
+            .open(&settings, &conditions)
+            .with_recorder(recorder.clone())
+            .with_blocked(blocked.clone()),
+    );
+    *reconnect = Reconnect::default();
+    next_state.set(ConnectionState::Connecting);
+    events.send(ConnectionEvent::Connecting {
+        url: settings.room_url(),
+    });
+}
+
+/// Watches the socket. Once it closes its peers are treated as disconnected
+/// and it is swapped for an `OfflineTransport` until the backoff runs out and
+/// we try again with the same settings. Our `PlayerUuid` stays the same, so
+/// everyone who still has our player reattaches it when we say hello again.
+pub fn update_connection_state(
+    mut commands: Commands,
+    time: Res<Time>,
+    settings: Option<Res<ConnectionSettings>>,
+    opener: Res<SocketOpener>,
+    conditions: Res<NetworkConditions>,
+    recorder: Res<SessionRecorder>,
+    blocked: Res<BlockedPeers>,
+    reconnect_settings: Res<ReconnectSettings>,
+    mut reconnect: ResMut<Reconnect>,
+    socket: Option<ResMut<NetworkSocket>>,
+    state: Res<State<ConnectionState>>,
+    mut next_state: ResMut<NextState<ConnectionState>>,
+    mut events: EventWriter<ConnectionEvent>,
+    mut disconnected: EventWriter<PeerDisconnected>,
+) {
+    let (Some(settings), Some(mut socket)) = (settings, socket) else {
+        return;
+    };
+    let now = time.elapsed_seconds();
+    if let Some(retry_at) = reconnect.retry_at {
+        if now >= retry_at {
+            info!(
+                "reconnecting to {}, attempt {}",
+                settings.room_url(),
+                reconnect.attempt
+            );
+            reconnect.retry_at = None;
+            let format = socket.format();
+            commands.insert_resource(
+                opener
+                    .open(&settings, &conditions)
+                    .with_format(format)
+                    .with_recorder(recorder.clone())
+                    .with_blocked(blocked.clone()),
+            );
+            events.send(ConnectionEvent::Connecting {
+                url: settings.room_url(),
+            });
+        }
+        return;
+    }
+    if socket.is_closed() {
+        warn!("lost the connection to {}", settings.room_url());
+        for peer in socket.connected_peers() {
+            disconnected.send(PeerDisconnected(peer));
+        }
+        socket.replace_transport(OfflineTransport);
+        if *state.get() == ConnectionState::Connected {
+            events.send(ConnectionEvent::Lost);
+        }
+        reconnect.attempt += 1;
+        let delay = reconnect_settings.delay(reconnect.attempt);
+        reconnect.retry_at = Some(now + delay);
+        events.send(ConnectionEvent::Retrying {
+            attempt: reconnect.attempt,
+            delay,
+        });
+        next_state.set(ConnectionState::Reconnecting);
+        return;
+    }
+    if *state.get() != ConnectionState::Connected {
+        if let Some(id) = socket.id() {
+            info!("connected to {} as {}", settings.room_url(), id);
+            reconnect.attempt = 0;
+            next_state.set(ConnectionState::Connected);
+            events.send(ConnectionEvent::Connected { id });
+        }
+    }
+}
+
+/// Gives up on players that didn't come back in time.
+pub fn expire_departed_players(
+    mut commands: Commands,
+    time: Res<Time>,
+    settings: Res<ReconnectSettings>,
+    departed: Query<(Entity, &Departed)>,
+) {
+    let now = time.elapsed_seconds();
+    for (entity, departed) in departed.iter() {
+        if now - departed.since >= settings.resume_grace {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::test_app::{
+        external_players, headless_app, peer_id, spawn_local_player, update_all,
+    };
+    use crate::networking::transport::{LoopbackHub, NetworkTransport};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Resource, Default)]
+    struct Seen(Vec<ConnectionEvent>);
+
+    fn collect_events(mut events: EventReader<ConnectionEvent>, mut seen: ResMut<Seen>) {
+        seen.0.extend(events.read().cloned());
+    }
+
+    /// An app that opens its sockets on `hub` once it gets settings, ones
+    /// that close right away while `online` is false.
+    fn connecting_app(hub: &LoopbackHub, online: Arc<AtomicBool>) -> App {
+        let mut app = headless_app(hub);
+        app.world_mut().remove_resource::<NetworkSocket>();
+        let hub = hub.clone();
+        app.insert_resource(SocketOpener::new(move |_, _| {
+            let mut transport = hub.connect();
+            if !online.load(Ordering::Relaxed) {
+                hub.disconnect(transport.id().unwrap());
+            }
+            NetworkSocket::new(transport)
+        }))
+        .init_resource::<Seen>()
+        .add_systems(Update, collect_events);
+        app
+    }
+
+    fn state(app: &App) -> ConnectionState {
+        *app.world().resource::<State<ConnectionState>>().get()
+    }
+
+    fn retry_delays(app: &App) -> Vec<f32> {
+        app.world()
+            .resource::<Seen>()
+            .0
+            .iter()
+            .filter_map(|event| match event {
+                ConnectionEvent::Retrying { delay, .. } => Some(*delay),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn connects_then_backs_off_longer_every_time() {
+        let hub = LoopbackHub::new();
+        let online = Arc::new(AtomicBool::new(true));
+        let mut app = connecting_app(&hub, online.clone());
+        spawn_local_player(&mut app, "alice");
+        app.insert_resource(ConnectionSettings::default());
+        update_all(std::slice::from_mut(&mut app), 3);
+        assert_eq!(state(&app), ConnectionState::Connected);
+        let id = peer_id(&mut app);
+        assert!(matches!(
+            app.world().resource::<Seen>().0[..],
+            [
+                ConnectionEvent::Connecting { .. },
+                ConnectionEvent::Connected { id: connected }
+            ] if connected == id
+        ));
+
+        // the signaling server goes away and stays away for a while
+        online.store(false, Ordering::Relaxed);
+        hub.disconnect(id);
+        update_all(std::slice::from_mut(&mut app), 2);
+        assert_eq!(state(&app), ConnectionState::Reconnecting);
+        assert!(matches!(
+            app.world().resource::<Seen>().0[2],
+            ConnectionEvent::Lost
+        ));
+        // 1 + 2 seconds of failed attempts, the third one waits 4
+        update_all(std::slice::from_mut(&mut app), 60 * 3 + 10);
+        assert_eq!(retry_delays(&app), vec![1.0, 2.0, 4.0]);
+        assert_eq!(app.world().resource::<Reconnect>().attempt(), 3);
+
+        online.store(true, Ordering::Relaxed);
+        update_all(std::slice::from_mut(&mut app), 60 * 4 + 10);
+        assert_eq!(state(&app), ConnectionState::Connected);
+        assert_eq!(app.world().resource::<Reconnect>().attempt(), 0);
+        assert_ne!(peer_id(&mut app), id);
+    }
+
+    #[test]
+    fn new_settings_reconnect() {
+        let hub = LoopbackHub::new();
+        let mut apps = vec![
+            connecting_app(&hub, Arc::new(AtomicBool::new(true))),
+            headless_app(&hub),
+        ];
+        spawn_local_player(&mut apps[0], "alice");
+        let bob = spawn_local_player(&mut apps[1], "bob");
+        apps[0].insert_resource(ConnectionSettings::default());
+        update_all(&mut apps, 10);
+        assert_eq!(external_players(&mut apps[0]), vec![bob.clone()]);
+        let id = peer_id(&mut apps[0]);
+
+        apps[0].insert_resource(ConnectionSettings {
+            room: "elsewhere".to_string(),
+            ..default()
+        });
+        update_all(std::slice::from_mut(&mut apps[0]), 1);
+        // everyone on the old socket went away with it
+        assert!(external_players(&mut apps[0]).is_empty());
+        assert_ne!(peer_id(&mut apps[0]), id);
+        let seen = &apps[0].world().resource::<Seen>().0;
+        assert!(matches!(
+            seen.last(),
+            Some(ConnectionEvent::Connecting { url }) if url.ends_with("/elsewhere")
+        ));
+
+        // the hub doesn't know about rooms, so bob is right back
+        update_all(&mut apps, 10);
+        assert_eq!(state(&apps[0]), ConnectionState::Connected);
+        assert_eq!(external_players(&mut apps[0]), vec![bob]);
+    }
+}
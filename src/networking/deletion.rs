@@ -0,0 +1,141 @@
+use crate::networking::index::PropIndex;
+use crate::networking::message::DeleteProp;
+use crate::networking::transport::NetworkSocket;
+use crate::networking::{Authority, Message, PlayerUuid, PropUuid, SocketSendMessage};
+use bevy::prelude::*;
+use unavi_player::LocalPlayer;
+
+/// Asks for a prop to be deleted for everyone, only does anything if we have
+/// authority over it.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct RequestDeleteProp(pub Entity);
+
+/// Sent right before a deleted prop is despawned, so whatever holds it can
+/// let go.
+#[derive(Event, Clone, Debug)]
+pub struct PropDeleted {
+    pub prop: Entity,
+    pub prop_uuid: PropUuid,
+}
+
+pub fn send_delete_prop(
+    mut requests: EventReader<RequestDeleteProp>,
+    props: Query<(&PropUuid, &Authority)>,
+    local_player: Query<&PlayerUuid, With<LocalPlayer>>,
+    mut socket: ResMut<NetworkSocket>,
+    mut delete_prop: EventWriter<DeleteProp>,
+) {
+    let Ok(local_player) = local_player.get_single() else {
+        return;
+    };
+    for RequestDeleteProp(prop) in requests.read() {
+        let Ok((prop_uuid, authority)) = props.get(*prop) else {
+            continue;
+        };
+        if authority.player() != local_player {
+            warn!(
+                "can't delete prop {}, it belongs to {}",
+                prop_uuid.0,
+                authority.player().0
+            );
+            continue;
+        }
+        let delete = DeleteProp {
+            authority: authority.clone(),
+            prop_uuid: prop_uuid.clone(),
+        };
+        socket.send_msg_all_reliable(&Message::DeleteProp(delete.clone()));
+        delete_prop.send(delete);
+    }
+}
+
+pub fn handle_delete_prop(
+    mut commands: Commands,
+    mut event_reader: EventReader<DeleteProp>,
+    prop_index: Res<PropIndex>,
+    props: Query<&Authority>,
+    mut deleted: EventWriter<PropDeleted>,
+) {
+    for delete in event_reader.read() {
+        let Some(prop) = prop_index.get(&delete.prop_uuid) else {
+            continue;
+        };
+        let Ok(authority) = props.get(prop) else {
+            continue;
+        };
+        // only the current holder, a delete from someone who has since lost
+        // the prop is stale and one from further ahead is made up
+        if *authority != delete.authority {
+            continue;
+        }
+        deleted.send(PropDeleted {
+            prop,
+            prop_uuid: delete.prop_uuid.clone(),
+        });
+        commands.entity(prop).despawn_recursive();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::ownership::AuthorityPriority;
+    use crate::networking::test_app::{
+        headless_app, peer_id, raw_peer, send_raw, spawn_local_player, update_all,
+    };
+    use crate::networking::transport::{Channel, LoopbackHub};
+
+    #[test]
+    fn only_the_holder_deletes() {
+        let hub = LoopbackHub::new();
+        let mut apps = vec![headless_app(&hub), headless_app(&hub)];
+        let alice = spawn_local_player(&mut apps[0], "alice");
+        spawn_local_player(&mut apps[1], "bob");
+        update_all(&mut apps, 5);
+        let mut mallory = raw_peer(&hub, &mut apps[0], "mallory");
+        let target = peer_id(&mut apps[0]);
+
+        let prop_uuid = PropUuid("prop".to_string());
+        let authority = Authority::new(alice);
+        let props = apps
+            .iter_mut()
+            .map(|app| {
+                app.world_mut()
+                    .spawn((prop_uuid.clone(), authority.clone()))
+                    .id()
+            })
+            .collect::<Vec<_>>();
+        update_all(&mut apps, 2);
+
+        // a claim one ahead of the holder's, as if mallory had taken it
+        let forged = authority
+            .next(PlayerUuid("mallory".to_string()), AuthorityPriority::Normal)
+            .unwrap();
+        send_raw(
+            &mut mallory,
+            target,
+            Channel::Reliable,
+            &Message::DeleteProp(DeleteProp {
+                authority: forged,
+                prop_uuid: prop_uuid.clone(),
+            }),
+        );
+        // bob doesn't have it either
+        apps[1].world_mut().send_event(RequestDeleteProp(props[1]));
+        update_all(&mut apps, 5);
+        for (app, prop) in apps.iter_mut().zip(&props) {
+            assert!(app.world().get_entity(*prop).is_some());
+        }
+
+        apps[0].world_mut().send_event(RequestDeleteProp(props[0]));
+        update_all(&mut apps, 5);
+        for (app, prop) in apps.iter_mut().zip(&props) {
+            assert!(app.world().get_entity(*prop).is_none());
+            assert!(app
+                .world()
+                .resource::<PropIndex>()
+                .get(&prop_uuid)
+                .is_none());
+        }
+    }
+}
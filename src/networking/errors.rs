@@ -0,0 +1,269 @@
+use crate::networking::codec::{CodecError, MessageKind};
+use crate::networking::registry::IdentityMismatch;
+use crate::networking::transport::{PeerConnected, PeerDisconnected};
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub enum NetworkErrorKind {
+    Decode(CodecError),
+    /// A message that is only allowed on the other channel.
+    WrongChannel(MessageKind),
+    /// The message claims to come from someone the peer isn't.
+    Spoofed(MessageKind, IdentityMismatch),
+    /// Voice opus couldn't decode.
+    Voice(opus::Error),
+    /// Voice with a channel count we have no decoder for.
+    VoiceChannels(u16),
+}
+
+impl Display for NetworkErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkErrorKind::Decode(err) => write!(f, "{err}"),
+            NetworkErrorKind::WrongChannel(kind) => {
+                write!(f, "{kind:?} is not allowed on this channel")
+            }
+            NetworkErrorKind::Spoofed(kind, IdentityMismatch::Peer(peer)) => {
+                write!(f, "{kind:?} claims to be from peer {peer}")
+            }
+            NetworkErrorKind::Spoofed(kind, IdentityMismatch::Player(uuid)) => {
+                write!(f, "{kind:?} claims to be from player {}", uuid.0)
+            }
+            NetworkErrorKind::Voice(err) => write!(f, "undecodable voice: {err}"),
+            NetworkErrorKind::VoiceChannels(channels) => {
+                write!(f, "voice with {channels} audio channels")
+            }
+        }
+    }
+}
+
+/// Something a peer sent that we couldn't use.
+#[derive(Event, Debug)]
+pub struct NetworkError {
+    pub peer: PeerId,
+    pub kind: NetworkErrorKind,
+}
+
+/// Sent once when a peer crosses the malformed packet threshold.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct PeerBlocked(pub PeerId);
+
+#[derive(Resource, Clone, Debug)]
+pub struct NetworkErrorSettings {
+    /// How many bad packets a peer may send before we stop listening to them.
+    pub block_threshold: u32,
+    /// Minimum seconds between two log lines about the same peer.
+    pub log_interval: f32,
+}
+
+impl Default for NetworkErrorSettings {
+    fn default() -> Self {
+        Self {
+            block_threshold: 50,
+            log_interval: 5.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PeerErrorCount {
+    pub total: u32,
+    last_logged: Option<f32>,
+    suppressed: u32,
+}
+
+#[derive(Resource, Default)]
+pub struct PeerErrorCounters(HashMap<PeerId, PeerErrorCount>);
+
+impl PeerErrorCounters {
+    pub fn get(&self, peer: PeerId) -> u32 {
+        self.0.get(&peer).map(|count| count.total).unwrap_or(0)
+    }
+}
+
+/// Peers that sent us too much garbage, nothing they send gets routed. The
+/// socket can't kick a single peer, so every `NetworkSocket` the app opens
+/// shares this and leaves them out of `connected_peers` and anything we send.
+#[derive(Resource, Clone, Default)]
+pub struct BlockedPeers(Arc<Mutex<HashSet<PeerId>>>);
+
+impl BlockedPeers {
+    pub fn contains(&self, peer: PeerId) -> bool {
+        self.0.lock().unwrap().contains(&peer)
+    }
+
+    pub fn block(&self, peer: PeerId) {
+        self.0.lock().unwrap().insert(peer);
+    }
+}
+
+pub fn count_network_errors(
+    time: Res<Time>,
+    settings: Res<NetworkErrorSettings>,
+    mut counters: ResMut<PeerErrorCounters>,
+    blocked: Res<BlockedPeers>,
+    mut errors: EventReader<NetworkError>,
+    mut peer_blocked: EventWriter<PeerBlocked>,
+    mut disconnected: EventWriter<PeerDisconnected>,
+) {
+    let now = time.elapsed_seconds();
+    for NetworkError { peer, kind } in errors.read() {
+        if blocked.contains(*peer) {
+            continue;
+        }
+        let count = counters.0.entry(*peer).or_default();
+        count.total += 1;
+
+        let should_log = count
+            .last_logged
+            .map_or(true, |last| now - last >= settings.log_interval);
+        if should_log {
+            if count.suppressed > 0 {
+                warn!(
+                    "bad packet from {}: {} ({} more since the last warning)",
+                    peer, kind, count.suppressed
+                );
+            } else {
+                warn!("bad packet from {}: {}", peer, kind);
+            }
+            count.last_logged = Some(now);
+            count.suppressed = 0;
+        } else {
+            count.suppressed += 1;
+        }
+
+        if count.total >= settings.block_threshold {
+            warn!(
+                "blocking peer {} after {} bad packets",
+                peer, count.total
+            );
+            blocked.block(*peer);
+            peer_blocked.send(PeerBlocked(*peer));
+            // as far as the game is concerned they are gone
+            disconnected.send(PeerDisconnected(*peer));
+        }
+    }
+}
+
+pub fn forget_peer_errors(
+    mut counters: ResMut<PeerErrorCounters>,
+    mut connected: EventReader<PeerConnected>,
+) {
+    // blocked peers stay blocked, everyone else starts with a clean slate
+    for PeerConnected(peer) in connected.read() {
+        counters.0.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::codec::{self, WireFormat, HEADER_LEN};
+    use crate::networking::test_app::{
+        headless_app, peer_id, raw_peer, sample_messages, spawn_local_player, update_all,
+        TestRng,
+    };
+    use crate::networking::transport::{
+        Channel, LoopbackHub, LoopbackTransport, NetworkSocket, NetworkTransport,
+    };
+    use crate::networking::Message;
+
+    fn app_and_attacker() -> (App, LoopbackTransport) {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        spawn_local_player(&mut app, "victim");
+        let attacker = raw_peer(&hub, &mut app, "player");
+        (app, attacker)
+    }
+
+    fn update(app: &mut App, frames: usize) {
+        update_all(std::slice::from_mut(app), frames);
+    }
+
+    /// Random bytes, random bodies behind a valid header and real messages
+    /// with a few bytes flipped.
+    fn garbage(rng: &mut TestRng, samples: &[Vec<u8>]) -> Vec<u8> {
+        match rng.below(3) {
+            0 => {
+                let len = rng.below(64);
+                rng.bytes(len)
+            }
+            1 => {
+                let sample = &samples[rng.below(samples.len())];
+                let len = rng.below(256);
+                let mut packet = sample[..HEADER_LEN].to_vec();
+                packet.extend(rng.bytes(len));
+                packet
+            }
+            _ => {
+                let mut packet = samples[rng.below(samples.len())].clone();
+                for _ in 0..=rng.below(4) {
+                    let at = rng.below(packet.len());
+                    packet[at] = rng.next() as u8;
+                }
+                packet
+            }
+        }
+    }
+
+    fn encoded_samples() -> Vec<Vec<u8>> {
+        sample_messages()
+            .iter()
+            // the handshake is over, let them keep talking
+            .filter(|message| !matches!(message, Message::Handshake(_)))
+            .flat_map(|message| {
+                [WireFormat::Binary, WireFormat::Json]
+                    .map(|format| codec::encode(message, format))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn random_payloads_never_panic() {
+        let (mut app, mut attacker) = app_and_attacker();
+        app.world_mut()
+            .resource_mut::<NetworkErrorSettings>()
+            .block_threshold = u32::MAX;
+        let target = peer_id(&mut app);
+        let samples = encoded_samples();
+        let mut rng = TestRng::new(7);
+        for _ in 0..200 {
+            for _ in 0..10 {
+                let channel = Channel::ALL[rng.below(2)];
+                let packet = garbage(&mut rng, &samples);
+                attacker.send(channel, packet.into(), target);
+            }
+            app.update();
+        }
+        assert!(app.world().resource::<PeerErrorCounters>().get(attacker.id().unwrap()) > 0);
+        assert!(!app.world().resource::<BlockedPeers>().contains(attacker.id().unwrap()));
+    }
+
+    #[test]
+    fn blocked_peers_get_nothing() {
+        let (mut app, mut attacker) = app_and_attacker();
+        let target = peer_id(&mut app);
+        let attacker_id = attacker.id().unwrap();
+        let threshold = app.world().resource::<NetworkErrorSettings>().block_threshold;
+        for _ in 0..threshold {
+            attacker.send(Channel::Reliable, vec![0xff; 8].into(), target);
+        }
+        update(&mut app, 2);
+        assert!(app.world().resource::<BlockedPeers>().contains(attacker_id));
+
+        let socket = app.world().resource::<NetworkSocket>();
+        assert!(!socket.connected_peers().contains(&attacker_id));
+        for channel in Channel::ALL {
+            attacker.receive(channel);
+        }
+        // heartbeats, positions, anything we'd normally broadcast
+        update(&mut app, 120);
+        for channel in Channel::ALL {
+            assert!(attacker.receive(channel).is_empty());
+        }
+    }
+}
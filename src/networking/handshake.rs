@@ -0,0 +1,458 @@
+use crate::custom_audio::audio_output::AudioOutput;
+use crate::networking::codec::{WireFormat, PROTOCOL_VERSION};
+use crate::networking::clock::PeerClock;
+use crate::networking::connection::{Departed, PeerResumed};
+use crate::networking::heartbeat::PeerStats;
+use crate::networking::index::PlayerIndex;
+use crate::networking::registry::PeerRegistry;
+use crate::networking::transport::{NetworkSocket, PeerConnected, PeerDisconnected};
+use crate::networking::{
+    resume_external_player, spawn_external_player, DisplayName, ExternalPlayer, Message,
+    PlayerUuid, SocketSendMessage,
+};
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use unavi_player::LocalPlayer;
+
+/// What a peer can decode, sent as part of its `Hello`.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Capabilities {
+    pub wire_formats: Vec<WireFormat>,
+    /// Channel counts our opus decoders can handle.
+    pub voice_channels: Vec<u16>,
+    /// Optional features, unknown ones are ignored.
+    pub features: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn local() -> Self {
+        Self {
+            wire_formats: vec![WireFormat::Binary, WireFormat::Json],
+            voice_channels: vec![1, 2],
+            features: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Hello {
+    pub protocol_version: u8,
+    pub player_uuid: PlayerUuid,
+    pub display_name: String,
+    pub capabilities: Capabilities,
+}
+
+/// Handshake messages are always json and skip the header version check, so
+/// they have to stay readable by every version we have ever shipped.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum Handshake {
+    Hello(Hello),
+    /// Sent back once we accepted the peer's `Hello`, with the features both
+    /// sides support.
+    Welcome { features: Vec<String> },
+    Reject { reason: String },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PeerHandshake {
+    pub hello_sent: bool,
+    /// They accepted our hello.
+    pub welcomed: bool,
+    pub state: HandshakeState,
+}
+
+impl PeerHandshake {
+    /// Both sides accepted each other, anything we send now gets through.
+    pub fn is_complete(&self) -> bool {
+        self.welcomed && matches!(self.state, HandshakeState::Accepted { .. })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum HandshakeState {
+    #[default]
+    Pending,
+    Accepted {
+        hello: Hello,
+    },
+    Rejected {
+        reason: String,
+    },
+}
+
+/// The handshake state of every peer the socket told us about. Only messages
+/// from accepted peers get routed to the rest of the game.
+#[derive(Resource, Default)]
+pub struct Handshakes(HashMap<PeerId, PeerHandshake>);
+
+impl Handshakes {
+    pub fn get(&self, peer: PeerId) -> Option<&PeerHandshake> {
+        self.0.get(&peer)
+    }
+
+    pub fn is_accepted(&self, peer: PeerId) -> bool {
+        self.hello(peer).is_some()
+    }
+
+    pub fn hello(&self, peer: PeerId) -> Option<&Hello> {
+        match self.0.get(&peer).map(|handshake| &handshake.state) {
+            Some(HandshakeState::Accepted { hello, .. }) => Some(hello),
+            _ => None,
+        }
+    }
+
+    fn set_state(&mut self, peer: PeerId, state: HandshakeState) {
+        self.0.entry(peer).or_default().state = state;
+    }
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct HandshakeMessage {
+    pub peer: PeerId,
+    pub handshake: Handshake,
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct PeerAccepted {
+    pub peer: PeerId,
+    pub hello: Hello,
+}
+
+/// Sent once a peer and us have accepted each other.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct HandshakeComplete(pub PeerId);
+
+#[derive(Event, Clone, Debug)]
+pub struct PeerRejected {
+    pub peer: PeerId,
+    pub reason: String,
+}
+
+/// Returns why we can't talk to a peer, if we can't.
+pub fn incompatibility(hello: &Hello, our_format: WireFormat) -> Option<String> {
+    if hello.protocol_version != PROTOCOL_VERSION {
+        return Some(format!(
+            "protocol version mismatch, they speak {} and we speak {}",
+            hello.protocol_version, PROTOCOL_VERSION
+        ));
+    }
+    if !hello.capabilities.wire_formats.contains(&our_format) {
+        return Some(format!("they can't decode our wire format {:?}", our_format));
+    }
+    None
+}
+
+/// Peers we gave up on while the socket still has them, like ones that went
+/// silent for too long, start over with a new handshake in case they come
+/// back.
+pub fn track_peers(
+    socket: Res<NetworkSocket>,
+    mut handshakes: ResMut<Handshakes>,
+    mut connected: EventReader<PeerConnected>,
+    mut disconnected: EventReader<PeerDisconnected>,
+) {
+    for PeerConnected(peer) in connected.read() {
+        handshakes.0.insert(*peer, PeerHandshake::default());
+    }
+    let still_connected = socket.connected_peers();
+    for PeerDisconnected(peer) in disconnected.read() {
+        if still_connected.contains(peer) {
+            handshakes.0.insert(*peer, PeerHandshake::default());
+        } else {
+            handshakes.0.remove(peer);
+        }
+    }
+}
+
+pub fn send_hello(
+    mut socket: ResMut<NetworkSocket>,
+    mut handshakes: ResMut<Handshakes>,
+    local_player: Query<(&PlayerUuid, &DisplayName), With<LocalPlayer>>,
+) {
+    // we can't introduce ourselves until the local player exists
+    let Ok((uuid, display_name)) = local_player.get_single() else {
+        return;
+    };
+    for (peer, handshake) in handshakes.0.iter_mut() {
+        if handshake.hello_sent || matches!(handshake.state, HandshakeState::Rejected { .. }) {
+            continue;
+        }
+        // even if we already accepted them they won't accept anything from us
+        // until they get our hello
+        socket.send_msg_reliable(
+            *peer,
+            &Message::Handshake(Handshake::Hello(Hello {
+                protocol_version: PROTOCOL_VERSION,
+                player_uuid: uuid.clone(),
+                display_name: display_name.0.clone(),
+                capabilities: Capabilities::local(),
+            })),
+        );
+        handshake.hello_sent = true;
+    }
+}
+
+pub fn handle_handshake(
+    mut commands: Commands,
+    mut socket: ResMut<NetworkSocket>,
+    mut handshakes: ResMut<Handshakes>,
+    mut registry: ResMut<PeerRegistry>,
+    audio_output: Option<Res<AudioOutput>>,
+    asset_server: Res<AssetServer>,
+    time: Res<Time>,
+    player_index: Res<PlayerIndex>,
+    external_players: Query<Has<Departed>, With<ExternalPlayer>>,
+    mut event_reader: EventReader<HandshakeMessage>,
+    mut resumed: EventWriter<PeerResumed>,
+    mut accepted: EventWriter<PeerAccepted>,
+    mut complete: EventWriter<HandshakeComplete>,
+    mut rejected: EventWriter<PeerRejected>,
+    mut disconnected: EventWriter<PeerDisconnected>,
+) {
+    for HandshakeMessage { peer, handshake } in event_reader.read() {
+        match handshake {
+            Handshake::Hello(hello) => {
+                if handshakes.is_accepted(*peer) {
+                    // they gave up on us and started over, so do we, or
+                    // they'd never hear our hello again
+                    info!("peer {} started a new handshake", peer);
+                    handshakes.0.insert(*peer, PeerHandshake::default());
+                }
+                // the same player on a new peer, only believable once their
+                // old peer is gone since anyone can send a hello with their
+                // uuid
+                let hijack = || {
+                    let owner = registry
+                        .peer(&hello.player_uuid)
+                        .filter(|owner| owner != peer)?;
+                    let departed = player_index
+                        .get(&hello.player_uuid)
+                        .is_some_and(|player| external_players.get(player).unwrap_or(false));
+                    let gone = departed || !socket.connected_peers().contains(&owner);
+                    (!gone).then(|| {
+                        format!(
+                            "player {} is already connected as peer {}",
+                            hello.player_uuid.0, owner
+                        )
+                    })
+                };
+                if let Some(reason) = incompatibility(hello, socket.format()).or_else(hijack) {
+                    warn!("rejecting peer {}: {}", peer, reason);
+                    socket.send_msg_reliable(
+                        *peer,
+                        &Message::Handshake(Handshake::Reject {
+                            reason: reason.clone(),
+                        }),
+                    );
+                    handshakes.set_state(
+                        *peer,
+                        HandshakeState::Rejected {
+                            reason: reason.clone(),
+                        },
+                    );
+                    rejected.send(PeerRejected {
+                        peer: *peer,
+                        reason,
+                    });
+                    continue;
+                }
+                // whatever they were connected as before is dead, even if we
+                // haven't cleaned up after it yet
+                if let Err(stale) = registry.bind(*peer, hello.player_uuid.clone()) {
+                    warn!(
+                        "player {} is back as peer {}, dropping their old peer {}",
+                        hello.player_uuid.0, peer, stale
+                    );
+                    registry.unbind(stale);
+                    handshakes.0.remove(&stale);
+                    disconnected.send(PeerDisconnected(stale));
+                    let _ = registry.bind(*peer, hello.player_uuid.clone());
+                }
+                let features = Capabilities::local()
+                    .features
+                    .into_iter()
+                    .filter(|feature| hello.capabilities.features.contains(feature))
+                    .collect();
+                socket.send_msg_reliable(
+                    *peer,
+                    &Message::Handshake(Handshake::Welcome { features }),
+                );
+                handshakes.set_state(
+                    *peer,
+                    HandshakeState::Accepted {
+                        hello: hello.clone(),
+                    },
+                );
+                // whoever we already have a player for keeps it, whether
+                // they left, timed out or are on a new peer
+                let returning = player_index
+                    .get(&hello.player_uuid)
+                    .filter(|player| external_players.contains(*player));
+                let player = if let Some(player) = returning {
+                    resume_external_player(
+                        &mut commands,
+                        player,
+                        hello.player_uuid.clone(),
+                        DisplayName(hello.display_name.clone()),
+                        *peer,
+                    );
+                    resumed.send(PeerResumed { peer: *peer, player });
+                    player
+                } else {
+                    spawn_external_player(
+                        audio_output.as_deref(),
+                        &asset_server,
+                        &mut commands,
+                        hello.player_uuid.clone(),
+                        DisplayName(hello.display_name.clone()),
+                        *peer,
+                    )
+                };
+                commands.entity(player).insert((
+                    PeerStats::new(time.elapsed_seconds_f64()),
+                    PeerClock::default(),
+                ));
+                accepted.send(PeerAccepted {
+                    peer: *peer,
+                    hello: hello.clone(),
+                });
+                if handshakes.get(*peer).is_some_and(PeerHandshake::is_complete) {
+                    complete.send(HandshakeComplete(*peer));
+                }
+            }
+            Handshake::Welcome { .. } => {
+                // they can welcome us before their own hello went out
+                let handshake = handshakes.0.entry(*peer).or_default();
+                if handshake.welcomed {
+                    continue;
+                }
+                handshake.welcomed = true;
+                if handshake.is_complete() {
+                    complete.send(HandshakeComplete(*peer));
+                }
+            }
+            Handshake::Reject { reason } => {
+                warn!("peer {} rejected us: {}", peer, reason);
+                handshakes.set_state(
+                    *peer,
+                    HandshakeState::Rejected {
+                        reason: reason.clone(),
+                    },
+                );
+                rejected.send(PeerRejected {
+                    peer: *peer,
+                    reason: reason.clone(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::connection::Departed;
+    use crate::networking::heartbeat::HeartbeatSettings;
+    use crate::networking::test_app::{
+        headless_app, peer_id, raw_peer, received, say_hello, spawn_local_player, update_all,
+        FRAME,
+    };
+    use crate::networking::transport::{Channel, LoopbackHub, LoopbackTransport, NetworkTransport};
+
+    /// What `app` answered `peer`'s hello with.
+    fn answers(peer: &mut LoopbackTransport, app: &mut App) -> Vec<Handshake> {
+        let target = peer_id(app);
+        received(peer, Channel::Reliable)
+            .into_iter()
+            .filter(|(from, _)| *from == target)
+            .filter_map(|(_, message)| match message {
+                Message::Handshake(Handshake::Hello(_)) => None,
+                Message::Handshake(handshake) => Some(handshake),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn returning_player_resumes_once_their_old_peer_is_gone() {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        spawn_local_player(&mut app, "alice");
+        let old = raw_peer(&hub, &mut app, "bob");
+        let bob = PlayerUuid("bob".to_string());
+        let player = app.world().resource::<PlayerIndex>().get(&bob).unwrap();
+
+        // bob's browser reloaded and the old connection went away
+        let old_id = old.id().unwrap();
+        drop(old);
+        let mut new = raw_peer(&hub, &mut app, "bob");
+        let new_id = new.id().unwrap();
+        assert!(matches!(answers(&mut new, &mut app)[..], [Handshake::Welcome { .. }]));
+
+        let world = app.world();
+        assert_eq!(world.resource::<PeerRegistry>().peer(&bob), Some(new_id));
+        assert_eq!(world.resource::<PeerRegistry>().player(old_id), None);
+        assert!(world.resource::<Handshakes>().is_accepted(new_id));
+        // the same avatar, now on the new peer
+        assert_eq!(world.resource::<PlayerIndex>().get(&bob), Some(player));
+        assert_eq!(world.get::<ExternalPlayer>(player).unwrap().peer_id, new_id);
+        assert!(world.get::<Departed>(player).is_none());
+    }
+
+    #[test]
+    fn nobody_takes_over_a_connected_player() {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        spawn_local_player(&mut app, "alice");
+        let bob = raw_peer(&hub, &mut app, "bob");
+        let bob_id = bob.id().unwrap();
+        let uuid = PlayerUuid("bob".to_string());
+        let player = app.world().resource::<PlayerIndex>().get(&uuid).unwrap();
+
+        // bob's uuid is in every position update they send
+        let mut mallory = raw_peer(&hub, &mut app, "bob");
+        let mallory_id = mallory.id().unwrap();
+        assert!(matches!(answers(&mut mallory, &mut app)[..], [Handshake::Reject { .. }]));
+
+        let world = app.world();
+        assert_eq!(world.resource::<PeerRegistry>().peer(&uuid), Some(bob_id));
+        assert_eq!(world.resource::<PeerRegistry>().player(mallory_id), None);
+        assert!(world.resource::<Handshakes>().is_accepted(bob_id));
+        assert!(!world.resource::<Handshakes>().is_accepted(mallory_id));
+        assert_eq!(world.resource::<PlayerIndex>().get(&uuid), Some(player));
+        assert_eq!(world.get::<ExternalPlayer>(player).unwrap().peer_id, bob_id);
+    }
+
+    #[test]
+    fn silent_peers_can_handshake_again() {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        spawn_local_player(&mut app, "alice");
+        let mut bob = raw_peer(&hub, &mut app, "bob");
+        let bob_id = bob.id().unwrap();
+        let uuid = PlayerUuid("bob".to_string());
+        let player = app.world().resource::<PlayerIndex>().get(&uuid).unwrap();
+        received(&mut bob, Channel::Reliable);
+
+        // bob never answers a ping
+        let timeout = app.world().resource::<HeartbeatSettings>().peer_timeout;
+        update_all(std::slice::from_mut(&mut app), (timeout / FRAME) as usize + 10);
+        let world = app.world();
+        assert!(world.get::<Departed>(player).is_some());
+        assert_eq!(world.resource::<PeerRegistry>().player(bob_id), None);
+        assert!(!world.resource::<Handshakes>().is_accepted(bob_id));
+        // the socket still has them, so we introduce ourselves again
+        assert!(received(&mut bob, Channel::Reliable)
+            .iter()
+            .any(|(_, message)| matches!(message, Message::Handshake(Handshake::Hello(_)))));
+
+        say_hello(&mut bob, &mut app, "bob");
+        let world = app.world();
+        assert!(world.resource::<Handshakes>().is_accepted(bob_id));
+        assert_eq!(world.resource::<PeerRegistry>().peer(&uuid), Some(bob_id));
+        assert_eq!(world.resource::<PlayerIndex>().get(&uuid), Some(player));
+        assert!(world.get::<Departed>(player).is_none());
+    }
+}
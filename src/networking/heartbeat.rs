@@ -0,0 +1,227 @@
+use crate::networking::clock::{ntp_sample, ClockSettings, NetworkTime, PeerClock};
+use crate::networking::connection::Departed;
+use crate::networking::index::PeerIndex;
+use crate::networking::transport::{NetworkSocket, PeerDisconnected};
+use crate::networking::{ExternalPlayer, Message, SocketSendMessage};
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Sent over the unreliable channel, so a lost ping is a lost packet.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum Heartbeat {
+    /// `sent_at` is in the sender's own clock, it only ever gets compared
+    /// against that clock again.
+    Ping { sequence: u32, sent_at: f64 },
+    /// Echoes the ping it answers, `replied_at` is the answering peer's
+    /// `NetworkTime` so the pinging side can compare clocks.
+    Pong {
+        sequence: u32,
+        sent_at: f64,
+        replied_at: f64,
+    },
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct HeartbeatMessage {
+    pub peer: PeerId,
+    pub heartbeat: Heartbeat,
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct HeartbeatSettings {
+    /// Seconds between pings to every peer.
+    pub interval: f32,
+    /// A ping without a pong after this many seconds counts as lost.
+    pub pong_timeout: f32,
+    /// A peer we haven't heard a heartbeat from for this many seconds is
+    /// treated as disconnected, even if WebRTC still thinks it is there.
+    pub peer_timeout: f32,
+    /// How much each new sample moves the averages, like TCP's RTT estimate.
+    pub smoothing: f32,
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        Self {
+            interval: 1.0,
+            pong_timeout: 2.0,
+            peer_timeout: 10.0,
+            smoothing: 0.125,
+        }
+    }
+}
+
+/// How the connection to a peer is doing, on their `ExternalPlayer`.
+#[derive(Component, Clone, Debug)]
+pub struct PeerStats {
+    /// Smoothed round trip time in seconds, `None` until the first pong.
+    pub rtt: Option<f32>,
+    /// Smoothed difference between round trips, in seconds.
+    pub jitter: f32,
+    /// Smoothed share of pings that went unanswered, from 0 to 1.
+    pub loss: f32,
+    /// When we last heard a heartbeat from them, in `Time::elapsed_seconds_f64`.
+    pub last_seen: f64,
+    next_sequence: u32,
+    /// Pings waiting for their pong, oldest first.
+    pending: VecDeque<(u32, f64)>,
+}
+
+impl PeerStats {
+    pub fn new(now: f64) -> Self {
+        Self {
+            rtt: None,
+            jitter: 0.0,
+            loss: 0.0,
+            last_seen: now,
+            next_sequence: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Half the round trip, what one way most likely takes.
+    pub fn latency(&self) -> Option<f32> {
+        self.rtt.map(|rtt| rtt / 2.0)
+    }
+
+    fn ping(&mut self, now: f64) -> u32 {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.pending.push_back((sequence, now));
+        sequence
+    }
+
+    /// Returns when the ping it answers went out.
+    fn pong(&mut self, settings: &HeartbeatSettings, sequence: u32, now: f64) -> Option<f64> {
+        // a pong for something we already gave up on still shows they are
+        // there, but it doesn't count as an answer
+        let index = self.pending.iter().position(|(s, _)| *s == sequence)?;
+        let (_, sent_at) = self.pending.remove(index).unwrap();
+        let sample = (now - sent_at) as f32;
+        let smoothing = settings.smoothing;
+        match self.rtt {
+            Some(rtt) => {
+                self.jitter += smoothing * ((sample - rtt).abs() - self.jitter);
+                self.rtt = Some(rtt + smoothing * (sample - rtt));
+            }
+            None => self.rtt = Some(sample),
+        }
+        self.loss -= smoothing * self.loss;
+        Some(sent_at)
+    }
+
+    fn expire_pings(&mut self, settings: &HeartbeatSettings, now: f64) {
+        while let Some((_, sent_at)) = self.pending.front() {
+            if now - sent_at < settings.pong_timeout as f64 {
+                break;
+            }
+            self.pending.pop_front();
+            self.loss += settings.smoothing * (1.0 - self.loss);
+        }
+    }
+}
+
+pub fn send_pings(
+    mut socket: ResMut<NetworkSocket>,
+    time: Res<Time>,
+    settings: Res<HeartbeatSettings>,
+    mut players: Query<(&ExternalPlayer, &mut PeerStats), Without<Departed>>,
+    mut last_ping: Local<f64>,
+) {
+    let now = time.elapsed_seconds_f64();
+    for (_, mut stats) in players.iter_mut() {
+        stats.expire_pings(&settings, now);
+    }
+    if now - *last_ping < settings.interval as f64 {
+        return;
+    }
+    *last_ping = now;
+    for (player, mut stats) in players.iter_mut() {
+        let sequence = stats.ping(now);
+        socket.send_msg_unreliable(
+            player.peer_id,
+            &Message::Heartbeat(Heartbeat::Ping {
+                sequence,
+                sent_at: now,
+            }),
+        );
+    }
+}
+
+pub fn handle_heartbeats(
+    mut socket: ResMut<NetworkSocket>,
+    time: Res<Time>,
+    settings: Res<HeartbeatSettings>,
+    clock_settings: Res<ClockSettings>,
+    network_time: Res<NetworkTime>,
+    peer_index: Res<PeerIndex>,
+    mut heartbeats: EventReader<HeartbeatMessage>,
+    mut players: Query<(&mut PeerStats, &mut PeerClock), Without<Departed>>,
+) {
+    let now = time.elapsed_seconds_f64();
+    for HeartbeatMessage { peer, heartbeat } in heartbeats.read() {
+        let Some((mut stats, mut clock)) = peer_index
+            .get(*peer)
+            .and_then(|player| players.get_mut(player).ok())
+        else {
+            continue;
+        };
+        stats.last_seen = now;
+        match heartbeat {
+            Heartbeat::Ping { sequence, sent_at } => {
+                socket.send_msg_unreliable(
+                    *peer,
+                    &Message::Heartbeat(Heartbeat::Pong {
+                        sequence: *sequence,
+                        sent_at: *sent_at,
+                        replied_at: network_time.from_local(now),
+                    }),
+                );
+            }
+            Heartbeat::Pong {
+                sequence,
+                replied_at,
+                ..
+            } => {
+                // timed against our own record of the ping rather than the
+                // echoed time, which they could make up
+                let Some(sent_at) = stats.pong(&settings, *sequence, now) else {
+                    continue;
+                };
+                // they answer in the same frame, so receiving and replying
+                // happened at the same time as far as we can tell
+                let (offset, delay) = ntp_sample(
+                    network_time.from_local(sent_at),
+                    *replied_at,
+                    *replied_at,
+                    network_time.from_local(now),
+                );
+                clock.add_sample(&clock_settings, offset, delay);
+            }
+        }
+    }
+}
+
+pub fn time_out_silent_peers(
+    time: Res<Time>,
+    settings: Res<HeartbeatSettings>,
+    players: Query<(&ExternalPlayer, &PeerStats), Without<Departed>>,
+    mut disconnected: EventWriter<PeerDisconnected>,
+) {
+    let now = time.elapsed_seconds_f64();
+    for (player, stats) in players.iter() {
+        if now - stats.last_seen >= settings.peer_timeout as f64 {
+            warn!(
+                "peer {} has been silent for {:.1}s, dropping them",
+                player.peer_id,
+                now - stats.last_seen
+            );
+            // same as a WebRTC disconnect from here on, their player waits
+            // to be resumed like any other. The socket still has them, so
+            // `track_peers` starts a new handshake for when they speak up.
+            disconnected.send(PeerDisconnected(player.peer_id));
+        }
+    }
+}
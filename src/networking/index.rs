@@ -0,0 +1,252 @@
+use crate::networking::{ExternalPlayer, PlayerUuid, PropUuid};
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use std::collections::HashMap;
+
+/// Every entity with a `PlayerUuid`, local player included.
+#[derive(Resource, Default)]
+pub struct PlayerIndex(HashMap<PlayerUuid, Entity>);
+
+/// The `ExternalPlayer` entity of every peer.
+#[derive(Resource, Default)]
+pub struct PeerIndex(HashMap<PeerId, Entity>);
+
+/// Every entity with a `PropUuid`.
+#[derive(Resource, Default)]
+pub struct PropIndex(HashMap<PropUuid, Entity>);
+
+impl PlayerIndex {
+    pub fn get(&self, uuid: &PlayerUuid) -> Option<Entity> {
+        self.0.get(uuid).copied()
+    }
+}
+
+impl PeerIndex {
+    pub fn get(&self, peer: PeerId) -> Option<Entity> {
+        self.0.get(&peer).copied()
+    }
+}
+
+impl PropIndex {
+    pub fn get(&self, uuid: &PropUuid) -> Option<Entity> {
+        self.0.get(uuid).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PropUuid, &Entity)> {
+        self.0.iter()
+    }
+}
+
+// The indices are kept in sync by observers. `OnReplace` runs both when a
+// component is removed and when it is overwritten, before the old value is
+// gone, so we can still read which key to drop.
+
+fn index_player(
+    trigger: Trigger<OnInsert, PlayerUuid>,
+    players: Query<&PlayerUuid>,
+    mut index: ResMut<PlayerIndex>,
+) {
+    if let Ok(uuid) = players.get(trigger.entity()) {
+        index.0.insert(uuid.clone(), trigger.entity());
+    }
+}
+
+fn unindex_player(
+    trigger: Trigger<OnReplace, PlayerUuid>,
+    players: Query<&PlayerUuid>,
+    mut index: ResMut<PlayerIndex>,
+) {
+    if let Ok(uuid) = players.get(trigger.entity()) {
+        if index.0.get(uuid) == Some(&trigger.entity()) {
+            index.0.remove(uuid);
+        }
+    }
+}
+
+fn index_peer(
+    trigger: Trigger<OnInsert, ExternalPlayer>,
+    players: Query<&ExternalPlayer>,
+    mut index: ResMut<PeerIndex>,
+) {
+    if let Ok(player) = players.get(trigger.entity()) {
+        index.0.insert(player.peer_id, trigger.entity());
+    }
+}
+
+// a resumed player keeps its entity but gets a new peer id
+fn unindex_peer(
+    trigger: Trigger<OnReplace, ExternalPlayer>,
+    players: Query<&ExternalPlayer>,
+    mut index: ResMut<PeerIndex>,
+) {
+    if let Ok(player) = players.get(trigger.entity()) {
+        if index.0.get(&player.peer_id) == Some(&trigger.entity()) {
+            index.0.remove(&player.peer_id);
+        }
+    }
+}
+
+// two entities claiming to be the same prop would leave one of them
+// unreachable, the one that was there first wins
+fn index_prop(
+    trigger: Trigger<OnInsert, PropUuid>,
+    mut commands: Commands,
+    props: Query<&PropUuid>,
+    mut index: ResMut<PropIndex>,
+) {
+    let Ok(uuid) = props.get(trigger.entity()) else {
+        return;
+    };
+    match index.0.get(uuid) {
+        Some(existing) if *existing != trigger.entity() => {
+            warn!(
+                "prop {} already exists as {}, despawning duplicate {}",
+                uuid.0,
+                existing,
+                trigger.entity()
+            );
+            commands.entity(trigger.entity()).despawn_recursive();
+        }
+        _ => {
+            index.0.insert(uuid.clone(), trigger.entity());
+        }
+    }
+}
+
+fn unindex_prop(
+    trigger: Trigger<OnReplace, PropUuid>,
+    props: Query<&PropUuid>,
+    mut index: ResMut<PropIndex>,
+) {
+    if let Ok(uuid) = props.get(trigger.entity()) {
+        if index.0.get(uuid) == Some(&trigger.entity()) {
+            index.0.remove(uuid);
+        }
+    }
+}
+
+pub struct NetworkIndexPlugin;
+
+impl Plugin for NetworkIndexPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PlayerIndex>()
+            .init_resource::<PeerIndex>()
+            .init_resource::<PropIndex>()
+            .observe(index_player)
+            .observe(unindex_player)
+            .observe(index_peer)
+            .observe(unindex_peer)
+            .observe(index_prop)
+            .observe(unindex_prop);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::utils::Instant;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins(NetworkIndexPlugin);
+        app
+    }
+
+    fn prop(name: &str) -> PropUuid {
+        PropUuid(name.to_string())
+    }
+
+    #[test]
+    fn replaced_uuids_move_in_the_index() {
+        let mut app = app();
+        let entity = app.world_mut().spawn(prop("a")).id();
+        app.world_mut().entity_mut(entity).insert(prop("b"));
+        let player = app
+            .world_mut()
+            .spawn(PlayerUuid("alice".to_string()))
+            .id();
+        app.world_mut()
+            .entity_mut(player)
+            .insert(PlayerUuid("bob".to_string()));
+
+        let props = app.world().resource::<PropIndex>();
+        assert_eq!(props.get(&prop("a")), None);
+        assert_eq!(props.get(&prop("b")), Some(entity));
+        let players = app.world().resource::<PlayerIndex>();
+        assert_eq!(players.get(&PlayerUuid("alice".to_string())), None);
+        assert_eq!(players.get(&PlayerUuid("bob".to_string())), Some(player));
+
+        app.world_mut().entity_mut(entity).remove::<PropUuid>();
+        assert_eq!(app.world().resource::<PropIndex>().len(), 0);
+    }
+
+    #[test]
+    fn duplicate_props_keep_the_first() {
+        let mut app = app();
+        let first = app.world_mut().spawn(prop("a")).id();
+        let second = app.world_mut().spawn(prop("a")).id();
+        app.update();
+
+        assert_eq!(app.world().resource::<PropIndex>().get(&prop("a")), Some(first));
+        assert!(app.world().get_entity(second).is_none());
+
+        // and the first one can still go away cleanly
+        app.world_mut().despawn(first);
+        assert_eq!(app.world().resource::<PropIndex>().len(), 0);
+    }
+
+    /// Every peer updating every prop once, looked up through the index and
+    /// by scanning all props like we used to.
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn lookups_scale_with_room_size() {
+        const PROPS: usize = 500;
+        const PEERS: usize = 40;
+        let mut app = app();
+        let uuids = (0..PROPS)
+            .map(|i| prop(&format!("prop-{i}")))
+            .collect::<Vec<_>>();
+        for uuid in uuids.iter() {
+            app.world_mut().spawn(uuid.clone());
+        }
+        for i in 0..PEERS {
+            app.world_mut().spawn(PlayerUuid(format!("peer-{i}")));
+        }
+
+        let start = Instant::now();
+        let mut found = 0;
+        for _ in 0..PEERS {
+            let index = app.world().resource::<PropIndex>();
+            found += uuids.iter().filter_map(|uuid| index.get(uuid)).count();
+        }
+        let indexed = start.elapsed();
+        assert_eq!(found, PROPS * PEERS);
+
+        let mut query = app.world_mut().query::<(Entity, &PropUuid)>();
+        let start = Instant::now();
+        let mut found = 0;
+        for _ in 0..PEERS {
+            for uuid in uuids.iter() {
+                found += query
+                    .iter(app.world())
+                    .find(|(_, other)| *other == uuid)
+                    .map_or(0, |_| 1);
+            }
+        }
+        let scanned = start.elapsed();
+        assert_eq!(found, PROPS * PEERS);
+
+        println!(
+            "{} lookups for {} props: index {:?}, scan {:?}",
+            PROPS * PEERS,
+            PROPS,
+            indexed,
+            scanned
+        );
+        assert!(indexed < scanned);
+    }
+}
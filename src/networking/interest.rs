@@ -0,0 +1,317 @@
+use crate::networking::message::PlayerPosition;
+use crate::networking::transport::PeerDisconnected;
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Decides how much a peer cares about things happening at some position.
+pub trait InterestPolicy: Send + Sync + 'static {
+    /// How much of the normal send rate a viewer at `viewer` gets for
+    /// something at `subject`, `1.0` for all of it and `0.0` for nothing.
+    fn rate_scale(&self, viewer: Vec3, subject: Vec3) -> f32;
+    /// Whether a listener at `listener` can hear a speaker at `speaker`.
+    fn audible(&self, listener: Vec3, speaker: Vec3) -> bool;
+}
+
+/// Full rate up close, falling off linearly to `min_scale` at `cull_radius`
+/// and nothing beyond it.
+#[derive(Clone, Debug)]
+pub struct RadiusPolicy {
+    pub full_rate_radius: f32,
+    pub cull_radius: f32,
+    pub min_scale: f32,
+    pub voice_range: f32,
+}
+
+impl Default for RadiusPolicy {
+    fn default() -> Self {
+        Self {
+            full_rate_radius: 10.0,
+            cull_radius: 60.0,
+            min_scale: 0.2,
+            voice_range: 30.0,
+        }
+    }
+}
+
+impl InterestPolicy for RadiusPolicy {
+    fn rate_scale(&self, viewer: Vec3, subject: Vec3) -> f32 {
+        let distance = viewer.distance(subject);
+        if distance <= self.full_rate_radius {
+            return 1.0;
+        }
+        if distance > self.cull_radius {
+            return 0.0;
+        }
+        let t = (distance - self.full_rate_radius) / (self.cull_radius - self.full_rate_radius);
+        1.0 + (self.min_scale - 1.0) * t
+    }
+
+    fn audible(&self, listener: Vec3, speaker: Vec3) -> bool {
+        listener.distance(speaker) <= self.voice_range
+    }
+}
+
+/// Splits the floor into square cells, full rate in the viewer's own cell,
+/// `neighbor_scale` within `neighbor_cells` of it and nothing further out.
+/// Cheaper to reason about than distances when rooms get crowded.
+#[derive(Clone, Debug)]
+pub struct GridPolicy {
+    pub cell_size: f32,
+    pub neighbor_cells: i32,
+    pub neighbor_scale: f32,
+    pub voice_cells: i32,
+}
+
+impl Default for GridPolicy {
+    fn default() -> Self {
+        Self {
+            cell_size: 16.0,
+            neighbor_cells: 2,
+            neighbor_scale: 0.5,
+            voice_cells: 1,
+        }
+    }
+}
+
+impl GridPolicy {
+    fn cell(&self, position: Vec3) -> IVec2 {
+        (position.xz() / self.cell_size).floor().as_ivec2()
+    }
+
+    fn cells_apart(&self, a: Vec3, b: Vec3) -> i32 {
+        let offset = (self.cell(a) - self.cell(b)).abs();
+        offset.x.max(offset.y)
+    }
+}
+
+impl InterestPolicy for GridPolicy {
+    fn rate_scale(&self, viewer: Vec3, subject: Vec3) -> f32 {
+        match self.cells_apart(viewer, subject) {
+            0 => 1.0,
+            apart if apart <= self.neighbor_cells => self.neighbor_scale,
+            _ => 0.0,
+        }
+    }
+
+    fn audible(&self, listener: Vec3, speaker: Vec3) -> bool {
+        self.cells_apart(listener, speaker) <= self.voice_cells
+    }
+}
+
+/// The policy every sender of positional messages asks before sending to a
+/// peer. Peers we haven't heard a position from yet get everything.
+#[derive(Resource)]
+pub struct Interest {
+    policy: Box<dyn InterestPolicy>,
+}
+
+impl Default for Interest {
+    fn default() -> Self {
+        Self::new(RadiusPolicy::default())
+    }
+}
+
+impl Interest {
+    pub fn new(policy: impl InterestPolicy) -> Self {
+        Self {
+            policy: Box::new(policy),
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: impl InterestPolicy) {
+        self.policy = Box::new(policy);
+    }
+
+    pub fn rate_scale(&self, viewer: Option<Vec3>, subject: Vec3) -> f32 {
+        viewer.map_or(1.0, |viewer| self.policy.rate_scale(viewer, subject))
+    }
+
+    pub fn audible(&self, listener: Option<Vec3>, speaker: Vec3) -> bool {
+        listener.map_or(true, |listener| self.policy.audible(listener, speaker))
+    }
+}
+
+/// Where each peer's player last said it was.
+#[derive(Resource, Default)]
+pub struct PeerPositions(HashMap<PeerId, Vec3>);
+
+impl PeerPositions {
+    pub fn get(&self, peer: PeerId) -> Option<Vec3> {
+        self.0.get(&peer).copied()
+    }
+}
+
+pub fn track_peer_positions(
+    mut positions: ResMut<PeerPositions>,
+    mut player_positions: EventReader<PlayerPosition>,
+    mut disconnected: EventReader<PeerDisconnected>,
+) {
+    for player_position in player_positions.read() {
+        positions
+            .0
+            .insert(player_position.peer_id, player_position.position.0);
+    }
+    for PeerDisconnected(peer) in disconnected.read() {
+        positions.0.remove(peer);
+    }
+}
+
+/// When each peer was last sent something about `K`, for senders that send
+/// to different peers at different rates.
+pub struct PeerRateLimiter<K> {
+    last_sent: HashMap<(PeerId, K), f32>,
+}
+
+impl<K> Default for PeerRateLimiter<K> {
+    fn default() -> Self {
+        Self {
+            last_sent: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> PeerRateLimiter<K> {
+    /// Whether `peer` is due an update about `key` at `base_rate` scaled by
+    /// `scale`, and if so counts this as the update.
+    pub fn due(&mut self, peer: PeerId, key: K, now: f32, base_rate: f32, scale: f32) -> bool {
+        if scale <= 0.0 {
+            return false;
+        }
+        let entry = (peer, key);
+        let due = match self.last_sent.get(&entry) {
+            Some(last_sent) if base_rate > 0.0 => now - last_sent >= 1.0 / (base_rate * scale),
+            _ => true,
+        };
+        if due {
+            self.last_sent.insert(entry, now);
+        }
+        due
+    }
+
+    /// Counts an update that went out regardless of the rate.
+    pub fn record(&mut self, peer: PeerId, key: K, now: f32) {
+        self.last_sent.insert((peer, key), now);
+    }
+
+    pub fn retain_peers(&mut self, peers: &[PeerId]) {
+        self.retain(|peer, _| peers.contains(peer));
+    }
+
+    /// Forgets every peer and key `keep` says no to, so entries for things
+    /// that are gone don't pile up.
+    pub fn retain(&mut self, mut keep: impl FnMut(&PeerId, &K) -> bool) {
+        self.last_sent.retain(|(peer, key), _| keep(peer, key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::index::PropIndex;
+    use crate::networking::interpolation::SnapshotBuffer;
+    use crate::networking::test_app::{headless_app, spawn_local_player, update_all};
+    use crate::networking::transport::LoopbackHub;
+    use crate::networking::{Authority, PropUuid};
+    use crate::props::PropDescription;
+    use avian3d::prelude::{AngularVelocity, LinearVelocity, Position, Rotation};
+    use unavi_player::LocalPlayer;
+    use uuid::Uuid;
+
+    #[test]
+    fn radius_falls_off_then_culls() {
+        let policy = RadiusPolicy::default();
+        let at = |x: f32| policy.rate_scale(Vec3::ZERO, Vec3::new(x, 0.0, 0.0));
+        assert_eq!(at(0.0), 1.0);
+        assert_eq!(at(policy.full_rate_radius), 1.0);
+        let halfway = (policy.full_rate_radius + policy.cull_radius) / 2.0;
+        assert!((at(halfway) - (1.0 + policy.min_scale) / 2.0).abs() < 1e-5);
+        assert!((at(policy.cull_radius) - policy.min_scale).abs() < 1e-5);
+        assert_eq!(at(policy.cull_radius + 0.1), 0.0);
+
+        assert!(policy.audible(Vec3::ZERO, Vec3::new(0.0, 0.0, policy.voice_range)));
+        assert!(!policy.audible(Vec3::ZERO, Vec3::new(0.0, 0.0, policy.voice_range + 0.1)));
+    }
+
+    #[test]
+    fn grid_goes_by_cells() {
+        let policy = GridPolicy::default();
+        let cell = policy.cell_size;
+        let at = |x: f32, z: f32| policy.rate_scale(Vec3::new(1.0, 0.0, 1.0), Vec3::new(x, 0.0, z));
+        // the same cell, height doesn't count
+        assert_eq!(
+            policy.rate_scale(Vec3::ZERO, Vec3::new(cell - 0.1, 100.0, 0.0)),
+            1.0
+        );
+        // diagonal neighbors are as close as straight ones
+        assert_eq!(at(cell + 1.0, cell + 1.0), policy.neighbor_scale);
+        assert_eq!(at(-1.0, 1.0), policy.neighbor_scale);
+        let edge = cell * (policy.neighbor_cells + 1) as f32 - 0.1;
+        assert_eq!(at(edge, 0.0), policy.neighbor_scale);
+        assert_eq!(at(edge + 0.2, 0.0), 0.0);
+
+        assert!(policy.audible(Vec3::ZERO, Vec3::new(cell + 1.0, 0.0, 0.0)));
+        assert!(!policy.audible(Vec3::ZERO, Vec3::new(cell * 2.0 + 1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn limiter_forgets_what_it_is_told_to() {
+        let (a, b) = (PeerId(Uuid::new_v4()), PeerId(Uuid::new_v4()));
+        let mut limiter = PeerRateLimiter::default();
+        for key in [1, 2] {
+            limiter.record(a, key, 0.0);
+            limiter.record(b, key, 0.0);
+        }
+        assert!(!limiter.due(a, 1, 0.01, 10.0, 1.0));
+        limiter.retain(|peer, key| *peer == a && *key == 1);
+        assert!(!limiter.due(a, 1, 0.02, 10.0, 1.0));
+        assert!(limiter.due(a, 2, 0.02, 10.0, 1.0));
+        assert!(limiter.due(b, 1, 0.02, 10.0, 1.0));
+    }
+
+    #[test]
+    fn props_catch_up_with_peers_coming_into_range() {
+        let hub = LoopbackHub::new();
+        let mut apps = vec![headless_app(&hub), headless_app(&hub)];
+        let alice = spawn_local_player(&mut apps[0], "alice");
+        spawn_local_player(&mut apps[1], "bob");
+        let prop_uuid = PropUuid("crate".to_string());
+        let prop = apps[0]
+            .world_mut()
+            .spawn((
+                prop_uuid.clone(),
+                Authority::new(alice),
+                PropDescription::cube(0.25, [1.0, 0.0, 0.0]),
+                Position::default(),
+                Rotation::default(),
+                LinearVelocity::default(),
+                AngularVelocity::default(),
+            ))
+            .id();
+        let move_bob = |apps: &mut [App], x: f32| {
+            let world = apps[1].world_mut();
+            let mut players = world.query_filtered::<&mut Position, With<LocalPlayer>>();
+            players.single_mut(world).0 = Vec3::new(x, 0.0, 0.0);
+        };
+        move_bob(&mut apps, 1_000.0);
+        update_all(&mut apps, 60);
+
+        // bob is too far away to hear about it moving
+        let moved_to = Vec3::new(2.0, 0.0, 0.0);
+        apps[0].world_mut().get_mut::<Position>(prop).unwrap().0 = moved_to;
+        update_all(&mut apps, 10);
+        let latest = |apps: &[App]| {
+            let world = apps[1].world();
+            let prop = world.resource::<PropIndex>().get(&prop_uuid).unwrap();
+            let buffer = world.get::<SnapshotBuffer>(prop).unwrap();
+            buffer.latest().map(|snapshot| snapshot.position)
+        };
+        assert_ne!(latest(&apps), Some(moved_to));
+
+        // well before the next keyframe
+        move_bob(&mut apps, 0.0);
+        update_all(&mut apps, 30);
+        assert_eq!(latest(&apps), Some(moved_to));
+    }
+}
@@ -0,0 +1,299 @@
+use crate::networking::{Authority, PlayerUuid};
+use avian3d::prelude::{AngularVelocity, LinearVelocity, Position, Rotation};
+use bevy::prelude::*;
+use std::collections::VecDeque;
+use unavi_player::LocalPlayer;
+
+#[derive(Resource, Clone, Debug)]
+pub struct InterpolationSettings {
+    /// How far in the past remote entities are shown, in seconds. Snapshots
+    /// are placed at the time they were sent, so this needs to be longer than
+    /// the latency plus the gap between two updates plus the jitter on top.
+    pub delay: f64,
+    /// How long we keep predicting from the last snapshot's velocity once
+    /// we run out of snapshots, after that the entity stops where it is.
+    pub max_extrapolation: f64,
+    pub buffer_len: usize,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        Self {
+            delay: 0.15,
+            max_extrapolation: 0.25,
+            buffer_len: 32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Snapshot {
+    pub time: f64,
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub linear_velocity: Vec3,
+    pub angular_velocity: Vec3,
+}
+
+impl Snapshot {
+    fn lerp(&self, other: &Snapshot, t: f32) -> Snapshot {
+        Snapshot {
+            time: self.time + (other.time - self.time) * t as f64,
+            position: self.position.lerp(other.position, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            linear_velocity: self.linear_velocity.lerp(other.linear_velocity, t),
+            angular_velocity: self.angular_velocity.lerp(other.angular_velocity, t),
+        }
+    }
+
+    fn extrapolate(&self, dt: f32) -> Snapshot {
+        let spin = Quat::from_scaled_axis(self.angular_velocity * dt);
+        Snapshot {
+            time: self.time + dt as f64,
+            position: self.position + self.linear_velocity * dt,
+            rotation: (spin * self.rotation).normalize(),
+            ..*self
+        }
+    }
+}
+
+/// Received states of a remote entity, oldest first. Remote entities are
+/// drawn from this instead of having network updates written straight into
+/// their physics components.
+#[derive(Component, Clone, Debug, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotBuffer {
+    /// Inserts in time order, snapshots that arrive out of order still end up
+    /// in the right place.
+    pub fn push(&mut self, snapshot: Snapshot, max_len: usize) {
+        let index = self
+            .snapshots
+            .iter()
+            .rposition(|existing| existing.time <= snapshot.time)
+            .map_or(0, |index| index + 1);
+        self.snapshots.insert(index, snapshot);
+        while self.snapshots.len() > max_len.max(2) {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn latest(&self) -> Option<&Snapshot> {
+        self.snapshots.back()
+    }
+
+    /// The state at `time`, interpolated between the two snapshots around it
+    /// or extrapolated from the newest one for at most `max_extrapolation`.
+    pub fn sample(&self, time: f64, max_extrapolation: f64) -> Option<Snapshot> {
+        let first = self.snapshots.front()?;
+        if time <= first.time {
+            return Some(*first);
+        }
+        for (from, to) in self.snapshots.iter().zip(self.snapshots.iter().skip(1)) {
+            if time <= to.time {
+                let span = to.time - from.time;
+                if span <= f64::EPSILON {
+                    return Some(*to);
+                }
+                return Some(from.lerp(to, ((time - from.time) / span) as f32));
+            }
+        }
+        let latest = self.snapshots.back()?;
+        let dt = (time - latest.time).min(max_extrapolation);
+        Some(latest.extrapolate(dt as f32))
+    }
+
+    /// Drops snapshots nobody will sample anymore, always keeping the two
+    /// needed to interpolate up to `time`.
+    pub fn prune(&mut self, time: f64) {
+        while self.snapshots.len() > 2 && self.snapshots[1].time < time {
+            self.snapshots.pop_front();
+        }
+    }
+}
+
+/// Runs in a fixed schedule, where `Time` is `Time<Fixed>`. Snapshots are
+/// stamped in `Update` on the virtual clock, so that is the one we sample on.
+pub fn apply_snapshots(
+    time: Res<Time<Virtual>>,
+    settings: Res<InterpolationSettings>,
+    local_player: Query<&PlayerUuid, With<LocalPlayer>>,
+    mut remote: Query<(
+        &mut SnapshotBuffer,
+        &mut Position,
+        &mut Rotation,
+        &mut LinearVelocity,
+        Option<&mut AngularVelocity>,
+        Option<&Authority>,
+    )>,
+) {
+    let render_time = time.elapsed_seconds_f64() - settings.delay;
+    let local_uuid = local_player.get_single().ok();
+    for (
+        mut buffer,
+        mut position,
+        mut rotation,
+        mut linear_velocity,
+        angular_velocity,
+        authority,
+    ) in remote.iter_mut()
+    {
+        // props we simulate ourselves are driven by our own physics
+        if authority.is_some_and(|authority| Some(&authority.player) == local_uuid) {
+            if !buffer.is_empty() {
+                buffer.clear();
+            }
+            continue;
+        }
+        let Some(snapshot) = buffer.sample(render_time, settings.max_extrapolation) else {
+            continue;
+        };
+        buffer.prune(render_time);
+        position.0 = snapshot.position;
+        rotation.0 = snapshot.rotation;
+        linear_velocity.0 = snapshot.linear_velocity;
+        if let Some(mut angular_velocity) = angular_velocity {
+            angular_velocity.0 = snapshot.angular_velocity;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::test_app::{TestRng, FRAME};
+    use bevy::time::TimeUpdateStrategy;
+    use std::time::Duration;
+
+    /// Moving along x at one unit per second.
+    fn at(time: f64) -> Snapshot {
+        Snapshot {
+            time,
+            position: Vec3::new(time as f32, 0.0, 0.0),
+            rotation: Quat::IDENTITY,
+            linear_velocity: Vec3::X,
+            angular_velocity: Vec3::ZERO,
+        }
+    }
+
+    /// 20 sends a second, each stamped up to `jitter` off and delivered in
+    /// a shuffled order.
+    fn jittered(rng: &mut TestRng, count: usize, jitter: f64) -> Vec<Snapshot> {
+        let mut snapshots = (0..count)
+            .map(|i| {
+                let sent = i as f64 * 0.05;
+                let noise = (rng.below(1001) as f64 / 1000.0 - 0.5) * 2.0 * jitter;
+                Snapshot {
+                    time: sent + noise,
+                    ..at(sent)
+                }
+            })
+            .collect::<Vec<_>>();
+        for i in (1..snapshots.len()).rev() {
+            snapshots.swap(i, rng.below(i + 1));
+        }
+        snapshots
+    }
+
+    #[test]
+    fn out_of_order_snapshots_end_up_sorted() {
+        let mut rng = TestRng::new(8);
+        let mut buffer = SnapshotBuffer::default();
+        for snapshot in jittered(&mut rng, 40, 0.02) {
+            buffer.push(snapshot, 64);
+        }
+        let times = buffer.snapshots.iter().map(|s| s.time).collect::<Vec<_>>();
+        assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(buffer.latest().unwrap().time, times[times.len() - 1]);
+    }
+
+    #[test]
+    fn jitter_only_costs_as_much_as_the_jitter() {
+        let jitter = 0.01;
+        let mut rng = TestRng::new(9);
+        let mut buffer = SnapshotBuffer::default();
+        for snapshot in jittered(&mut rng, 40, jitter) {
+            buffer.push(snapshot, 64);
+        }
+        // the stamps are off but positions are where the sender was, between
+        // two snapshots the error is never worse than at either of them
+        for i in 10..180 {
+            let time = i as f64 * 0.01;
+            let sampled = buffer.sample(time, 0.25).unwrap().position.x;
+            assert!((sampled - time as f32).abs() <= jitter as f32 + 1e-4);
+        }
+    }
+
+    #[test]
+    fn extrapolation_stops() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(at(0.0), 8);
+        buffer.push(at(1.0), 8);
+        assert!((buffer.sample(1.1, 0.25).unwrap().position.x - 1.1).abs() < 1e-6);
+        assert_eq!(buffer.sample(5.0, 0.25).unwrap().position.x, 1.25);
+        // before the first snapshot we just show the first snapshot
+        assert_eq!(buffer.sample(-1.0, 0.25).unwrap().position.x, 0.0);
+    }
+
+    #[test]
+    fn pruning_keeps_what_sampling_needs() {
+        let mut buffer = SnapshotBuffer::default();
+        for i in 0..10 {
+            buffer.push(at(i as f64 * 0.1), 8);
+        }
+        // only 8 fit
+        assert_eq!(buffer.snapshots.front().unwrap().time, 0.2);
+        buffer.prune(0.55);
+        assert_eq!(buffer.snapshots.front().unwrap().time, 0.5);
+        buffer.prune(100.0);
+        assert_eq!(buffer.snapshots.len(), 2);
+        assert!((buffer.sample(0.85, 0.25).unwrap().position.x - 0.85).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sampled_on_the_clock_snapshots_are_stamped_with() {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+                FRAME,
+            )))
+            .init_resource::<InterpolationSettings>()
+            .add_systems(FixedPreUpdate, apply_snapshots);
+        let mut buffer = SnapshotBuffer::default();
+        for i in 0..40 {
+            buffer.push(at(i as f64 * 0.05), 64);
+        }
+        let entity = app
+            .world_mut()
+            .spawn((
+                buffer,
+                Position::default(),
+                Rotation::default(),
+                LinearVelocity::default(),
+            ))
+            .id();
+        let delay = app.world().resource::<InterpolationSettings>().delay;
+        // a frame rate that doesn't divide the fixed rate, so the fixed
+        // clock is behind the virtual one by a different amount every frame
+        for _ in 0..60 {
+            app.update();
+            let now = app.world().resource::<Time<Virtual>>().elapsed_seconds_f64();
+            let fixed = app.world().resource::<Time<Fixed>>().elapsed_seconds_f64();
+            let position = app.world().get::<Position>(entity).unwrap().x;
+            // nothing got applied if no fixed step ran yet
+            if fixed > 0.0 && now > delay {
+                assert!((position as f64 - (now - delay)).abs() < 1e-4);
+            }
+        }
+    }
+}
@@ -0,0 +1,982 @@
+use crate::custom_audio::audio_output::AudioOutput;
+use crate::custom_audio::spatial_audio::{SpatialAudioSink, SpatialAudioSinkBundle};
+use crate::file_sharing::{AvatarPart, AvatarPartEnum, LoadingBar};
+use crate::networking::message::{DeleteProp, PlayerPosition, SpawnProp};
+use crate::networking::systems::{
+    message_handling, remove_dead_players, sync_local_player_to_network,
+    sync_local_props_to_network,
+};
+use crate::voice_chat::VoiceMsg;
+use crate::SPAWN;
+use avian3d::collision::{Collider, CollisionLayers};
+use avian3d::prelude::{
+    AngularVelocity, GravityScale, LinearVelocity, LockedAxes, Position, RigidBody, Rotation,
+};
+use avian_interpolation3d::{InterpolateTransformFields, InterpolationMode};
+use bevy::app::App;
+use bevy::asset::AssetServer;
+use bevy::prelude::{
+    default, info, resource_exists_and_changed, warn, AppExtStates, BuildChildren, Commands,
+    Component, Entity, FixedPreUpdate, GlobalTransform, IntoSystemConfigs, Name, Plugin,
+    PostUpdate, PreUpdate, SceneBundle, SpatialBundle, Transform, Update, Vec3,
+};
+use crate::networking::clock::{sync_network_time, ClockSettings, NetworkTime};
+use crate::networking::codec::{CodecError, MessageKind};
+use crate::networking::conditions::{tick_network_conditions, NetworkConditions};
+use crate::networking::recording::{RecordedEvent, SessionRecorder};
+use crate::networking::connection::{
+    apply_connection_settings, expire_departed_players, update_connection_state, ConnectionEvent,
+    ConnectionSettings, ConnectionState, Departed, PeerResumed, Reconnect, ReconnectSettings,
+    SocketOpener,
+};
+use crate::networking::deletion::{
+    handle_delete_prop, send_delete_prop, PropDeleted, RequestDeleteProp,
+};
+use crate::networking::errors::{
+    count_network_errors, forget_peer_errors, BlockedPeers, NetworkError, NetworkErrorSettings,
+    PeerBlocked, PeerErrorCounters,
+};
+use crate::networking::handshake::{
+    handle_handshake, send_hello, track_peers, Handshake, HandshakeComplete, HandshakeMessage,
+    Handshakes, PeerAccepted, PeerRejected,
+};
+use crate::networking::heartbeat::{
+    handle_heartbeats, send_pings, time_out_silent_peers, Heartbeat, HeartbeatMessage,
+    HeartbeatSettings,
+};
+use crate::networking::index::NetworkIndexPlugin;
+use crate::networking::interest::{track_peer_positions, Interest, PeerPositions};
+use crate::networking::interpolation::{apply_snapshots, InterpolationSettings, SnapshotBuffer};
+use crate::networking::ownership::{
+    handle_authority_messages, reassign_orphaned_props, release_dropped_props,
+    request_held_props, send_authority_requests, AuthorityChanged, AuthorityMessage,
+    AuthorityPriority, RequestAuthority,
+};
+use crate::networking::registry::{unbind_disconnected_peers, PeerRegistry};
+use crate::networking::replication::{
+    apply_replication, receive_replication, send_replication, AuthorityRule, Reliability,
+    ReplicationAppExt, ReplicationMessage, ReplicationPlugin, ReplicationRule, SendMode,
+};
+use crate::networking::scheduler::{flush_outbox, SendSchedulerSettings};
+use crate::networking::sync::{sync_due, NetworkSyncSettings, PropSyncSettings, RemoteSequence};
+use crate::networking::traffic::{log_traffic, TrafficLogSettings};
+use crate::networking::transport::{
+    update_peers, Channel, NetworkSocket, PeerConnected, PeerDisconnected,
+};
+use crate::networking::rpc::{RpcAppExt, RpcDispatch, RpcMessage, RpcPlugin};
+use crate::networking::world_state::{
+    answer_world_snapshot_requests, handle_world_snapshot, request_world_snapshot,
+    GetWorldSnapshot, WorldSync,
+};
+use bevy_matchbox::prelude::PeerId;
+use bevy_vrm::VrmBundle;
+use rodio::SpatialSink;
+use serde::{Deserialize, Serialize};
+use std::str::Utf8Error;
+use bevy_health_bar3d::prelude::BarSettings;
+use futures::channel::mpsc::SendError;
+use unavi_avatar::{
+    default_character_animations, AvatarBundle, AverageVelocity, FallbackAvatar, DEFAULT_VRM,
+    PLAYER_HEIGHT, PLAYER_WIDTH,
+};
+use unavi_player::layers::LAYER_OTHER_PLAYER;
+
+pub mod clock;
+pub mod codec;
+pub mod conditions;
+pub mod connection;
+pub mod deletion;
+pub mod errors;
+pub mod handshake;
+pub mod heartbeat;
+pub mod index;
+pub mod interest;
+pub mod interpolation;
+pub mod ownership;
+pub mod recording;
+pub mod registry;
+pub mod replication;
+pub mod rpc;
+pub mod scheduler;
+#[cfg(not(target_family = "wasm"))]
+pub mod signaling;
+pub mod sync;
+#[cfg(test)]
+pub mod test_app;
+pub mod traffic;
+pub mod transport;
+pub mod world_state;
+
+#[derive(
+    Component, Clone, Debug, PartialEq, Eq, Hash, Ord, PartialOrd, Serialize, Deserialize,
+)]
+pub struct PlayerUuid(pub String);
+
+#[derive(Component, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Ord, PartialOrd)]
+pub struct PropUuid(pub String);
+
+#[derive(Component, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Authority {
+    pub(crate) player: PlayerUuid,
+    pub(crate) counter: u64,
+    pub(crate) priority: AuthorityPriority,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum Message {
+    SpawnProp(SpawnProp),
+    Replication(ReplicationMessage),
+    DeleteProp(DeleteProp),
+    PlayerPosition(PlayerPosition),
+    VoiceChat(VoiceMsg),
+    AvatarPart(AvatarPartEnum),
+    Handshake(Handshake),
+    Rpc(RpcMessage),
+    Authority(AuthorityMessage),
+    Heartbeat(Heartbeat),
+}
+
+/// The name a player introduced themselves with in their `Hello`.
+#[derive(Component, Clone, Debug)]
+pub struct DisplayName(pub String);
+
+#[derive(Component)]
+pub struct ExternalPlayer {
+    uuid: PlayerUuid,
+    peer_id: PeerId,
+}
+
+pub trait SocketSendMessage {
+    fn send_msg_unreliable(&mut self, peer: PeerId, message: &Message);
+    fn send_msg_reliable(&mut self, peer: PeerId, message: &Message);
+    fn receive_msg_reliable(&mut self) -> Vec<(PeerId, Result<Message, CodecError>)>;
+    fn receive_msg_unreliable(&mut self) -> Vec<(PeerId, Result<Message, CodecError>)>;
+    fn send_msg_all_reliable(&mut self, message: &Message);
+    fn send_msg_all_unreliable(&mut self, message: &Message);
+    fn try_send_msg_all_reliable(&mut self, message: &Message) -> Result<(), SendError>;
+    fn try_send_msg_reliable(&mut self, peer: PeerId, message: &Message) -> Result<(), SendError>;
+}
+
+impl NetworkSocket {
+    /// Everything but handshakes waits for the scheduler, handshakes have to
+    /// get there before anything else is let through anyway.
+    fn queue(&mut self, peer: PeerId, channel: Channel, message: &Message) {
+        if self.is_blocked(peer) {
+            return;
+        }
+        self.recorder().record(
+            peer,
+            RecordedEvent::Sent {
+                channel,
+                message: message.clone(),
+            },
+        );
+        let msg = codec::encode(message, self.format());
+        if message.kind().is_handshake() {
+            self.traffic_mut().record_sent(message.kind(), msg.len());
+            self.send(channel, msg.into(), peer);
+            return;
+        }
+        self.scheduler_mut().enqueue(peer, channel, message, msg);
+    }
+
+    fn receive_counted(&mut self, channel: Channel) -> Vec<(PeerId, Result<Message, CodecError>)> {
+        let mut messages = vec![];
+        for (id, packet) in self.receive(channel) {
+            let packets = match codec::peek_kind(&packet) {
+                Some(MessageKind::Batch) => match codec::split_batch(&packet) {
+                    Ok(packets) => packets,
+                    Err(err) => {
+                        messages.push((id, Err(err)));
+                        continue;
+                    }
+                },
+                _ => vec![&packet[..]],
+            };
+            for packet in packets {
+                if let Some(kind) = codec::peek_kind(packet) {
+                    self.traffic_mut().record_received(kind, packet.len());
+                }
+                let message = codec::decode(packet);
+                if let Ok(message) = &message {
+                    self.recorder().record(
+                        id,
+                        RecordedEvent::Received {
+                            channel,
+                            message: message.clone(),
+                        },
+                    );
+                }
+                messages.push((id, message));
+            }
+        }
+        messages
+    }
+}
+
+impl SocketSendMessage for NetworkSocket {
+    fn send_msg_unreliable(&mut self, peer: PeerId, message: &Message) {
+        self.queue(peer, Channel::Unreliable, message);
+    }
+    fn send_msg_reliable(&mut self, peer: PeerId, message: &Message) {
+        self.queue(peer, Channel::Reliable, message);
+    }
+    fn receive_msg_reliable(&mut self) -> Vec<(PeerId, Result<Message, CodecError>)> {
+        self.receive_counted(Channel::Reliable)
+    }
+    fn receive_msg_unreliable(&mut self) -> Vec<(PeerId, Result<Message, CodecError>)> {
+        self.receive_counted(Channel::Unreliable)
+    }
+    fn send_msg_all_reliable(&mut self, message: &Message) {
+        for peer in self.connected_peers() {
+            self.send_msg_reliable(peer, message);
+        }
+    }
+    fn send_msg_all_unreliable(&mut self, message: &Message) {
+        for peer in self.connected_peers() {
+            self.send_msg_unreliable(peer, message);
+        }
+    }
+
+    fn try_send_msg_all_reliable(&mut self, message: &Message) -> Result<(), SendError> {
+        for peer in self.connected_peers() {
+            self.try_send_msg_reliable(peer, message)?;
+        }
+        Ok(())
+    }
+
+    fn try_send_msg_reliable(&mut self, peer: PeerId, message: &Message) -> Result<(), SendError> {
+        if self.is_blocked(peer) {
+            return Ok(());
+        }
+        let msg = codec::encode(message, self.format());
+        let len = msg.len();
+
+        self.try_send(Channel::Reliable, msg.into(), peer)?;
+        self.traffic_mut().record_sent(message.kind(), len);
+        self.recorder().record(
+            peer,
+            RecordedEvent::Sent {
+                channel: Channel::Reliable,
+                message: message.clone(),
+            },
+        );
+        Ok(())
+    }
+}
+
+pub struct NetworkingPlugin;
+
+impl Plugin for NetworkingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<PlayerPosition>()
+            .add_event::<SpawnProp>()
+            .add_event::<DeleteProp>()
+            .add_event::<RequestDeleteProp>()
+            .add_event::<PropDeleted>()
+            .add_event::<PeerConnected>()
+            .add_event::<PeerDisconnected>()
+            .add_event::<HandshakeMessage>()
+            .add_event::<PeerAccepted>()
+            .add_event::<HandshakeComplete>()
+            .add_event::<PeerRejected>()
+            .add_event::<NetworkError>()
+            .add_event::<PeerBlocked>()
+            .add_event::<AuthorityMessage>()
+            .add_event::<RequestAuthority>()
+            .add_event::<AuthorityChanged>()
+            .add_event::<ConnectionEvent>()
+            .add_event::<PeerResumed>()
+            .add_event::<HeartbeatMessage>();
+
+        app.add_plugins((NetworkIndexPlugin, ReplicationPlugin, RpcPlugin))
+            .add_rpc::<GetWorldSnapshot>();
+
+        // props are spawned with `SpawnProp`, after that their owner keeps
+        // them moving through replication
+        let prop_motion = ReplicationRule {
+            reliability: Reliability::Reliable,
+            authority: AuthorityRule::Owner,
+            send: SendMode::OnRequest,
+        };
+        app.replicate_interpolated::<Position>("position", prop_motion, |position, snapshot| {
+            snapshot.position = position.0
+        })
+        .replicate_interpolated::<Rotation>("rotation", prop_motion, |rotation, snapshot| {
+            snapshot.rotation = rotation.0
+        })
+        .replicate_interpolated::<LinearVelocity>(
+            "linear_velocity",
+            prop_motion,
+            |velocity, snapshot| snapshot.linear_velocity = velocity.0,
+        )
+        .replicate_interpolated::<AngularVelocity>(
+            "angular_velocity",
+            prop_motion,
+            |velocity, snapshot| snapshot.angular_velocity = velocity.0,
+        );
+
+        app.init_resource::<Handshakes>()
+            .init_resource::<NetworkSyncSettings>()
+            .init_resource::<PropSyncSettings>()
+            .init_resource::<TrafficLogSettings>()
+            .init_resource::<SendSchedulerSettings>()
+            .init_resource::<Interest>()
+            .init_resource::<PeerPositions>()
+            .init_resource::<InterpolationSettings>()
+            .init_resource::<PeerRegistry>()
+            .init_resource::<NetworkErrorSettings>()
+            .init_resource::<PeerErrorCounters>()
+            .init_resource::<BlockedPeers>()
+            .init_resource::<ReconnectSettings>()
+            .init_resource::<HeartbeatSettings>()
+            .init_resource::<ClockSettings>()
+            .init_resource::<NetworkConditions>()
+            .init_resource::<SessionRecorder>()
+            .init_resource::<NetworkTime>()
+            .init_resource::<Reconnect>()
+            .init_resource::<SocketOpener>()
+            .init_resource::<WorldSync>()
+            .init_state::<ConnectionState>();
+
+        app.add_systems(
+            Update,
+            (tick_network_conditions, update_peers, track_peers, send_hello)
+                .chain()
+                .before(message_handling::route_messages),
+        )
+        .add_systems(Update, handle_handshake.after(message_handling::route_messages))
+        .add_systems(
+            Update,
+            (
+                request_world_snapshot
+                    .after(RpcDispatch)
+                    .after(handle_handshake),
+                answer_world_snapshot_requests.after(RpcDispatch),
+                handle_world_snapshot
+                    .after(RpcDispatch)
+                    .before(request_world_snapshot),
+            ),
+        )
+        .add_systems(
+            Update,
+            (
+                forget_peer_errors.after(update_peers),
+                unbind_disconnected_peers.after(update_peers),
+                reassign_orphaned_props
+                    .after(update_peers)
+                    .before(unbind_disconnected_peers),
+                count_network_errors.after(message_handling::route_messages),
+            ),
+        )
+        .add_systems(
+            Update,
+            (
+                (
+                    request_held_props,
+                    send_authority_requests,
+                    release_dropped_props,
+                )
+                    .chain(),
+                handle_authority_messages.after(message_handling::route_messages),
+            ),
+        );
+        app.add_systems(
+            Update,
+            (send_delete_prop, handle_delete_prop)
+                .chain()
+                .after(message_handling::route_messages),
+        );
+        app.add_systems(Update, message_handling::route_messages)
+            .add_systems(
+                Update,
+                (
+                    message_handling::player_position,
+                    (receive_replication, apply_replication).chain(),
+                )
+                    .after(message_handling::route_messages)
+                    .after(handle_handshake),
+            );
+
+        app.add_systems(
+            Update,
+            (
+                sync_local_props_to_network.run_if(sync_due(MessageKind::Replication)),
+                sync_local_player_to_network.run_if(sync_due(MessageKind::PlayerPosition)),
+                remove_dead_players
+                    .after(update_peers)
+                    .after(handle_handshake),
+                expire_departed_players,
+                send_pings,
+                handle_heartbeats.after(message_handling::route_messages),
+                time_out_silent_peers
+                    .after(handle_heartbeats)
+                    .before(remove_dead_players),
+                sync_network_time.after(handle_heartbeats),
+                log_traffic,
+                track_peer_positions.after(message_handling::route_messages),
+            ),
+        );
+
+        // runs before physics so avian_interpolation smooths the snapshots
+        // like any other fixed step movement
+        app.add_systems(FixedPreUpdate, apply_snapshots);
+        app.add_systems(PostUpdate, (send_replication, flush_outbox).chain());
+        app.add_systems(
+            PreUpdate,
+            (
+                apply_connection_settings
+                    .run_if(resource_exists_and_changed::<ConnectionSettings>),
+                update_connection_state,
+            )
+                .chain(),
+        );
+    }
+}
+
+pub mod message {
+    use crate::networking::{Authority, PlayerUuid, PropUuid};
+    use crate::props::PropSpec;
+    use avian3d::prelude::{LinearVelocity, Position, Rotation};
+    use bevy::prelude::Event;
+    use bevy_matchbox::prelude::PeerId;
+    use serde::{Deserialize, Serialize};
+
+    /// Spawns a prop on every peer, `prop` is resolved against each peer's own
+    /// `PropKinds`.
+    #[derive(Clone, Serialize, Deserialize, Debug, Event)]
+    pub struct SpawnProp {
+        pub authority: Authority,
+        pub prop_uuid: PropUuid,
+        pub prop: PropSpec,
+        pub position: Position,
+        pub rotation: Rotation,
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Debug, Event)]
+    pub struct DeleteProp {
+        pub authority: Authority,
+        pub prop_uuid: PropUuid,
+    }
+
+    #[derive(Clone, Serialize, Deserialize, Debug, Event)]
+    pub struct PlayerPosition {
+        pub player_uuid: PlayerUuid,
+        pub peer_id: PeerId,
+        /// Increases by one every send, receivers drop anything older than
+        /// what they already applied.
+        pub sequence: u64,
+        pub position: Position,
+        pub rotation: Rotation,
+        pub linear_velocity: LinearVelocity,
+        /// `NetworkTime` the state is from.
+        pub sent_at: f64,
+    }
+}
+
+pub mod systems {
+    use crate::networking::message::PlayerPosition;
+    use crate::networking::{
+        Authority, ExternalPlayer, Message, PlayerUuid, PropUuid, SocketSendMessage,
+    };
+    use crate::networking::clock::NetworkTime;
+    use crate::networking::codec::MessageKind;
+    use crate::networking::connection::Departed;
+    use crate::networking::index::PeerIndex;
+    use crate::networking::interest::{Interest, PeerPositions, PeerRateLimiter};
+    use crate::networking::replication::ReplicationOutbox;
+    use crate::networking::sync::{
+        NetworkSyncSettings, PeerSyncStates, PropSyncSettings, PropSyncState,
+    };
+    use crate::networking::transport::{NetworkSocket, PeerDisconnected};
+    use avian3d::prelude::{LinearVelocity, Position, Rotation, Sleeping};
+    use bevy::prelude::*;
+    use unavi_player::LocalPlayer;
+
+    /// Peers too far away to care about us still hear where we are about once
+    /// a second, otherwise neither side would notice coming back into range.
+    const CULLED_PLAYER_RATE_SCALE: f32 = 1.0 / 30.0;
+
+    pub fn sync_local_player_to_network(
+        mut socket: ResMut<NetworkSocket>,
+        time: Res<Time>,
+        network_time: Res<NetworkTime>,
+        sync_settings: Res<NetworkSyncSettings>,
+        interest: Res<Interest>,
+        peer_positions: Res<PeerPositions>,
+        // sent every tick rather than on change, this goes over the unreliable
+        // channel so the last update before standing still might get lost
+        local_player: Query<
+            (&Position, &Rotation, &LinearVelocity, &PlayerUuid),
+            With<LocalPlayer>,
+        >,
+        mut sequence: Local<u64>,
+        mut limiter: Local<PeerRateLimiter<()>>,
+    ) {
+        let Some(socket_id) = socket.id() else {
+            return;
+        };
+
+        let (position, rotation, linear_velocity, uuid) = match local_player.get_single() {
+            Ok(val) => val,
+            Err(err) => {
+                debug!("there is not exactly one local player: {}", err);
+                return;
+            }
+        };
+
+        *sequence += 1;
+        let message = Message::PlayerPosition(PlayerPosition {
+            player_uuid: uuid.clone(),
+            peer_id: socket_id,
+            sequence: *sequence,
+            position: position.clone(),
+            rotation: rotation.clone(),
+            linear_velocity: linear_velocity.clone(),
+            sent_at: network_time.now(),
+        });
+        let rate = sync_settings
+            .rate(MessageKind::PlayerPosition)
+            .unwrap_or_default();
+        let now = time.elapsed_seconds();
+        let peers = socket.connected_peers();
+        limiter.retain_peers(&peers);
+        for peer in peers {
+            let scale = interest
+                .rate_scale(peer_positions.get(peer), position.0)
+                .max(CULLED_PLAYER_RATE_SCALE);
+            if limiter.due(peer, (), now, rate, scale) {
+                socket.send_msg_unreliable(peer, &message);
+            }
+        }
+    }
+
+    /// Decides which props are worth sending to whom, `send_replication`
+    /// sends them.
+    pub fn sync_local_props_to_network(
+        mut commands: Commands,
+        mut socket: ResMut<NetworkSocket>,
+        mut outbox: ResMut<ReplicationOutbox>,
+        time: Res<Time>,
+        settings: Res<PropSyncSettings>,
+        sync_settings: Res<NetworkSyncSettings>,
+        interest: Res<Interest>,
+        peer_positions: Res<PeerPositions>,
+        mut limiter: Local<PeerRateLimiter<Entity>>,
+        // avian marks these changed every step even at rest, so rather than
+        // change detection we compare against what we last sent
+        mut local_props: Query<
+            (
+                Entity,
+                &Position,
+                &Rotation,
+                &LinearVelocity,
+                &Authority,
+                Has<Sleeping>,
+                Option<&mut PeerSyncStates>,
+            ),
+            With<PropUuid>,
+        >,
+        local_player: Query<&PlayerUuid, With<LocalPlayer>>,
+    ) {
+        if socket.id().is_none() {
+            return;
+        }
+        let player_uuid = match local_player.get_single() {
+            Ok(val) => val,
+            Err(err) => {
+                debug!("there is not exactly one local player: {}", err);
+                return;
+            }
+        };
+        let now = time.elapsed_seconds();
+        let rate = sync_settings.rate(MessageKind::Replication).unwrap_or_default();
+        let peers = socket.connected_peers();
+        // props that were despawned or handed over don't need a rate anymore
+        limiter.retain(|peer, prop| {
+            peers.contains(peer)
+                && local_props
+                    .get(*prop)
+                    .is_ok_and(|(.., authority, _, _)| authority.player == *player_uuid)
+        });
+
+        for (
+            entity,
+            position,
+            rotation,
+            linear_velocity,
+            authority,
+            sleeping,
+            states,
+        ) in local_props.iter_mut()
+        {
+            if authority.player != *player_uuid {
+                continue;
+            }
+            let current = PropSyncState {
+                authority: authority.clone(),
+                position: position.0,
+                rotation: rotation.0,
+                linear_velocity: linear_velocity.0,
+                sleeping,
+                time: now,
+            };
+            let mut inserted = None;
+            let states = match states {
+                Some(states) => states.into_inner(),
+                None => inserted.insert(PeerSyncStates::default()),
+            };
+            states.0.retain(|peer, _| peers.contains(peer));
+            for peer in peers.iter().copied() {
+                let last_sent = states.0.get(&peer);
+                if last_sent.is_some_and(|last_sent| !last_sent.needs_send(&settings, &current)) {
+                    continue;
+                }
+                let scale = interest.rate_scale(peer_positions.get(peer), position.0);
+                if scale <= 0.0 {
+                    continue;
+                }
+                if last_sent.map_or(true, |last_sent| last_sent.urgent(&settings, &current)) {
+                    limiter.record(peer, entity, now);
+                } else if !limiter.due(peer, entity, now, rate, scale) {
+                    continue;
+                }
+                outbox.request(entity, peer);
+                states.0.insert(peer, current.clone());
+            }
+            if let Some(states) = inserted {
+                commands.entity(entity).insert(states);
+            }
+        }
+    }
+
+    /// Players of disconnected peers stay for a while in case they reconnect,
+    /// see `expire_departed_players`.
+    pub fn remove_dead_players(
+        mut commands: Commands,
+        time: Res<Time>,
+        mut disconnected: EventReader<PeerDisconnected>,
+        peer_index: Res<PeerIndex>,
+    ) {
+        for PeerDisconnected(peer_id) in disconnected.read() {
+            if let Some(entity) = peer_index.get(*peer_id) {
+                commands.entity(entity).insert(Departed {
+                    since: time.elapsed_seconds(),
+                });
+            }
+        }
+    }
+
+    pub mod message_handling {
+        use crate::file_sharing::{AvatarPart, AvatarPartEnum};
+        use crate::networking::message::*;
+        use crate::networking::{ExternalPlayer, Message, PlayerUuid, SocketSendMessage};
+        use crate::voice_chat::VoiceMsg;
+        use crate::networking::clock::NetworkTime;
+        use crate::networking::errors::{BlockedPeers, NetworkError, NetworkErrorKind};
+        use crate::networking::handshake::{HandshakeMessage, Handshakes};
+        use crate::networking::heartbeat::HeartbeatMessage;
+        use crate::networking::index::PlayerIndex;
+        use crate::networking::interpolation::{InterpolationSettings, Snapshot, SnapshotBuffer};
+        use crate::networking::ownership::AuthorityMessage;
+        use crate::networking::registry::PeerRegistry;
+        use crate::networking::replication::ReplicationMessage;
+        use crate::networking::sync::RemoteSequence;
+        use crate::networking::transport::NetworkSocket;
+        use crate::networking::rpc::RpcReceived;
+        use bevy::prelude::*;
+
+        pub fn route_messages(
+            mut socket: ResMut<NetworkSocket>,
+            handshakes: Res<Handshakes>,
+            registry: Res<PeerRegistry>,
+            blocked: Res<BlockedPeers>,
+            mut errors: EventWriter<NetworkError>,
+            mut handshake_messages: EventWriter<HandshakeMessage>,
+            mut player_position: EventWriter<PlayerPosition>,
+            mut spawn_prop: EventWriter<SpawnProp>,
+            mut replication: EventWriter<ReplicationMessage>,
+            mut delete_prop: EventWriter<DeleteProp>,
+            mut voice_chat: EventWriter<VoiceMsg>,
+            mut avatar_parts: EventWriter<AvatarPartEnum>,
+            mut rpcs: EventWriter<RpcReceived>,
+            mut authority_messages: EventWriter<AuthorityMessage>,
+            mut heartbeats: EventWriter<HeartbeatMessage>,
+        ) {
+            for (id, message) in socket.receive_msg_reliable() {
+                if blocked.contains(id) {
+                    continue;
+                }
+                let message = match message {
+                    Ok(message) => message,
+                    Err(err) => {
+                        errors.send(NetworkError {
+                            peer: id,
+                            kind: NetworkErrorKind::Decode(err),
+                        });
+                        continue;
+                    }
+                };
+                // nothing but the handshake gets through until we know who they are
+                if !handshakes.is_accepted(id) && !matches!(message, Message::Handshake(_)) {
+                    continue;
+                }
+                if let Err(mismatch) = registry.check(id, &message) {
+                    errors.send(NetworkError {
+                        peer: id,
+                        kind: NetworkErrorKind::Spoofed(message.kind(), mismatch),
+                    });
+                    continue;
+                }
+                match message {
+                    Message::SpawnProp(sp) => {
+                        spawn_prop.send(sp);
+                    }
+                    Message::Replication(rm) => {
+                        replication.send(rm);
+                    }
+                    Message::DeleteProp(dp) => {
+                        delete_prop.send(dp);
+                    }
+                    Message::PlayerPosition(pp) => {
+                        player_position.send(pp);
+                    }
+                    Message::VoiceChat(vc) => {
+                        voice_chat.send(vc);
+                    }
+                    Message::AvatarPart(ap) => {
+                        avatar_parts.send(ap);
+                    }
+                    Message::Handshake(handshake) => {
+                        handshake_messages.send(HandshakeMessage {
+                            peer: id,
+                            handshake,
+                        });
+                    }
+                    Message::Rpc(message) => {
+                        rpcs.send(RpcReceived { peer: id, message });
+                    }
+                    Message::Authority(am) => {
+                        authority_messages.send(am);
+                    }
+                    Message::Heartbeat(_) => {
+                        errors.send(NetworkError {
+                            peer: id,
+                            kind: NetworkErrorKind::WrongChannel(message.kind()),
+                        });
+                    }
+                };
+            }
+            for (id, message) in socket.receive_msg_unreliable() {
+                if blocked.contains(id) {
+                    continue;
+                }
+                let message = match message {
+                    Ok(message) => message,
+                    Err(err) => {
+                        errors.send(NetworkError {
+                            peer: id,
+                            kind: NetworkErrorKind::Decode(err),
+                        });
+                        continue;
+                    }
+                };
+                if !handshakes.is_accepted(id) {
+                    continue;
+                }
+                if let Err(mismatch) = registry.check(id, &message) {
+                    errors.send(NetworkError {
+                        peer: id,
+                        kind: NetworkErrorKind::Spoofed(message.kind(), mismatch),
+                    });
+                    continue;
+                }
+                match message {
+                    Message::SpawnProp(sp) => {
+                        spawn_prop.send(sp);
+                    }
+                    Message::Replication(rm) => {
+                        replication.send(rm);
+                    }
+                    Message::DeleteProp(dp) => {
+                        delete_prop.send(dp);
+                    }
+                    Message::PlayerPosition(pp) => {
+                        player_position.send(pp);
+                    }
+                    Message::VoiceChat(vc) => {
+                        voice_chat.send(vc);
+                    }
+                    Message::Heartbeat(heartbeat) => {
+                        heartbeats.send(HeartbeatMessage {
+                            peer: id,
+                            heartbeat,
+                        });
+                    }
+                    Message::AvatarPart(_)
+                    | Message::Handshake(_)
+                    | Message::Rpc(_)
+                    | Message::Authority(_) => {
+                        errors.send(NetworkError {
+                            peer: id,
+                            kind: NetworkErrorKind::WrongChannel(message.kind()),
+                        });
+                    }
+                };
+            }
+        }
+
+        pub fn player_position(
+            time: Res<Time>,
+            network_time: Res<NetworkTime>,
+            settings: Res<InterpolationSettings>,
+            mut event_reader: EventReader<PlayerPosition>,
+            player_index: Res<PlayerIndex>,
+            mut external_players: Query<
+                (&mut SnapshotBuffer, &mut RemoteSequence),
+                With<ExternalPlayer>,
+            >,
+        ) {
+            // external players are spawned when their handshake is accepted
+            for player_position in event_reader.read() {
+                let Some(entity) = player_index.get(&player_position.player_uuid) else {
+                    continue;
+                };
+                let Ok((mut buffer, mut sequence)) = external_players.get_mut(entity) else {
+                    continue;
+                };
+                if !sequence.accept(player_position.sequence) {
+                    continue;
+                }
+                buffer.push(
+                    Snapshot {
+                        time: network_time
+                            .local_time_of(player_position.sent_at, time.elapsed_seconds_f64()),
+                        position: player_position.position.0,
+                        rotation: player_position.rotation.0,
+                        linear_velocity: player_position.linear_velocity.0,
+                        angular_velocity: Vec3::ZERO,
+                    },
+                    settings.buffer_len,
+                );
+            }
+        }
+    }
+}
+
+/// Without an audio output, e.g. when running headless, the player is spawned
+/// without a voice.
+pub fn spawn_external_player(
+    audio_output: Option<&AudioOutput>,
+    asset_server: &AssetServer,
+    commands: &mut Commands,
+    uuid: PlayerUuid,
+    display_name: DisplayName,
+    peer_id: PeerId,
+) -> Entity {
+    info!(
+        "spawning external player: {}, {} ({})",
+        peer_id, uuid.0, display_name.0
+    );
+
+    let animations = default_character_animations(&asset_server);
+
+    let body = commands
+        .spawn((
+            Collider::capsule(PLAYER_WIDTH / 2.0, PLAYER_HEIGHT - PLAYER_WIDTH),
+            CollisionLayers {
+                memberships: LAYER_OTHER_PLAYER,
+                ..default()
+            },
+            RigidBody::Dynamic,
+            LockedAxes::ROTATION_LOCKED,
+            GravityScale(0.0),
+            SpatialBundle {
+                global_transform: GlobalTransform::from_translation(SPAWN),
+                ..default()
+            },
+            uuid.clone(),
+            RemoteSequence::default(),
+            SnapshotBuffer::default(),
+            InterpolateTransformFields {
+                translation: InterpolationMode::Linear,
+                rotation: InterpolationMode::Linear,
+            },
+            Name::new(display_name.0.clone()),
+            display_name,
+            ExternalPlayer { uuid, peer_id },
+            LoadingBar {
+                len: 1,
+                current: 1,
+            },
+            BarSettings::<LoadingBar> {
+                width: 1.,
+                offset: 0.8,
+                ..default()
+            },
+        ))
+        .id();
+
+    if let Some(stream_handle) = audio_output.and_then(|output| output.stream_handle.as_ref()) {
+        match SpatialSink::try_new(
+            stream_handle,
+            [0.0, 0.0, 0.0],
+            (Vec3::X * 4.0 / -2.0).to_array(),
+            (Vec3::X * 4.0 / 2.0).to_array(),
+        ) {
+            Ok(sink) => {
+                commands.entity(body).insert(SpatialAudioSink { sink });
+            }
+            Err(err) => warn!("no voice for peer {}: {}", peer_id, err),
+        }
+    }
+
+    let avatar = commands
+        .spawn((
+            AvatarBundle {
+                animations,
+                fallback: FallbackAvatar,
+                velocity: AverageVelocity {
+                    target: Some(body),
+                    ..default()
+                },
+            },
+            VrmBundle {
+                scene_bundle: SceneBundle {
+                    transform: Transform::from_xyz(0.0, -PLAYER_HEIGHT / 2.0, 0.0),
+                    ..default()
+                },
+                vrm: asset_server.load(DEFAULT_VRM),
+                ..default()
+            },
+        ))
+        .id();
+
+    commands.entity(body).push_children(&[avatar]);
+    body
+}
+
+/// Hands a departed player over to the peer they came back as, keeping their
+/// avatar and everything else we already had for them.
+pub fn resume_external_player(
+    commands: &mut Commands,
+    player: Entity,
+    uuid: PlayerUuid,
+    display_name: DisplayName,
+    peer_id: PeerId,
+) {
+    info!(
+        "resuming external player: {}, {} ({})",
+        peer_id, uuid.0, display_name.0
+    );
+    commands
+        .entity(player)
+        .remove::<Departed>()
+        .insert((
+            // their sequence starts over with the new session
+            RemoteSequence::default(),
+            Name::new(display_name.0.clone()),
+            display_name,
+            ExternalPlayer { uuid, peer_id },
+        ));
+}
@@ -0,0 +1,716 @@
+use crate::networking::index::PropIndex;
+use crate::networking::registry::PeerRegistry;
+use crate::networking::transport::{NetworkSocket, PeerDisconnected};
+use crate::networking::{Authority, Message, PlayerUuid, PropUuid, SocketSendMessage};
+use avian3d::prelude::Sleeping;
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use unavi_player::LocalPlayer;
+
+/// How long we wait on an owner to answer before asking again.
+const REQUEST_RETRY: f64 = 0.5;
+
+/// How far past the counter we know a claim from another peer may be. Every
+/// claim is broadcast, so we only fall behind by the few that are still on
+/// their way, a claim far ahead is someone trying to lock the prop.
+const MAX_CLAIM_JUMP: u64 = 16;
+
+/// Breaks ties between claims made with the same counter, and decides
+/// whether an owner hands over a prop it is holding.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum AuthorityPriority {
+    #[default]
+    Normal,
+    /// Taken even out of someone's hands.
+    High,
+}
+
+impl Authority {
+    pub fn new(player: PlayerUuid) -> Self {
+        Self {
+            player,
+            counter: 0,
+            priority: AuthorityPriority::Normal,
+        }
+    }
+
+    pub fn player(&self) -> &PlayerUuid {
+        &self.player
+    }
+
+    /// Props nobody has touched yet, like the ones in the scene.
+    pub fn unowned() -> Self {
+        Self::new(PlayerUuid(String::new()))
+    }
+
+    pub fn is_unowned(&self) -> bool {
+        self.player.0.is_empty()
+    }
+
+    /// The claim `player` makes to take the prop over from this one, if the
+    /// counter has any room left.
+    pub fn next(&self, player: PlayerUuid, priority: AuthorityPriority) -> Option<Self> {
+        Some(Self {
+            player,
+            counter: self.counter.checked_add(1)?,
+            priority,
+        })
+    }
+
+    /// Whether a claim another peer sent is close enough to this one to be
+    /// one we just haven't heard about yet.
+    pub fn within_reach(&self, claim: &Authority) -> bool {
+        claim.counter <= self.counter.saturating_add(MAX_CLAIM_JUMP)
+    }
+
+    /// Whether every peer would pick this claim over `other`. Higher counters
+    /// win, then higher priority, then the lower player uuid, so two players
+    /// grabbing the same prop at once end up agreeing on one of them.
+    pub fn supersedes(&self, other: &Authority) -> bool {
+        self.rank() > other.rank()
+    }
+
+    fn rank(&self) -> (u64, AuthorityPriority, Reverse<&PlayerUuid>) {
+        (self.counter, self.priority, Reverse(&self.player))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Event)]
+pub enum AuthorityMessage {
+    /// Asks the owner to hand the prop over, only sent to the owner.
+    Request {
+        prop_uuid: PropUuid,
+        requester: PlayerUuid,
+        priority: AuthorityPriority,
+    },
+    /// The owner handing the prop over in answer to a `Request`.
+    Grant {
+        prop_uuid: PropUuid,
+        from: Authority,
+        to: Authority,
+    },
+    /// The owner isn't holding the prop anymore, anyone may take it without
+    /// asking. It keeps simulating it until someone does.
+    Release {
+        prop_uuid: PropUuid,
+        authority: Authority,
+    },
+    /// Taking a prop without asking, for released props or with high priority.
+    Steal {
+        prop_uuid: PropUuid,
+        authority: Authority,
+    },
+}
+
+impl AuthorityMessage {
+    pub fn prop_uuid(&self) -> &PropUuid {
+        match self {
+            AuthorityMessage::Request { prop_uuid, .. }
+            | AuthorityMessage::Grant { prop_uuid, .. }
+            | AuthorityMessage::Release { prop_uuid, .. }
+            | AuthorityMessage::Steal { prop_uuid, .. } => prop_uuid,
+        }
+    }
+
+    /// Who has to have sent this.
+    pub fn sender(&self) -> &PlayerUuid {
+        match self {
+            AuthorityMessage::Request { requester, .. } => requester,
+            AuthorityMessage::Grant { from, .. } => &from.player,
+            AuthorityMessage::Release { authority, .. }
+            | AuthorityMessage::Steal { authority, .. } => &authority.player,
+        }
+    }
+}
+
+/// A prop a local actor has in hand.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Held;
+
+/// The owner let go of this prop, see `AuthorityMessage::Release`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Released;
+
+/// A prop someone took without asking because it was free. Everyone else
+/// who grabbed it at the same time made their claim with the same counter,
+/// those still get a say until the tie-break settles it.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct TakenFree {
+    counter: u64,
+}
+
+/// Asks for authority over a prop, `Normal` goes through the owner unless
+/// the prop is free, `High` steals it.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct RequestAuthority {
+    pub prop: Entity,
+    pub priority: AuthorityPriority,
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct AuthorityChanged {
+    pub prop: Entity,
+    pub prop_uuid: PropUuid,
+    pub previous: Authority,
+    pub current: Authority,
+}
+
+/// Takes `claim` if it beats what the prop has, the one place a prop's
+/// authority changes.
+pub fn apply_claim(
+    commands: &mut Commands,
+    changed: &mut EventWriter<AuthorityChanged>,
+    prop: Entity,
+    prop_uuid: &PropUuid,
+    authority: &mut Authority,
+    claim: &Authority,
+) -> bool {
+    if !claim.supersedes(authority) {
+        return false;
+    }
+    let previous = std::mem::replace(authority, claim.clone());
+    commands.entity(prop).remove::<Released>();
+    changed.send(AuthorityChanged {
+        prop,
+        prop_uuid: prop_uuid.clone(),
+        previous,
+        current: claim.clone(),
+    });
+    true
+}
+
+/// Keeps asking for the props we hold but don't own, and takes back ones we
+/// released and picked up again.
+pub fn request_held_props(
+    time: Res<Time>,
+    held: Query<(Entity, &Authority, Has<Released>), With<Held>>,
+    local_player: Query<&PlayerUuid, With<LocalPlayer>>,
+    mut requests: EventWriter<RequestAuthority>,
+    mut last_requested: Local<HashMap<Entity, f64>>,
+) {
+    let Ok(local_player) = local_player.get_single() else {
+        return;
+    };
+    let now = time.elapsed_seconds_f64();
+    last_requested.retain(|prop, _| held.contains(*prop));
+    for (prop, authority, released) in held.iter() {
+        if authority.player == *local_player && !released {
+            last_requested.remove(&prop);
+            continue;
+        }
+        if last_requested
+            .get(&prop)
+            .is_some_and(|last| now - last < REQUEST_RETRY)
+        {
+            continue;
+        }
+        last_requested.insert(prop, now);
+        requests.send(RequestAuthority {
+            prop,
+            priority: AuthorityPriority::Normal,
+        });
+    }
+}
+
+pub fn send_authority_requests(
+    mut commands: Commands,
+    mut socket: ResMut<NetworkSocket>,
+    registry: Res<PeerRegistry>,
+    mut requests: EventReader<RequestAuthority>,
+    mut changed: EventWriter<AuthorityChanged>,
+    mut props: Query<(&PropUuid, &mut Authority, Has<Released>)>,
+    local_player: Query<&PlayerUuid, With<LocalPlayer>>,
+) {
+    let Ok(local_player) = local_player.get_single() else {
+        return;
+    };
+    for request in requests.read() {
+        let Ok((prop_uuid, mut authority, released)) = props.get_mut(request.prop) else {
+            continue;
+        };
+        if authority.player == *local_player && !released {
+            continue;
+        }
+        let free = authority.is_unowned() || released;
+        if free || request.priority > AuthorityPriority::Normal {
+            let Some(claim) = authority.next(local_player.clone(), request.priority) else {
+                continue;
+            };
+            socket.send_msg_all_reliable(&Message::Authority(AuthorityMessage::Steal {
+                prop_uuid: prop_uuid.clone(),
+                authority: claim.clone(),
+            }));
+            if free {
+                commands.entity(request.prop).insert(TakenFree {
+                    counter: claim.counter,
+                });
+            }
+            apply_claim(
+                &mut commands,
+                &mut changed,
+                request.prop,
+                prop_uuid,
+                &mut authority,
+                &claim,
+            );
+            continue;
+        }
+        let Some(owner) = registry.peer(&authority.player) else {
+            continue;
+        };
+        socket.send_msg_reliable(
+            owner,
+            &Message::Authority(AuthorityMessage::Request {
+                prop_uuid: prop_uuid.clone(),
+                requester: local_player.clone(),
+                priority: request.priority,
+            }),
+        );
+    }
+}
+
+/// Lets everyone know we stopped holding a prop we own.
+pub fn release_dropped_props(
+    mut commands: Commands,
+    mut socket: ResMut<NetworkSocket>,
+    mut dropped: RemovedComponents<Held>,
+    props: Query<(&PropUuid, &Authority)>,
+    local_player: Query<&PlayerUuid, With<LocalPlayer>>,
+) {
+    let Ok(local_player) = local_player.get_single() else {
+        return;
+    };
+    for prop in dropped.read() {
+        let Ok((prop_uuid, authority)) = props.get(prop) else {
+            continue;
+        };
+        if authority.player != *local_player {
+            continue;
+        }
+        socket.send_msg_all_reliable(&Message::Authority(AuthorityMessage::Release {
+            prop_uuid: prop_uuid.clone(),
+            authority: authority.clone(),
+        }));
+        commands.entity(prop).insert(Released);
+    }
+}
+
+pub fn handle_authority_messages(
+    mut commands: Commands,
+    mut socket: ResMut<NetworkSocket>,
+    prop_index: Res<PropIndex>,
+    registry: Res<PeerRegistry>,
+    mut messages: EventReader<AuthorityMessage>,
+    mut changed: EventWriter<AuthorityChanged>,
+    mut props: Query<(
+        &PropUuid,
+        &mut Authority,
+        Has<Held>,
+        Has<Released>,
+        Option<&TakenFree>,
+    )>,
+    local_player: Query<&PlayerUuid, With<LocalPlayer>>,
+) {
+    let Ok(local_player) = local_player.get_single() else {
+        return;
+    };
+    for message in messages.read() {
+        let Some(prop) = prop_index.get(message.prop_uuid()) else {
+            continue;
+        };
+        let Ok((prop_uuid, mut authority, held, released, taken_free)) = props.get_mut(prop) else {
+            continue;
+        };
+        match message {
+            AuthorityMessage::Request {
+                requester,
+                priority,
+                ..
+            } => {
+                // whoever we handed it to will answer the next one
+                if authority.player != *local_player {
+                    continue;
+                }
+                if held && *priority <= AuthorityPriority::Normal {
+                    continue;
+                }
+                let Some(grant) = authority.next(requester.clone(), *priority) else {
+                    continue;
+                };
+                socket.send_msg_all_reliable(&Message::Authority(AuthorityMessage::Grant {
+                    prop_uuid: prop_uuid.clone(),
+                    from: authority.clone(),
+                    to: grant.clone(),
+                }));
+                apply_claim(
+                    &mut commands,
+                    &mut changed,
+                    prop,
+                    prop_uuid,
+                    &mut authority,
+                    &grant,
+                );
+            }
+            AuthorityMessage::Grant { from, to, .. } => {
+                // only the holder can hand it over
+                if *from != *authority {
+                    continue;
+                }
+                if !authority.within_reach(to) {
+                    warn!(
+                        "ignoring grant of prop {} to counter {}, we are at {}",
+                        prop_uuid.0, to.counter, authority.counter
+                    );
+                    continue;
+                }
+                apply_claim(
+                    &mut commands,
+                    &mut changed,
+                    prop,
+                    prop_uuid,
+                    &mut authority,
+                    to,
+                );
+            }
+            AuthorityMessage::Steal {
+                authority: claim, ..
+            } => {
+                // a holder that left can't answer requests anymore, the heir
+                // takes their props without asking
+                let holder_left =
+                    authority.player != *local_player && registry.peer(&authority.player).is_none();
+                let free = authority.is_unowned() || released || holder_left;
+                let contested = taken_free.is_some_and(|taken| taken.counter == claim.counter);
+                if !free && !contested && claim.priority <= AuthorityPriority::Normal {
+                    warn!(
+                        "ignoring steal of prop {} by {}, {} is holding it",
+                        prop_uuid.0, claim.player.0, authority.player.0
+                    );
+                    continue;
+                }
+                if !authority.within_reach(claim) {
+                    warn!(
+                        "ignoring steal of prop {} at counter {}, we are at {}",
+                        prop_uuid.0, claim.counter, authority.counter
+                    );
+                    continue;
+                }
+                if free {
+                    commands.entity(prop).insert(TakenFree {
+                        counter: claim.counter,
+                    });
+                }
+                apply_claim(
+                    &mut commands,
+                    &mut changed,
+                    prop,
+                    prop_uuid,
+                    &mut authority,
+                    claim,
+                );
+            }
+            AuthorityMessage::Release {
+                authority: released,
+                ..
+            } => {
+                if *released == *authority {
+                    commands.entity(prop).insert(Released);
+                }
+            }
+        }
+    }
+}
+
+/// Who takes over the props of a player that left, the lowest uuid among the
+/// players still here. Every peer works this out on its own, and if two of
+/// them disagree the lower uuid's claim wins anyway.
+pub fn heir(registry: &PeerRegistry, local_player: &PlayerUuid, departed: PeerId) -> PlayerUuid {
+    registry
+        .iter()
+        .filter(|(peer, _)| **peer != departed)
+        .map(|(_, uuid)| uuid)
+        .chain([local_player])
+        .min()
+        .cloned()
+        .unwrap_or_else(|| local_player.clone())
+}
+
+/// Hands the props of disconnected players to their heir, released so anyone
+/// can pick them up. Runs before the registry forgets who the peer was. The
+/// heir announces its claim, for peers that saw the disconnect late or not at
+/// all, or that worked out a different heir.
+pub fn reassign_orphaned_props(
+    mut commands: Commands,
+    mut socket: ResMut<NetworkSocket>,
+    registry: Res<PeerRegistry>,
+    mut disconnected: EventReader<PeerDisconnected>,
+    mut changed: EventWriter<AuthorityChanged>,
+    mut props: Query<(Entity, &PropUuid, &mut Authority)>,
+    local_player: Query<&PlayerUuid, With<LocalPlayer>>,
+) {
+    let Ok(local_player) = local_player.get_single() else {
+        return;
+    };
+    for PeerDisconnected(peer) in disconnected.read() {
+        let Some(departed) = registry.player(*peer) else {
+            continue;
+        };
+        let heir = heir(&registry, local_player, *peer);
+        for (prop, prop_uuid, mut authority) in props.iter_mut() {
+            if authority.player != *departed {
+                continue;
+            }
+            let Some(claim) = authority.next(heir.clone(), AuthorityPriority::Normal) else {
+                continue;
+            };
+            if !apply_claim(
+                &mut commands,
+                &mut changed,
+                prop,
+                prop_uuid,
+                &mut authority,
+                &claim,
+            ) {
+                continue;
+            }
+            // whatever the last snapshot had it doing, our physics carries on
+            // from there
+            commands.entity(prop).insert(Released);
+            if heir == *local_player {
+                commands.entity(prop).remove::<Sleeping>();
+                socket.send_msg_all_reliable(&Message::Authority(AuthorityMessage::Steal {
+                    prop_uuid: prop_uuid.clone(),
+                    authority: claim.clone(),
+                }));
+                socket.send_msg_all_reliable(&Message::Authority(AuthorityMessage::Release {
+                    prop_uuid: prop_uuid.clone(),
+                    authority: claim,
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::test_app::{
+        headless_app, peer_id, raw_peer, received, say_hello, send_raw, spawn_local_player,
+        update_all,
+    };
+    use crate::networking::transport::{Channel, LoopbackHub, LoopbackTransport};
+    use avian3d::prelude::{LinearVelocity, Position};
+
+    fn claim(player: &str, counter: u64) -> Authority {
+        Authority {
+            player: PlayerUuid(player.to_string()),
+            counter,
+            priority: AuthorityPriority::Normal,
+        }
+    }
+
+    fn high(player: &str, counter: u64) -> Authority {
+        Authority {
+            priority: AuthorityPriority::High,
+            ..claim(player, counter)
+        }
+    }
+
+    #[test]
+    fn counters_dont_wrap() {
+        let last = claim("alice", u64::MAX);
+        assert!(last.next(PlayerUuid("bob".to_string()), AuthorityPriority::High).is_none());
+        let next = claim("alice", 3).next(PlayerUuid("bob".to_string()), AuthorityPriority::Normal);
+        assert_eq!(next, Some(claim("bob", 4)));
+    }
+
+    #[test]
+    fn claims_need_the_holder_and_a_believable_counter() {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        spawn_local_player(&mut app, "alice");
+        let prop_uuid = PropUuid("prop".to_string());
+        let prop = app
+            .world_mut()
+            .spawn((prop_uuid.clone(), claim("bob", 3)))
+            .id();
+        let target = peer_id(&mut app);
+        let mut bob = raw_peer(&hub, &mut app, "bob");
+        let mut mallory = raw_peer(&hub, &mut app, "mallory");
+        let mut send = |peer: &mut LoopbackTransport, message: AuthorityMessage| {
+            send_raw(peer, target, Channel::Reliable, &Message::Authority(message));
+            update_all(std::slice::from_mut(&mut app), 2);
+            app.world().get::<Authority>(prop).unwrap().clone()
+        };
+
+        // far enough ahead that nobody could ever take it back
+        let locked = send(
+            &mut mallory,
+            AuthorityMessage::Steal {
+                prop_uuid: prop_uuid.clone(),
+                authority: high("mallory", u64::MAX),
+            },
+        );
+        assert_eq!(locked, claim("bob", 3));
+
+        // bob is holding it, that takes asking or high priority
+        let grabbed = send(
+            &mut mallory,
+            AuthorityMessage::Steal {
+                prop_uuid: prop_uuid.clone(),
+                authority: claim("mallory", 4),
+            },
+        );
+        assert_eq!(grabbed, claim("bob", 3));
+
+        // handing over something that isn't theirs, now or one claim ahead
+        for from in [claim("mallory", 3), claim("mallory", 4)] {
+            let forged = send(
+                &mut mallory,
+                AuthorityMessage::Grant {
+                    prop_uuid: prop_uuid.clone(),
+                    to: from
+                        .next(from.player.clone(), AuthorityPriority::Normal)
+                        .unwrap(),
+                    from,
+                },
+            );
+            assert_eq!(forged, claim("bob", 3));
+        }
+
+        let granted = send(
+            &mut bob,
+            AuthorityMessage::Grant {
+                prop_uuid: prop_uuid.clone(),
+                from: claim("bob", 3),
+                to: claim("carol", 4),
+            },
+        );
+        assert_eq!(granted, claim("carol", 4));
+
+        // a stale grant of something bob no longer has
+        let stale = send(
+            &mut bob,
+            AuthorityMessage::Grant {
+                prop_uuid: prop_uuid.clone(),
+                from: claim("bob", 3),
+                to: claim("bob", 4),
+            },
+        );
+        assert_eq!(stale, claim("carol", 4));
+
+        // one we haven't heard about yet is fine
+        let stolen = send(
+            &mut mallory,
+            AuthorityMessage::Steal {
+                prop_uuid,
+                authority: high("mallory", 6),
+            },
+        );
+        assert_eq!(stolen, high("mallory", 6));
+    }
+
+    #[test]
+    fn grabbing_at_once_ends_in_agreement() {
+        let hub = LoopbackHub::new();
+        let mut apps = vec![headless_app(&hub), headless_app(&hub)];
+        let players = [
+            spawn_local_player(&mut apps[0], "alice"),
+            spawn_local_player(&mut apps[1], "bob"),
+        ];
+        update_all(&mut apps, 5);
+        let prop_uuid = PropUuid("ball".to_string());
+        let props = apps
+            .iter_mut()
+            .map(|app| {
+                app.world_mut()
+                    .spawn((prop_uuid.clone(), Authority::unowned()))
+                    .id()
+            })
+            .collect::<Vec<_>>();
+        update_all(&mut apps, 2);
+
+        for (app, prop) in apps.iter_mut().zip(&props) {
+            app.world_mut().send_event(RequestAuthority {
+                prop: *prop,
+                priority: AuthorityPriority::Normal,
+            });
+        }
+        update_all(&mut apps, 5);
+
+        // same counter, so the lower uuid wins on both
+        let winner = players.iter().min().unwrap();
+        let expected = Authority::unowned()
+            .next(winner.clone(), AuthorityPriority::Normal)
+            .unwrap();
+        for (app, prop) in apps.iter_mut().zip(props) {
+            assert_eq!(app.world().get::<Authority>(prop), Some(&expected));
+        }
+    }
+
+    #[test]
+    fn heir_takes_over_mid_throw_and_says_so() {
+        let hub = LoopbackHub::new();
+        let mut apps = vec![headless_app(&hub), headless_app(&hub)];
+        let players = [
+            spawn_local_player(&mut apps[0], "alice"),
+            spawn_local_player(&mut apps[1], "carol"),
+        ];
+        update_all(&mut apps, 5);
+        // raw peers sort after any generated uuid, so one of the apps inherits
+        let mut holder = hub.connect();
+        let mut watcher = hub.connect();
+        for app in apps.iter_mut() {
+            say_hello(&mut holder, app, "z-holder");
+            say_hello(&mut watcher, app, "z-watcher");
+        }
+        let heir = players.iter().min().unwrap().clone();
+        let heir_app = players.iter().position(|player| *player == heir).unwrap();
+        let heir_id = peer_id(&mut apps[heir_app]);
+
+        let prop_uuid = PropUuid("ball".to_string());
+        let props = apps
+            .iter_mut()
+            .map(|app| {
+                app.world_mut()
+                    .spawn((
+                        prop_uuid.clone(),
+                        claim("z-holder", 1),
+                        Position::default(),
+                        LinearVelocity(Vec3::new(4.0, 3.0, 0.0)),
+                    ))
+                    .id()
+            })
+            .collect::<Vec<_>>();
+        update_all(&mut apps, 2);
+        received(&mut watcher, Channel::Reliable);
+
+        drop(holder);
+        update_all(&mut apps, 5);
+
+        let expected = claim(&heir.0, 2);
+        for (app, prop) in apps.iter_mut().zip(props) {
+            let world = app.world();
+            assert_eq!(world.get::<Authority>(prop), Some(&expected));
+            assert!(world.get::<Released>(prop).is_some());
+            // still flying, nobody stopped it
+            assert_eq!(world.get::<LinearVelocity>(prop).unwrap().0, Vec3::new(4.0, 3.0, 0.0));
+        }
+        let announced = received(&mut watcher, Channel::Reliable)
+            .into_iter()
+            .filter(|(from, _)| *from == heir_id)
+            .any(|(_, message)| {
+                matches!(
+                    message,
+                    Message::Authority(AuthorityMessage::Steal { authority, .. })
+                        if authority == expected
+                )
+            });
+        assert!(announced);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::networking::codec::{self, PROTOCOL_VERSION};
+use crate::networking::transport::{Channel, NetworkTransport};
+use crate::networking::Message;
+use bevy::log::{error, info};
+use bevy::prelude::Resource;
+use bevy::utils::Instant;
+use bevy_matchbox::matchbox_socket::{Packet, PeerState};
+use bevy_matchbox::prelude::PeerId;
+use futures::channel::mpsc::SendError;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Written at the start of every recording, replays refuse recordings from
+/// another protocol version since their messages won't decode.
+const MAGIC: &[u8; 5] = b"p2pvr";
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum RecordedEvent {
+    Connected,
+    Disconnected,
+    Received { channel: Channel, message: Message },
+    Sent { channel: Channel, message: Message },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Record {
+    /// Seconds since the recording started.
+    pub time: f64,
+    /// Who sent it or who it went to.
+    pub peer: PeerId,
+    pub event: RecordedEvent,
+}
+
+/// Seconds between flushes, so a crash loses at most this much.
+const FLUSH_INTERVAL: f64 = 1.0;
+
+struct RecordingFile {
+    writer: BufWriter<File>,
+    start: Instant,
+    last_flush: f64,
+}
+
+/// Shared with every `NetworkSocket` the app opens, so a recording carries on
+/// across reconnects. Records nothing until started.
+#[derive(Resource, Clone, Default)]
+pub struct SessionRecorder(Arc<Mutex<Option<RecordingFile>>>);
+
+impl SessionRecorder {
+    /// Starts writing to `path`, replacing whatever was being recorded. Only
+    /// what happens from now on is in it, start before connecting to get the
+    /// handshakes a replay needs.
+    pub fn start(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path.as_ref())?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[PROTOCOL_VERSION])?;
+        info!("recording the session to {}", path.as_ref().display());
+        *self.0.lock().unwrap() = Some(RecordingFile {
+            writer,
+            start: Instant::now(),
+            last_flush: 0.0,
+        });
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        if let Some(mut file) = self.0.lock().unwrap().take() {
+            if let Err(err) = file.writer.flush() {
+                error!("couldn't finish the recording: {}", err);
+            }
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    pub fn record(&self, peer: PeerId, event: RecordedEvent) {
+        let mut file = self.0.lock().unwrap();
+        let Some(recording) = file.as_mut() else {
+            return;
+        };
+        let time = recording.start.elapsed().as_secs_f64();
+        let record = Record { time, peer, event };
+        let mut written = bincode::serialize_into(&mut recording.writer, &record);
+        if written.is_ok() && time - recording.last_flush >= FLUSH_INTERVAL {
+            recording.last_flush = time;
+            written = recording.writer.flush().map_err(Into::into);
+        }
+        if let Err(err) = written {
+            // a half written record would break everything after it
+            error!("stopped recording the session: {}", err);
+            *file = None;
+        }
+    }
+}
+
+/// Reads a whole recording made by `SessionRecorder`.
+pub fn read_recording(path: impl AsRef<Path>) -> io::Result<Vec<Record>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0; MAGIC.len() + 1];
+    reader.read_exact(&mut header)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a session recording",
+        ));
+    }
+    let version = header[MAGIC.len()];
+    if version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("recorded with protocol version {version}, we speak {PROTOCOL_VERSION}"),
+        ));
+    }
+    let mut records = vec![];
+    loop {
+        match bincode::deserialize_from::<_, Record>(&mut reader) {
+            Ok(record) => records.push(record),
+            Err(err) => match *err {
+                // a recording cut off mid record still replays up to there
+                bincode::ErrorKind::Io(ref io) if io.kind() == io::ErrorKind::UnexpectedEof => {
+                    break
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+            },
+        }
+    }
+    Ok(records)
+}
+
+/// Plays a recording back as if its peers were connected right now. Received
+/// messages come out of `receive` at the pace they were recorded, so they go
+/// through `route_messages` and everything after it like live ones. Anything
+/// sent is dropped.
+pub struct ReplayTransport {
+    id: PeerId,
+    records: VecDeque<Record>,
+    start: Instant,
+    speed: f64,
+    peer_changes: Vec<(PeerId, PeerState)>,
+    connected: HashSet<PeerId>,
+    inbox: [Vec<(PeerId, Packet)>; 2],
+}
+
+impl ReplayTransport {
+    pub fn new(records: Vec<Record>) -> Self {
+        Self {
+            id: PeerId(Uuid::new_v4()),
+            records: records.into(),
+            start: Instant::now(),
+            speed: 1.0,
+            peer_changes: vec![],
+            connected: HashSet::new(),
+            inbox: [vec![], vec![]],
+        }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(read_recording(path)?))
+    }
+
+    /// How much faster than recorded to play.
+    pub fn with_speed(mut self, speed: f64) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Moves everything that is due by now out of the recording.
+    fn advance(&mut self) {
+        let now = self.start.elapsed().as_secs_f64() * self.speed;
+        while self
+            .records
+            .front()
+            .is_some_and(|record| record.time <= now)
+        {
+            let record = self.records.pop_front().unwrap();
+            match record.event {
+                RecordedEvent::Connected => {
+                    self.connected.insert(record.peer);
+                    self.peer_changes.push((record.peer, PeerState::Connected));
+                }
+                RecordedEvent::Disconnected => {
+                    self.connected.remove(&record.peer);
+                    self.peer_changes
+                        .push((record.peer, PeerState::Disconnected));
+                }
+                RecordedEvent::Received { channel, message } => {
+                    let packet = codec::encode(&message, Default::default());
+                    self.inbox[channel.index()].push((record.peer, packet.into()));
+                }
+                RecordedEvent::Sent { .. } => {}
+            }
+        }
+    }
+}
+
+impl NetworkTransport for ReplayTransport {
+    fn id(&mut self) -> Option<PeerId> {
+        Some(self.id)
+    }
+
+    fn update_peers(&mut self) -> Vec<(PeerId, PeerState)> {
+        self.advance();
+        std::mem::take(&mut self.peer_changes)
+    }
+
+    fn connected_peers(&self) -> Vec<PeerId> {
+        self.connected.iter().copied().collect()
+    }
+
+    fn send(&mut self, _channel: Channel, _packet: Packet, _peer: PeerId) {}
+
+    fn try_send(
+        &mut self,
+        _channel: Channel,
+        _packet: Packet,
+        _peer: PeerId,
+    ) -> Result<(), SendError> {
+        Ok(())
+    }
+
+    fn receive(&mut self, channel: Channel) -> Vec<(PeerId, Packet)> {
+        // only advanced in `update_peers`, so peers always connect before
+        // their first message shows up
+        std::mem::take(&mut self.inbox[channel.index()])
+    }
+
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::index::PlayerIndex;
+    use crate::networking::interpolation::SnapshotBuffer;
+    use crate::networking::test_app::{
+        external_players, headless_app, spawn_local_player, update_all,
+    };
+    use crate::networking::transport::{LoopbackHub, NetworkSocket, OfflineTransport};
+    use crate::networking::PlayerUuid;
+    use avian3d::prelude::Position;
+    use bevy::prelude::*;
+    use std::time::Duration;
+    use unavi_player::LocalPlayer;
+
+    fn latest_x(app: &mut App, player: &PlayerUuid) -> Option<f32> {
+        let entity = app.world().resource::<PlayerIndex>().get(player)?;
+        let buffer = app.world().get::<SnapshotBuffer>(entity)?;
+        buffer.latest().map(|snapshot| snapshot.position.x)
+    }
+
+    #[test]
+    fn recordings_replay_what_we_received() {
+        let path = std::env::temp_dir().join(format!("p2pvr-test-{}.rec", Uuid::new_v4()));
+        let hub = LoopbackHub::new();
+        let mut apps = vec![headless_app(&hub), headless_app(&hub)];
+        let recorder = apps[0].world().resource::<SessionRecorder>().clone();
+        recorder.start(&path).unwrap();
+        spawn_local_player(&mut apps[0], "alice");
+        let bob = spawn_local_player(&mut apps[1], "bob");
+        apps[1]
+            .world_mut()
+            .query_filtered::<&mut Position, With<LocalPlayer>>()
+            .single_mut(apps[1].world_mut())
+            .0
+            .x = 3.0;
+        update_all(&mut apps, 30);
+        assert_eq!(latest_x(&mut apps[0], &bob), Some(3.0));
+
+        // losing the connection doesn't end the recording
+        let mut socket = apps[0].world_mut().resource_mut::<NetworkSocket>();
+        socket.replace_transport(OfflineTransport);
+        assert!(socket.recorder().is_recording());
+        recorder.stop();
+
+        let records = read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let duration = records.last().unwrap().time;
+        let mut replay = headless_app(&LoopbackHub::new());
+        replay.insert_resource(NetworkSocket::new(ReplayTransport::new(records)));
+        spawn_local_player(&mut replay, "carol");
+        let start = Instant::now();
+        while start.elapsed().as_secs_f64() <= duration {
+            replay.update();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        update_all(std::slice::from_mut(&mut replay), 5);
+        assert_eq!(external_players(&mut replay), vec![bob.clone()]);
+        assert_eq!(latest_x(&mut replay, &bob), Some(3.0));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::file_sharing::AvatarPartEnum;
+use crate::networking::transport::PeerDisconnected;
+use crate::networking::{Message, PlayerUuid};
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use std::collections::HashMap;
+
+/// Which player each connected peer is. A peer gets bound to exactly one
+/// `PlayerUuid` when its handshake is accepted, and from then on anything it
+/// sends has to be about that player.
+#[derive(Resource, Default)]
+pub struct PeerRegistry {
+    players: HashMap<PeerId, PlayerUuid>,
+    peers: HashMap<PlayerUuid, PeerId>,
+}
+
+impl PeerRegistry {
+    /// Fails if the uuid already belongs to a different peer.
+    pub fn bind(&mut self, peer: PeerId, uuid: PlayerUuid) -> Result<(), PeerId> {
+        if let Some(existing) = self.peers.get(&uuid) {
+            if *existing != peer {
+                return Err(*existing);
+            }
+        }
+        if let Some(old) = self.players.insert(peer, uuid.clone()) {
+            self.peers.remove(&old);
+        }
+        self.peers.insert(uuid, peer);
+        Ok(())
+    }
+
+    pub fn unbind(&mut self, peer: PeerId) -> Option<PlayerUuid> {
+        let uuid = self.players.remove(&peer)?;
+        self.peers.remove(&uuid);
+        Some(uuid)
+    }
+
+    pub fn player(&self, peer: PeerId) -> Option<&PlayerUuid> {
+        self.players.get(&peer)
+    }
+
+    pub fn peer(&self, uuid: &PlayerUuid) -> Option<PeerId> {
+        self.peers.get(uuid).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &PlayerUuid)> {
+        self.players.iter()
+    }
+
+    /// Whether `peer` is allowed to send `message`.
+    pub fn check(&self, peer: PeerId, message: &Message) -> Result<(), IdentityMismatch> {
+        if let Message::PlayerPosition(pp) = message {
+            if pp.peer_id != peer {
+                return Err(IdentityMismatch::Peer(pp.peer_id));
+            }
+        }
+        let Some(claimed) = message.claimed_player() else {
+            return Ok(());
+        };
+        match self.player(peer) {
+            Some(bound) if bound == claimed => Ok(()),
+            _ => Err(IdentityMismatch::Player(claimed.clone())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum IdentityMismatch {
+    Peer(PeerId),
+    Player(PlayerUuid),
+}
+
+impl Message {
+    /// The player a message says it comes from, if it says anything.
+    pub fn claimed_player(&self) -> Option<&PlayerUuid> {
+        match self {
+            Message::SpawnProp(sp) => Some(&sp.authority.player),
+            // not always the owner, see `AuthorityRule::Anyone`
+            Message::Replication(rm) => Some(&rm.sender),
+            Message::DeleteProp(dp) => Some(&dp.authority.player),
+            Message::PlayerPosition(pp) => Some(&pp.player_uuid),
+            Message::VoiceChat(vc) => Some(vc.uuid()),
+            Message::AvatarPart(ap) => match ap {
+                AvatarPartEnum::Len(uuid, ..) => Some(uuid),
+                AvatarPartEnum::AvatarPart(part) => Some(part.uuid()),
+                AvatarPartEnum::Done(uuid, _) => Some(uuid),
+            },
+            // the handshake is what binds the uuid in the first place
+            Message::Handshake(_) => None,
+            // requests and answers aren't about anyone in particular
+            Message::Rpc(_) => None,
+            Message::Authority(am) => Some(am.sender()),
+            Message::Heartbeat(_) => None,
+        }
+    }
+}
+
+pub fn unbind_disconnected_peers(
+    mut registry: ResMut<PeerRegistry>,
+    mut disconnected: EventReader<PeerDisconnected>,
+) {
+    for PeerDisconnected(peer) in disconnected.read() {
+        registry.unbind(*peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::errors::PeerErrorCounters;
+    use crate::networking::index::PlayerIndex;
+    use crate::networking::interpolation::SnapshotBuffer;
+    use crate::networking::message::PlayerPosition;
+    use crate::networking::test_app::{
+        headless_app, peer_id, raw_peer, send_raw, spawn_local_player, update_all,
+    };
+    use crate::networking::transport::{Channel, LoopbackHub, NetworkTransport};
+    use crate::voice_chat::VoiceMsg;
+    use avian3d::prelude::{LinearVelocity, Position, Rotation};
+
+    fn position(player: &str, peer: PeerId, sequence: u64, x: f32) -> Message {
+        Message::PlayerPosition(PlayerPosition {
+            player_uuid: PlayerUuid(player.to_string()),
+            peer_id: peer,
+            sequence,
+            position: Position::new(Vec3::new(x, 0.0, 0.0)),
+            rotation: Rotation::default(),
+            linear_velocity: LinearVelocity::default(),
+            sent_at: 0.0,
+        })
+    }
+
+    fn latest_x(app: &mut App, player: &str) -> Option<f32> {
+        let entity = app
+            .world()
+            .resource::<PlayerIndex>()
+            .get(&PlayerUuid(player.to_string()))
+            .unwrap();
+        let buffer = app.world().get::<SnapshotBuffer>(entity).unwrap();
+        buffer.latest().map(|snapshot| snapshot.position.x)
+    }
+
+    #[test]
+    fn bound_peers_can_only_speak_for_themselves() {
+        let mut registry = PeerRegistry::default();
+        let (alice, bob) = (PeerId(uuid::Uuid::from_u128(1)), PeerId(uuid::Uuid::from_u128(2)));
+        registry.bind(alice, PlayerUuid("alice".to_string())).unwrap();
+        assert_eq!(registry.bind(bob, PlayerUuid("alice".to_string())), Err(alice));
+        registry.bind(bob, PlayerUuid("bob".to_string())).unwrap();
+
+        assert!(registry.check(alice, &position("alice", alice, 0, 0.0)).is_ok());
+        assert!(matches!(
+            registry.check(alice, &position("bob", alice, 0, 0.0)),
+            Err(IdentityMismatch::Player(uuid)) if uuid.0 == "bob"
+        ));
+        assert!(matches!(
+            registry.check(alice, &position("alice", bob, 0, 0.0)),
+            Err(IdentityMismatch::Peer(peer)) if peer == bob
+        ));
+
+        // once they are gone nobody is allowed to be them
+        registry.unbind(bob);
+        assert!(registry.check(bob, &position("bob", bob, 0, 0.0)).is_err());
+    }
+
+    #[test]
+    fn spoofed_messages_are_dropped_and_counted() {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        spawn_local_player(&mut app, "alice");
+        let target = peer_id(&mut app);
+        let mut bob = raw_peer(&hub, &mut app, "bob");
+        let mut mallory = raw_peer(&hub, &mut app, "mallory");
+        let (bob_id, mallory_id) = (bob.id().unwrap(), mallory.id().unwrap());
+
+        let spoofed = [
+            // bob's uuid from mallory's peer
+            position("bob", mallory_id, 1, 66.0),
+            // mallory's uuid with bob's peer id
+            position("mallory", bob_id, 1, 66.0),
+            Message::VoiceChat(VoiceMsg::new(
+                vec![0; 10],
+                PlayerUuid("bob".to_string()),
+                1,
+                0.0,
+            )),
+        ];
+        for message in spoofed.iter() {
+            send_raw(&mut mallory, target, Channel::Unreliable, message);
+        }
+        update_all(std::slice::from_mut(&mut app), 2);
+
+        let counters = app.world().resource::<PeerErrorCounters>();
+        assert_eq!(counters.get(mallory_id), spoofed.len() as u32);
+        assert_eq!(counters.get(bob_id), 0);
+        assert_eq!(latest_x(&mut app, "bob"), None);
+
+        // the real bob still gets through
+        send_raw(&mut bob, target, Channel::Unreliable, &position("bob", bob_id, 1, 3.0));
+        update_all(std::slice::from_mut(&mut app), 2);
+        assert_eq!(latest_x(&mut app, "bob"), Some(3.0));
+        assert_eq!(app.world().resource::<PeerErrorCounters>().get(bob_id), 0);
+    }
+}
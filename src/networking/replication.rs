@@ -0,0 +1,631 @@
+use crate::networking::clock::NetworkTime;
+use crate::networking::handshake::HandshakeComplete;
+use crate::networking::index::PropIndex;
+use crate::networking::interpolation::{InterpolationSettings, Snapshot, SnapshotBuffer};
+use crate::networking::transport::{Channel, NetworkSocket};
+use crate::networking::{Authority, Message, PlayerUuid, PropUuid, SocketSendMessage};
+use bevy::ecs::component::{ComponentId, ComponentTicks, Tick};
+use bevy::ecs::event::ManualEventReader;
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use unavi_player::LocalPlayer;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Reliability {
+    #[default]
+    Reliable,
+    Unreliable,
+}
+
+/// Who gets to change a replicated component.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AuthorityRule {
+    /// Only whoever holds the entity's `Authority` sends it, anyone else's
+    /// updates are ignored.
+    #[default]
+    Owner,
+    /// Every peer sends its own changes, the last one to arrive wins.
+    Anyone,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SendMode {
+    /// Whenever the component changes on a `Replicated` entity.
+    #[default]
+    OnChange,
+    /// Only when asked through `ReplicationOutbox`, for components that change
+    /// every frame and need their own idea of when they are worth sending.
+    OnRequest,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReplicationRule {
+    pub reliability: Reliability,
+    pub authority: AuthorityRule,
+    pub send: SendMode,
+}
+
+/// One component, serialized with bincode. Components are told apart by the
+/// name they were registered with.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ComponentData {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum ReplicationAction {
+    /// Every replicated component the entity has, creating the entity on
+    /// peers that don't have it yet.
+    Spawn(Vec<ComponentData>),
+    Update(Vec<ComponentData>),
+    Despawn,
+}
+
+/// Replicated state of the entity with `prop_uuid`.
+#[derive(Clone, Serialize, Deserialize, Debug, Event)]
+pub struct ReplicationMessage {
+    pub sender: PlayerUuid,
+    pub prop_uuid: PropUuid,
+    /// Who the sender thinks owns the entity.
+    pub authority: Authority,
+    /// `NetworkTime` the state is from.
+    pub sent_at: f64,
+    pub action: ReplicationAction,
+}
+
+/// Fully replicated entities: spawned on every peer when added, sent whenever
+/// one of their `SendMode::OnChange` components changes and despawned
+/// everywhere when removed. Needs a `PropUuid` and an `Authority`, only the
+/// owner spawns and despawns.
+///
+/// Entities that are spawned some other way, like props, can still have
+/// their components replicated without it through `ReplicationOutbox`.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct Replicated;
+
+type ApplyFn = fn(&mut EntityWorldMut, &[u8]) -> bincode::Result<()>;
+type SnapshotFn = Box<dyn Fn(&[u8], &mut Snapshot) -> bincode::Result<()> + Send + Sync>;
+
+struct ReplicatedComponent {
+    name: &'static str,
+    id: ComponentId,
+    rule: ReplicationRule,
+    write: fn(EntityRef) -> Option<Vec<u8>>,
+    apply: ApplyFn,
+    /// Set for components that go through the entity's `SnapshotBuffer`
+    /// instead of being written straight into the component.
+    snapshot: Option<SnapshotFn>,
+}
+
+fn write_component<C: Component + Serialize>(entity: EntityRef) -> Option<Vec<u8>> {
+    let component = entity.get::<C>()?;
+    Some(bincode::serialize(component).expect("replicated components are always serializable"))
+}
+
+fn apply_component<C: Component + DeserializeOwned>(
+    entity: &mut EntityWorldMut,
+    data: &[u8],
+) -> bincode::Result<()> {
+    entity.insert(bincode::deserialize::<C>(data)?);
+    Ok(())
+}
+
+/// Every component type registered with `ReplicationAppExt`.
+#[derive(Resource, Default)]
+pub struct ReplicationRegistry {
+    components: Vec<ReplicatedComponent>,
+    /// Ticks `apply_replication` wrote at since we last sent, so changes
+    /// that came from the network aren't sent right back.
+    applied: Vec<Tick>,
+}
+
+impl ReplicationRegistry {
+    fn get(&self, name: &str) -> Option<&ReplicatedComponent> {
+        self.components
+            .iter()
+            .find(|component| component.name == name)
+    }
+
+    /// The components of `entity` that pass `filter`, and the channel they
+    /// have to go on together.
+    fn collect(
+        &self,
+        entity: EntityRef,
+        filter: impl Fn(&ReplicatedComponent, ComponentTicks) -> bool,
+    ) -> (Vec<ComponentData>, Channel) {
+        let mut components = vec![];
+        let mut channel = Channel::Unreliable;
+        for component in self.components.iter() {
+            let Some(ticks) = entity.get_change_ticks_by_id(component.id) else {
+                continue;
+            };
+            if !filter(component, ticks) {
+                continue;
+            }
+            let Some(data) = (component.write)(entity) else {
+                continue;
+            };
+            if component.rule.reliability == Reliability::Reliable {
+                channel = Channel::Reliable;
+            }
+            components.push(ComponentData {
+                name: component.name.to_string(),
+                data,
+            });
+        }
+        (components, channel)
+    }
+}
+
+pub trait ReplicationAppExt {
+    /// `name` is what the component goes by on the wire, it has to be the
+    /// same on every peer no matter where the type lives or how it was built.
+    fn replicate<C>(&mut self, name: &'static str, rule: ReplicationRule) -> &mut Self
+    where
+        C: Component + Serialize + DeserializeOwned;
+
+    /// Like `replicate`, but on entities with a `SnapshotBuffer` received
+    /// values are written into a snapshot with `write` and interpolated.
+    fn replicate_interpolated<C>(
+        &mut self,
+        name: &'static str,
+        rule: ReplicationRule,
+        write: fn(C, &mut Snapshot),
+    ) -> &mut Self
+    where
+        C: Component + Serialize + DeserializeOwned;
+}
+
+impl ReplicationAppExt for App {
+    fn replicate<C>(&mut self, name: &'static str, rule: ReplicationRule) -> &mut Self
+    where
+        C: Component + Serialize + DeserializeOwned,
+    {
+        register::<C>(self, name, rule, None);
+        self
+    }
+
+    fn replicate_interpolated<C>(
+        &mut self,
+        name: &'static str,
+        rule: ReplicationRule,
+        write: fn(C, &mut Snapshot),
+    ) -> &mut Self
+    where
+        C: Component + Serialize + DeserializeOwned,
+    {
+        let snapshot: SnapshotFn = Box::new(move |data, snapshot| {
+            write(bincode::deserialize::<C>(data)?, snapshot);
+            Ok(())
+        });
+        register::<C>(self, name, rule, Some(snapshot));
+        self
+    }
+}
+
+fn register<C>(
+    app: &mut App,
+    name: &'static str,
+    rule: ReplicationRule,
+    snapshot: Option<SnapshotFn>,
+) where
+    C: Component + Serialize + DeserializeOwned,
+{
+    let id = app.world_mut().init_component::<C>();
+    let mut registry = app
+        .world_mut()
+        .get_resource_or_insert_with(ReplicationRegistry::default);
+    assert!(registry.get(name).is_none(), "{name} is already replicated");
+    registry.components.push(ReplicatedComponent {
+        name,
+        id,
+        rule,
+        write: write_component::<C>,
+        apply: apply_component::<C>,
+        snapshot,
+    });
+}
+
+/// What `send_replication` sends this frame besides the changes it finds on
+/// its own.
+#[derive(Resource, Default)]
+pub struct ReplicationOutbox {
+    requested: Vec<(Entity, PeerId)>,
+    despawned: Vec<(PropUuid, Authority)>,
+}
+
+impl ReplicationOutbox {
+    /// Sends the `SendMode::OnRequest` components of `entity` to `peer`.
+    pub fn request(&mut self, entity: Entity, peer: PeerId) {
+        self.requested.push((entity, peer));
+    }
+}
+
+struct Incoming {
+    message: ReplicationMessage,
+    /// Whether it comes from whoever holds the entity's `Authority`, anything
+    /// else only gets to change `AuthorityRule::Anyone` components.
+    from_owner: bool,
+}
+
+/// Accepted messages waiting for `apply_replication`.
+#[derive(Resource, Default)]
+pub struct ReplicationInbox(Vec<Incoming>);
+
+/// Ownership only ever changes through `AuthorityMessage`s, where the rules
+/// about who may take what are. A message here counts as the owner's when
+/// the sender is who we think holds the entity, updates a new owner sends
+/// before we heard about their claim are dropped.
+pub fn receive_replication(
+    mut commands: Commands,
+    mut messages: EventReader<ReplicationMessage>,
+    prop_index: Res<PropIndex>,
+    authorities: Query<&Authority>,
+    mut inbox: ResMut<ReplicationInbox>,
+) {
+    for message in messages.read() {
+        let claims_ownership = message.sender == message.authority.player;
+        let existing = prop_index
+            .get(&message.prop_uuid)
+            .and_then(|entity| Some((entity, authorities.get(entity).ok()?)));
+        let from_owner = match existing {
+            Some((_, authority)) => claims_ownership && *authority == message.authority,
+            None => claims_ownership,
+        };
+        if let ReplicationAction::Despawn = message.action {
+            // a despawn from someone who has since lost the entity is stale
+            if let (true, Some((entity, _))) = (from_owner, existing) {
+                commands.entity(entity).despawn_recursive();
+            }
+            continue;
+        }
+        inbox.0.push(Incoming {
+            message: message.clone(),
+            from_owner,
+        });
+    }
+}
+
+/// Writes what `receive_replication` accepted into the world. Exclusive since
+/// the components it writes are only known at runtime.
+pub fn apply_replication(world: &mut World) {
+    let incoming = std::mem::take(&mut world.resource_mut::<ReplicationInbox>().0);
+    if incoming.is_empty() {
+        return;
+    }
+    let now = world.resource::<Time>().elapsed_seconds_f64();
+    let network_time = world.resource::<NetworkTime>().clone();
+    let buffer_len = world.resource::<InterpolationSettings>().buffer_len;
+    world.resource_scope(|world, mut registry: Mut<ReplicationRegistry>| {
+        for Incoming {
+            message,
+            from_owner,
+        } in incoming
+        {
+            let (components, spawn) = match message.action {
+                ReplicationAction::Spawn(components) => (components, true),
+                ReplicationAction::Update(components) => (components, false),
+                ReplicationAction::Despawn => continue,
+            };
+            // looked up here rather than in `receive_replication` so two
+            // spawns of the same entity in one frame make only one of it
+            let entity = match world.resource::<PropIndex>().get(&message.prop_uuid) {
+                Some(entity) => entity,
+                None if spawn && from_owner => world
+                    .spawn((
+                        message.prop_uuid.clone(),
+                        message.authority.clone(),
+                        Replicated,
+                    ))
+                    .id(),
+                None => continue,
+            };
+            let Some(mut entity) = world.get_entity_mut(entity) else {
+                continue;
+            };
+            let interpolated = entity.contains::<SnapshotBuffer>();
+            let mut snapshot: Option<Snapshot> = None;
+            for ComponentData { name, data } in components {
+                let Some(component) = registry.get(&name) else {
+                    warn!("received {name}, which we don't replicate");
+                    continue;
+                };
+                if component.rule.authority == AuthorityRule::Owner && !from_owner {
+                    continue;
+                }
+                let applied = match (&component.snapshot, interpolated) {
+                    (Some(write), true) => {
+                        let snapshot = snapshot.get_or_insert_with(|| {
+                            let buffer = entity.get::<SnapshotBuffer>().unwrap();
+                            buffer.latest().copied().unwrap_or(Snapshot {
+                                time: now,
+                                position: Vec3::ZERO,
+                                rotation: Quat::IDENTITY,
+                                linear_velocity: Vec3::ZERO,
+                                angular_velocity: Vec3::ZERO,
+                            })
+                        });
+                        write(&data, snapshot)
+                    }
+                    _ => (component.apply)(&mut entity, &data),
+                };
+                if let Err(err) = applied {
+                    warn!("couldn't apply replicated {name}: {err}");
+                }
+            }
+            if let Some(mut snapshot) = snapshot {
+                snapshot.time = network_time.local_time_of(message.sent_at, now);
+                entity
+                    .get_mut::<SnapshotBuffer>()
+                    .unwrap()
+                    .push(snapshot, buffer_len);
+            }
+        }
+        // every insert above happened at this tick
+        let tick = world.change_tick();
+        registry.applied.push(tick);
+    });
+}
+
+/// Sends spawns, changes and despawns of `Replicated` entities, and whatever
+/// was asked for through `ReplicationOutbox`. Exclusive for the same reason as
+/// `apply_replication`.
+pub fn send_replication(
+    world: &mut World,
+    mut handshakes: Local<ManualEventReader<HandshakeComplete>>,
+) {
+    let last_run = world.last_change_tick();
+    let this_run = world.change_tick();
+    let joined: Vec<PeerId> = handshakes
+        .read(world.resource::<Events<HandshakeComplete>>())
+        .map(|HandshakeComplete(peer)| *peer)
+        .collect();
+    let mut outbox = world.resource_mut::<ReplicationOutbox>();
+    let requested = std::mem::take(&mut outbox.requested);
+    let despawned = std::mem::take(&mut outbox.despawned);
+    let applied = std::mem::take(&mut world.resource_mut::<ReplicationRegistry>().applied);
+    let Ok(local) = world
+        .query_filtered::<&PlayerUuid, With<LocalPlayer>>()
+        .get_single(world)
+        .cloned()
+    else {
+        return;
+    };
+    let sent_at = world.resource::<NetworkTime>().now();
+    let peers = world.resource::<NetworkSocket>().connected_peers();
+    let message = |prop_uuid: &PropUuid, authority: &Authority, action| {
+        Message::Replication(ReplicationMessage {
+            sender: local.clone(),
+            prop_uuid: prop_uuid.clone(),
+            authority: authority.clone(),
+            sent_at,
+            action,
+        })
+    };
+
+    let mut outgoing: Vec<(PeerId, Channel, Message)> = vec![];
+    let mut replicated = world.query_filtered::<EntityRef, With<Replicated>>();
+    let registry = world.resource::<ReplicationRegistry>();
+    let may_send = |component: &ReplicatedComponent, owner: bool| {
+        owner || component.rule.authority == AuthorityRule::Anyone
+    };
+
+    for (entity, peer) in requested {
+        let Some(entity) = world.get_entity(entity) else {
+            continue;
+        };
+        let (Some(prop_uuid), Some(authority)) =
+            (entity.get::<PropUuid>(), entity.get::<Authority>())
+        else {
+            continue;
+        };
+        let owner = authority.player == local;
+        let (components, channel) = registry.collect(entity, |component, _| {
+            component.rule.send == SendMode::OnRequest && may_send(component, owner)
+        });
+        if !components.is_empty() {
+            let message = message(prop_uuid, authority, ReplicationAction::Update(components));
+            outgoing.push((peer, channel, message));
+        }
+    }
+
+    for entity in replicated.iter(world) {
+        let (Some(prop_uuid), Some(authority)) =
+            (entity.get::<PropUuid>(), entity.get::<Authority>())
+        else {
+            continue;
+        };
+        let owner = authority.player == local;
+        let from_us = |ticks: ComponentTicks| !applied.contains(&ticks.last_changed_tick());
+        let spawned = entity
+            .get_change_ticks::<Replicated>()
+            .is_some_and(|ticks| ticks.is_added(last_run, this_run) && from_us(ticks));
+        let spawn_to: &[PeerId] = match (owner, spawned) {
+            (false, _) => &[],
+            (true, true) => &peers,
+            (true, false) => &joined,
+        };
+        if !spawn_to.is_empty() {
+            let (components, _) = registry.collect(entity, |_, _| true);
+            let message = message(prop_uuid, authority, ReplicationAction::Spawn(components));
+            for peer in spawn_to {
+                outgoing.push((*peer, Channel::Reliable, message.clone()));
+            }
+            if spawned {
+                continue;
+            }
+        }
+        let (components, channel) = registry.collect(entity, |component, ticks| {
+            component.rule.send == SendMode::OnChange
+                && may_send(component, owner)
+                && ticks.is_changed(last_run, this_run)
+                && from_us(ticks)
+        });
+        if !components.is_empty() {
+            let message = message(prop_uuid, authority, ReplicationAction::Update(components));
+            for peer in peers.iter() {
+                outgoing.push((*peer, channel, message.clone()));
+            }
+        }
+    }
+
+    for (prop_uuid, authority) in despawned {
+        let message = message(&prop_uuid, &authority, ReplicationAction::Despawn);
+        for peer in peers.iter() {
+            outgoing.push((*peer, Channel::Reliable, message.clone()));
+        }
+    }
+
+    let mut socket = world.resource_mut::<NetworkSocket>();
+    for (peer, channel, message) in outgoing {
+        match channel {
+            Channel::Reliable => socket.send_msg_reliable(peer, &message),
+            Channel::Unreliable => socket.send_msg_unreliable(peer, &message),
+        }
+    }
+}
+
+fn queue_despawn(
+    trigger: Trigger<OnRemove, Replicated>,
+    entities: Query<(&PropUuid, &Authority)>,
+    local_player: Query<&PlayerUuid, With<LocalPlayer>>,
+    mut outbox: ResMut<ReplicationOutbox>,
+) {
+    let Ok((prop_uuid, authority)) = entities.get(trigger.entity()) else {
+        return;
+    };
+    if local_player
+        .get_single()
+        .is_ok_and(|local| *local == authority.player)
+    {
+        outbox
+            .despawned
+            .push((prop_uuid.clone(), authority.clone()));
+    }
+}
+
+pub struct ReplicationPlugin;
+
+impl Plugin for ReplicationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ReplicationMessage>()
+            .init_resource::<ReplicationRegistry>()
+            .init_resource::<ReplicationOutbox>()
+            .init_resource::<ReplicationInbox>()
+            .observe(queue_despawn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::ownership::AuthorityPriority;
+    use crate::networking::test_app::{
+        headless_app, peer_id, raw_peer, send_raw, spawn_local_player, update_all,
+    };
+    use crate::networking::transport::LoopbackHub;
+
+    /// Something only the game knows about, replicated like any other.
+    #[derive(Component, Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Score(u32);
+
+    fn score_of(app: &mut App, prop_uuid: &PropUuid) -> Option<u32> {
+        let entity = app.world().resource::<PropIndex>().get(prop_uuid)?;
+        app.world().get::<Score>(entity).map(|score| score.0)
+    }
+
+    fn set_score(app: &mut App, prop_uuid: &PropUuid, score: u32) {
+        let entity = app.world().resource::<PropIndex>().get(prop_uuid).unwrap();
+        app.world_mut().get_mut::<Score>(entity).unwrap().0 = score;
+    }
+
+    #[test]
+    fn replicated_entities_spawn_change_and_despawn_everywhere() {
+        let hub = LoopbackHub::new();
+        let mut apps = vec![headless_app(&hub), headless_app(&hub)];
+        for app in apps.iter_mut() {
+            app.replicate::<Score>("score", ReplicationRule::default());
+        }
+        let alice = spawn_local_player(&mut apps[0], "alice");
+        spawn_local_player(&mut apps[1], "bob");
+        update_all(&mut apps, 10);
+
+        let flag = PropUuid("flag".to_string());
+        let entity = apps[0]
+            .world_mut()
+            .spawn((
+                flag.clone(),
+                Authority::new(alice.clone()),
+                Replicated,
+                Score(1),
+            ))
+            .id();
+        update_all(&mut apps, 5);
+        assert_eq!(score_of(&mut apps[1], &flag), Some(1));
+        let copy = apps[1].world().resource::<PropIndex>().get(&flag).unwrap();
+        assert_eq!(
+            apps[1].world().get::<Authority>(copy),
+            Some(&Authority::new(alice.clone()))
+        );
+        assert!(apps[1].world().get::<Replicated>(copy).is_some());
+
+        set_score(&mut apps[0], &flag, 2);
+        update_all(&mut apps, 5);
+        assert_eq!(score_of(&mut apps[1], &flag), Some(2));
+
+        // only the owner's changes go anywhere
+        set_score(&mut apps[1], &flag, 99);
+        update_all(&mut apps, 5);
+        assert_eq!(score_of(&mut apps[0], &flag), Some(2));
+
+        apps[0].world_mut().despawn(entity);
+        update_all(&mut apps, 5);
+        assert_eq!(apps[1].world().resource::<PropIndex>().get(&flag), None);
+    }
+
+    #[test]
+    fn replication_cant_take_ownership() {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        app.replicate::<Score>("score", ReplicationRule::default());
+        let alice = spawn_local_player(&mut app, "alice");
+        let target = peer_id(&mut app);
+        let flag = PropUuid("flag".to_string());
+        let owned = Authority::new(alice.clone());
+        app.world_mut()
+            .spawn((flag.clone(), owned.clone(), Replicated, Score(1)));
+        let mut mallory = raw_peer(&hub, &mut app, "mallory");
+
+        // mallory's own claim, far ahead of alice's
+        let claim = Authority {
+            player: PlayerUuid("mallory".to_string()),
+            counter: 5,
+            priority: AuthorityPriority::High,
+        };
+        let score = bincode::serialize(&Score(66)).unwrap();
+        for action in [
+            ReplicationAction::Update(vec![ComponentData {
+                name: "score".to_string(),
+                data: score,
+            }]),
+            ReplicationAction::Despawn,
+        ] {
+            let message = Message::Replication(ReplicationMessage {
+                sender: claim.player.clone(),
+                prop_uuid: flag.clone(),
+                authority: claim.clone(),
+                sent_at: 0.0,
+                action,
+            });
+            send_raw(&mut mallory, target, Channel::Reliable, &message);
+            update_all(std::slice::from_mut(&mut app), 2);
+        }
+
+        let entity = app.world().resource::<PropIndex>().get(&flag).unwrap();
+        assert_eq!(app.world().get::<Authority>(entity), Some(&owned));
+        assert_eq!(score_of(&mut app, &flag), Some(1));
+    }
+}
@@ -0,0 +1,422 @@
+use crate::networking::systems::message_handling::route_messages;
+use crate::networking::transport::{NetworkSocket, PeerDisconnected};
+use crate::networking::{Message, SocketSendMessage};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display, Formatter};
+
+/// Something one peer can ask another, answered with a `Response`. Register
+/// with `RpcAppExt::add_rpc`.
+pub trait Rpc: Serialize + DeserializeOwned + Clone + Debug + Send + Sync + 'static {
+    type Response: Serialize + DeserializeOwned + Clone + Debug + Send + Sync + 'static;
+
+    /// What requests are told apart by on the wire, it has to be the same on
+    /// every peer no matter where the type lives or how it was built.
+    const NAME: &'static str;
+}
+
+/// Only unique among our own requests, answers are matched by peer and id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RequestId(u64);
+
+#[cfg(test)]
+impl RequestId {
+    pub(crate) fn new(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RpcError {
+    /// No answer within `RpcSettings::timeout`.
+    TimedOut,
+    /// They left before answering.
+    Disconnected,
+    /// They don't handle this kind of request.
+    Unsupported(String),
+    /// The request or the answer didn't decode.
+    Malformed(String),
+    /// They handled it and said no.
+    Failed(String),
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::TimedOut => write!(f, "timed out"),
+            RpcError::Disconnected => write!(f, "peer disconnected"),
+            RpcError::Unsupported(name) => write!(f, "peer doesn't handle {name}"),
+            RpcError::Malformed(err) => write!(f, "malformed: {err}"),
+            RpcError::Failed(reason) => write!(f, "failed: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// Only ever sent over the reliable channel.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub enum RpcMessage {
+    Request {
+        id: RequestId,
+        name: String,
+        data: Vec<u8>,
+    },
+    Response {
+        id: RequestId,
+        result: Result<Vec<u8>, RpcError>,
+    },
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct RpcReceived {
+    pub peer: PeerId,
+    pub message: RpcMessage,
+}
+
+/// Someone asked us something, answer with `Rpcs::respond`. Requests that
+/// never get an answer time out on their side.
+#[derive(Event, Clone, Debug)]
+pub struct RpcRequest<R: Rpc> {
+    pub peer: PeerId,
+    pub id: RequestId,
+    pub request: R,
+}
+
+/// How a request we made with `Rpcs::request` ended.
+#[derive(Event, Clone, Debug)]
+pub struct RpcResult<R: Rpc> {
+    pub peer: PeerId,
+    pub id: RequestId,
+    pub result: Result<R::Response, RpcError>,
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct RpcSettings {
+    /// Seconds we wait for an answer.
+    pub timeout: f32,
+}
+
+impl Default for RpcSettings {
+    fn default() -> Self {
+        Self { timeout: 10.0 }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Pending {
+    peer: PeerId,
+    name: &'static str,
+    deadline: f64,
+}
+
+/// Requests waiting for an answer.
+#[derive(Resource, Default)]
+pub struct PendingRpcs {
+    next_id: u64,
+    pending: HashMap<RequestId, Pending>,
+    /// Ended without an answer, waiting for the dispatcher of their type.
+    failed: Vec<(RequestId, Pending, RpcError)>,
+}
+
+/// Names of every registered `Rpc`.
+#[derive(Resource, Default)]
+struct RpcRegistry(HashSet<&'static str>);
+
+#[derive(SystemParam)]
+pub struct Rpcs<'w> {
+    socket: ResMut<'w, NetworkSocket>,
+    pending: ResMut<'w, PendingRpcs>,
+    settings: Res<'w, RpcSettings>,
+    time: Res<'w, Time>,
+}
+
+impl Rpcs<'_> {
+    /// The answer shows up as an `RpcResult<R>` with the returned id.
+    pub fn request<R: Rpc>(&mut self, peer: PeerId, request: &R) -> RequestId {
+        let id = RequestId(self.pending.next_id);
+        self.pending.next_id += 1;
+        let name = R::NAME;
+        self.pending.pending.insert(
+            id,
+            Pending {
+                peer,
+                name,
+                deadline: self.time.elapsed_seconds_f64() + self.settings.timeout as f64,
+            },
+        );
+        let data = bincode::serialize(request).expect("requests are always serializable");
+        self.socket.send_msg_reliable(
+            peer,
+            &Message::Rpc(RpcMessage::Request {
+                id,
+                name: name.to_string(),
+                data,
+            }),
+        );
+        id
+    }
+
+    pub fn respond<R: Rpc>(
+        &mut self,
+        peer: PeerId,
+        id: RequestId,
+        result: Result<R::Response, RpcError>,
+    ) {
+        let result = result.map(|response| {
+            bincode::serialize(&response).expect("responses are always serializable")
+        });
+        self.socket
+            .send_msg_reliable(peer, &Message::Rpc(RpcMessage::Response { id, result }));
+    }
+
+    /// Stops waiting, whatever they answer is dropped.
+    pub fn cancel(&mut self, id: RequestId) {
+        self.pending.pending.remove(&id);
+    }
+
+    pub fn is_pending(&self, id: RequestId) -> bool {
+        self.pending.pending.contains_key(&id)
+    }
+}
+
+fn fail_rpcs(
+    time: Res<Time>,
+    mut pending: ResMut<PendingRpcs>,
+    mut disconnected: EventReader<PeerDisconnected>,
+) {
+    let gone: Vec<PeerId> = disconnected
+        .read()
+        .map(|PeerDisconnected(peer)| *peer)
+        .collect();
+    let now = time.elapsed_seconds_f64();
+    let PendingRpcs {
+        pending, failed, ..
+    } = &mut *pending;
+    pending.retain(|id, request| {
+        let error = if gone.contains(&request.peer) {
+            RpcError::Disconnected
+        } else if now >= request.deadline {
+            RpcError::TimedOut
+        } else {
+            return true;
+        };
+        failed.push((*id, request.clone(), error));
+        false
+    });
+}
+
+fn reject_unknown_rpcs(
+    mut rpcs: Rpcs,
+    registry: Res<RpcRegistry>,
+    mut received: EventReader<RpcReceived>,
+) {
+    for RpcReceived { peer, message } in received.read() {
+        if let RpcMessage::Request { id, name, .. } = message {
+            if !registry.0.contains(name.as_str()) {
+                rpcs.socket.send_msg_reliable(
+                    *peer,
+                    &Message::Rpc(RpcMessage::Response {
+                        id: *id,
+                        result: Err(RpcError::Unsupported(name.clone())),
+                    }),
+                );
+            }
+        }
+    }
+}
+
+/// Turns the requests and answers for `R` into events.
+fn dispatch_rpcs<R: Rpc>(
+    mut rpcs: Rpcs,
+    mut received: EventReader<RpcReceived>,
+    mut requests: EventWriter<RpcRequest<R>>,
+    mut results: EventWriter<RpcResult<R>>,
+) {
+    let name = R::NAME;
+    for RpcReceived { peer, message } in received.read() {
+        match message {
+            RpcMessage::Request {
+                id,
+                name: requested,
+                data,
+            } if requested == name => match bincode::deserialize::<R>(data) {
+                Ok(request) => {
+                    requests.send(RpcRequest {
+                        peer: *peer,
+                        id: *id,
+                        request,
+                    });
+                }
+                Err(err) => {
+                    rpcs.respond::<R>(*peer, *id, Err(RpcError::Malformed(err.to_string())))
+                }
+            },
+            RpcMessage::Request { .. } => {}
+            RpcMessage::Response { id, result } => {
+                // only the peer we asked gets to answer
+                if !rpcs
+                    .pending
+                    .pending
+                    .get(id)
+                    .is_some_and(|pending| pending.name == name && pending.peer == *peer)
+                {
+                    continue;
+                }
+                rpcs.pending.pending.remove(id);
+                let result = result.clone().and_then(|data| {
+                    bincode::deserialize::<R::Response>(&data)
+                        .map_err(|err| RpcError::Malformed(err.to_string()))
+                });
+                results.send(RpcResult {
+                    peer: *peer,
+                    id: *id,
+                    result,
+                });
+            }
+        }
+    }
+    let (ours, others) = std::mem::take(&mut rpcs.pending.failed)
+        .into_iter()
+        .partition(|(_, pending, _)| pending.name == name);
+    rpcs.pending.failed = others;
+    for (id, pending, error) in ours {
+        results.send(RpcResult {
+            peer: pending.peer,
+            id,
+            result: Err(error),
+        });
+    }
+}
+
+/// Every dispatcher, read `RpcRequest`s and `RpcResult`s after this.
+#[derive(SystemSet, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RpcDispatch;
+
+pub trait RpcAppExt {
+    fn add_rpc<R: Rpc>(&mut self) -> &mut Self;
+}
+
+impl RpcAppExt for App {
+    fn add_rpc<R: Rpc>(&mut self) -> &mut Self {
+        let name = R::NAME;
+        let mut registry = self
+            .world_mut()
+            .get_resource_or_insert_with(RpcRegistry::default);
+        assert!(registry.0.insert(name), "{name} is already registered");
+        self.add_event::<RpcRequest<R>>()
+            .add_event::<RpcResult<R>>()
+            .add_systems(Update, dispatch_rpcs::<R>.in_set(RpcDispatch))
+    }
+}
+
+pub struct RpcPlugin;
+
+impl Plugin for RpcPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RpcReceived>()
+            .init_resource::<RpcSettings>()
+            .init_resource::<PendingRpcs>()
+            .init_resource::<RpcRegistry>()
+            .configure_sets(Update, RpcDispatch.after(route_messages))
+            .add_systems(
+                Update,
+                (
+                    fail_rpcs.before(RpcDispatch),
+                    reject_unknown_rpcs.after(route_messages),
+                ),
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::test_app::{
+        headless_app, peer_id, raw_peer, spawn_local_player, update_all, FRAME,
+    };
+    use crate::networking::transport::{LoopbackHub, NetworkTransport};
+    use bevy::ecs::system::RunSystemOnce;
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct Echo(u32);
+
+    impl Rpc for Echo {
+        type Response = u32;
+        const NAME: &'static str = "echo";
+    }
+
+    #[derive(Resource, Default)]
+    struct Answers(Vec<RpcResult<Echo>>);
+
+    fn collect_answers(mut results: EventReader<RpcResult<Echo>>, mut answers: ResMut<Answers>) {
+        answers.0.extend(results.read().cloned());
+    }
+
+    fn echo_app(hub: &LoopbackHub) -> App {
+        let mut app = headless_app(hub);
+        app.add_rpc::<Echo>()
+            .init_resource::<Answers>()
+            .add_systems(Update, collect_answers.after(RpcDispatch));
+        app
+    }
+
+    fn ask(app: &mut App, peer: PeerId) -> RequestId {
+        app.world_mut()
+            .run_system_once_with(peer, |In(peer): In<PeerId>, mut rpcs: Rpcs| {
+                rpcs.request(peer, &Echo(1))
+            })
+    }
+
+    #[test]
+    fn unanswered_requests_time_out() {
+        let hub = LoopbackHub::new();
+        let mut app = echo_app(&hub);
+        app.insert_resource(RpcSettings { timeout: 1.0 });
+        spawn_local_player(&mut app, "alice");
+        // a raw peer never answers anything
+        let bob = raw_peer(&hub, &mut app, "bob");
+        let bob_id = bob.id().unwrap();
+
+        let id = ask(&mut app, bob_id);
+        update_all(std::slice::from_mut(&mut app), (0.5 / FRAME) as usize);
+        assert!(app.world().resource::<Answers>().0.is_empty());
+
+        update_all(std::slice::from_mut(&mut app), (0.6 / FRAME) as usize);
+        let answers = &app.world().resource::<Answers>().0;
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].peer, bob_id);
+        assert_eq!(answers[0].id, id);
+        assert_eq!(answers[0].result, Err(RpcError::TimedOut));
+        assert!(!app
+            .world()
+            .resource::<PendingRpcs>()
+            .pending
+            .contains_key(&id));
+    }
+
+    #[test]
+    fn requests_nobody_handles_are_unsupported() {
+        let hub = LoopbackHub::new();
+        // only the first one knows about echo
+        let mut apps = vec![echo_app(&hub), headless_app(&hub)];
+        spawn_local_player(&mut apps[0], "alice");
+        spawn_local_player(&mut apps[1], "bob");
+        update_all(&mut apps, 10);
+
+        let bob_id = peer_id(&mut apps[1]);
+        let id = ask(&mut apps[0], bob_id);
+        update_all(&mut apps, 5);
+        let answers = &apps[0].world().resource::<Answers>().0;
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].id, id);
+        assert_eq!(
+            answers[0].result,
+            Err(RpcError::Unsupported(Echo::NAME.to_string()))
+        );
+    }
+}
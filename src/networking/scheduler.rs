@@ -0,0 +1,419 @@
+use crate::networking::codec::{MessageKind, BATCH_LEN_PREFIX, HEADER_LEN};
+use crate::networking::replication::{ReplicationAction, ReplicationMessage};
+use crate::networking::transport::{Channel, NetworkSocket};
+use crate::networking::Message;
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use std::collections::{HashMap, VecDeque};
+
+/// Limits and priorities for everything going through `SocketSendMessage`.
+#[derive(Resource, Clone, Debug)]
+pub struct SendSchedulerSettings {
+    /// Upload budget over all peers together. Up to a second's worth can be
+    /// saved up for bursts.
+    pub bytes_per_second: f32,
+    /// Messages for the same peer and channel are packed together up to this
+    /// size, anything bigger goes out on its own.
+    pub max_packet_size: usize,
+    /// Priority a waiting message gains per second, so low priority messages
+    /// still go out eventually when the budget is tight.
+    pub aging: f32,
+    /// Unreliable messages that waited longer than this, in seconds, are
+    /// dropped. Voice and positions are worthless by then anyway.
+    pub max_unreliable_wait: f32,
+    pub priorities: HashMap<MessageKind, f32>,
+}
+
+impl Default for SendSchedulerSettings {
+    fn default() -> Self {
+        Self {
+            bytes_per_second: 512_000.0,
+            max_packet_size: 1_200,
+            aging: 20.0,
+            max_unreliable_wait: 0.25,
+            priorities: HashMap::from([
+                (MessageKind::VoiceChat, 100.0),
+                // waiting would show up as round trip time
+                (MessageKind::Heartbeat, 90.0),
+                (MessageKind::PlayerPosition, 80.0),
+                (MessageKind::Authority, 60.0),
+                (MessageKind::SpawnProp, 50.0),
+                (MessageKind::DeleteProp, 50.0),
+                (MessageKind::Replication, 50.0),
+                (MessageKind::Rpc, 40.0),
+                (MessageKind::AvatarPart, 10.0),
+            ]),
+        }
+    }
+}
+
+impl SendSchedulerSettings {
+    pub fn priority(&self, kind: MessageKind) -> f32 {
+        self.priorities.get(&kind).copied().unwrap_or_default()
+    }
+
+    fn urgency(&self, waiting: &Outgoing) -> f32 {
+        self.priority(waiting.kind) + self.aging * waiting.waited
+    }
+}
+
+impl Message {
+    /// State messages about the same thing replace each other while they wait
+    /// to be sent, only the newest is worth anything.
+    fn coalesce_key(&self) -> Option<String> {
+        match self {
+            // only updates of the same components replace each other
+            Message::Replication(ReplicationMessage {
+                prop_uuid,
+                action: ReplicationAction::Update(components),
+                ..
+            }) => {
+                let names: Vec<&str> = components.iter().map(|c| c.name.as_str()).collect();
+                Some(format!("{}/{}", prop_uuid.0, names.join(",")))
+            }
+            Message::PlayerPosition(pp) => Some(pp.player_uuid.0.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Outgoing {
+    pub peer: PeerId,
+    pub channel: Channel,
+    pub kind: MessageKind,
+    key: Option<String>,
+    pub packet: Vec<u8>,
+    /// Seconds spent in the queue, a replaced message keeps the age of the
+    /// one it replaced.
+    waited: f32,
+}
+
+/// Messages waiting for their turn to go out. Reliable messages leave in the
+/// order they were queued for each peer, the receiver relies on that, only
+/// unreliable ones are reordered by priority and dropped when they get stale.
+#[derive(Default)]
+pub struct SendScheduler {
+    reliable: HashMap<PeerId, VecDeque<Outgoing>>,
+    unreliable: Vec<Outgoing>,
+    budget: f32,
+}
+
+impl SendScheduler {
+    pub fn enqueue(&mut self, peer: PeerId, channel: Channel, message: &Message, packet: Vec<u8>) {
+        let kind = message.kind();
+        let key = message.coalesce_key();
+        let same = |waiting: &Outgoing| waiting.kind == kind && key.is_some() && waiting.key == key;
+        let mut outgoing = Outgoing {
+            peer,
+            channel,
+            kind,
+            key: key.clone(),
+            packet,
+            waited: 0.0,
+        };
+        match channel {
+            Channel::Reliable => {
+                let queue = self.reliable.entry(peer).or_default();
+                // the newer state goes behind everything queued since the
+                // one it replaces, so nothing overtakes anything
+                if let Some(index) = queue.iter().position(same) {
+                    outgoing.waited = queue.remove(index).unwrap().waited;
+                }
+                queue.push_back(outgoing);
+            }
+            Channel::Unreliable => {
+                if let Some(waiting) = self
+                    .unreliable
+                    .iter_mut()
+                    .find(|waiting| waiting.peer == peer && same(waiting))
+                {
+                    waiting.packet = outgoing.packet;
+                    return;
+                }
+                self.unreliable.push(outgoing);
+            }
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Outgoing> {
+        self.reliable
+            .values()
+            .flatten()
+            .chain(self.unreliable.iter())
+    }
+
+    /// Bytes waiting to be sent of one type, for senders that would rather
+    /// hold back than pile more on.
+    pub fn backlog(&self, kind: MessageKind) -> usize {
+        self.iter()
+            .filter(|waiting| waiting.kind == kind)
+            .map(|waiting| waiting.packet.len())
+            .sum()
+    }
+
+    /// Takes whatever the budget allows this frame, most urgent first, and
+    /// forgets messages for peers that are gone. A peer's reliable queue is
+    /// as urgent as the most urgent message in it, whatever is in front of
+    /// that message has to go first.
+    pub fn take_due(
+        &mut self,
+        settings: &SendSchedulerSettings,
+        dt: f32,
+        connected: &[PeerId],
+    ) -> Vec<Outgoing> {
+        self.reliable
+            .retain(|peer, queue| connected.contains(peer) && !queue.is_empty());
+        self.unreliable
+            .retain(|waiting| connected.contains(&waiting.peer));
+        self.budget = (self.budget + settings.bytes_per_second * dt).min(settings.bytes_per_second);
+        for waiting in self.reliable.values_mut().flatten() {
+            waiting.waited += dt;
+        }
+        for waiting in self.unreliable.iter_mut() {
+            waiting.waited += dt;
+        }
+        self.unreliable
+            .retain(|waiting| waiting.waited <= settings.max_unreliable_wait);
+        // stable, so messages of the same type and age keep their order
+        self.unreliable
+            .sort_by(|a, b| settings.urgency(b).total_cmp(&settings.urgency(a)));
+        let mut unreliable = std::mem::take(&mut self.unreliable).into_iter().peekable();
+
+        let mut due = vec![];
+        while self.budget > 0.0 {
+            let reliable = self
+                .reliable
+                .iter()
+                .filter_map(|(peer, queue)| {
+                    let urgency = queue
+                        .iter()
+                        .map(|waiting| settings.urgency(waiting))
+                        .reduce(f32::max)?;
+                    Some((*peer, urgency))
+                })
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+            let next = unreliable.peek().map(|waiting| settings.urgency(waiting));
+            let outgoing = match (reliable, next) {
+                (None, None) => break,
+                (Some((peer, urgency)), next) if next.map_or(true, |next| urgency >= next) => {
+                    let queue = self.reliable.get_mut(&peer).unwrap();
+                    let outgoing = queue.pop_front().unwrap();
+                    if queue.is_empty() {
+                        self.reliable.remove(&peer);
+                    }
+                    outgoing
+                }
+                _ => unreliable.next().unwrap(),
+            };
+            self.budget -= outgoing.packet.len() as f32;
+            due.push(outgoing);
+        }
+        self.unreliable = unreliable.collect();
+        due
+    }
+}
+
+/// Groups messages for the same peer and channel into packets of at most
+/// `max_packet_size`, keeping their order.
+pub fn batch(due: Vec<Outgoing>, max_packet_size: usize) -> Vec<(PeerId, Channel, Vec<Outgoing>)> {
+    let mut batches: Vec<(PeerId, Channel, Vec<Outgoing>, usize)> = vec![];
+    for outgoing in due {
+        let len = outgoing.packet.len() + BATCH_LEN_PREFIX;
+        let last = batches
+            .iter_mut()
+            .rev()
+            .find(|(peer, channel, _, _)| *peer == outgoing.peer && *channel == outgoing.channel);
+        match last {
+            Some((_, _, batch, size)) if *size + len <= max_packet_size => {
+                *size += len;
+                batch.push(outgoing);
+            }
+            _ => batches.push((
+                outgoing.peer,
+                outgoing.channel,
+                vec![outgoing],
+                HEADER_LEN + len,
+            )),
+        }
+    }
+    batches
+        .into_iter()
+        .map(|(peer, channel, batch, _)| (peer, channel, batch))
+        .collect()
+}
+
+/// Sends what got queued this frame, runs after everything that sends.
+pub fn flush_outbox(
+    time: Res<Time>,
+    settings: Res<SendSchedulerSettings>,
+    mut socket: ResMut<NetworkSocket>,
+) {
+    socket.flush(&settings, time.delta_seconds());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::test_app::sample_messages;
+    use uuid::Uuid;
+
+    fn message(kind: MessageKind) -> Message {
+        sample_messages()
+            .into_iter()
+            .find(|message| message.kind() == kind)
+            .unwrap()
+    }
+
+    fn tight(bytes_per_second: f32) -> SendSchedulerSettings {
+        SendSchedulerSettings {
+            bytes_per_second,
+            ..default()
+        }
+    }
+
+    #[test]
+    fn reliable_messages_keep_their_order() {
+        let peer = PeerId(Uuid::from_u128(1));
+        let mut scheduler = SendScheduler::default();
+        // low priority first, then something the priorities would pull ahead
+        let kinds = [
+            MessageKind::AvatarPart,
+            MessageKind::Rpc,
+            MessageKind::Authority,
+            MessageKind::AvatarPart,
+            MessageKind::SpawnProp,
+        ];
+        for (i, kind) in kinds.iter().enumerate() {
+            scheduler.enqueue(peer, Channel::Reliable, &message(*kind), vec![i as u8; 100]);
+        }
+        // two messages a frame
+        let settings = tight(200.0 * 60.0);
+        let mut sent = vec![];
+        for _ in 0..10 {
+            sent.extend(scheduler.take_due(&settings, 1.0 / 60.0, &[peer]));
+        }
+        let order = sent
+            .iter()
+            .map(|outgoing| outgoing.packet[0])
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn urgent_reliable_messages_pull_their_queue_ahead() {
+        let (slow, urgent) = (PeerId(Uuid::from_u128(1)), PeerId(Uuid::from_u128(2)));
+        let mut scheduler = SendScheduler::default();
+        scheduler.enqueue(
+            slow,
+            Channel::Reliable,
+            &message(MessageKind::Rpc),
+            vec![0; 100],
+        );
+        scheduler.enqueue(
+            urgent,
+            Channel::Reliable,
+            &message(MessageKind::AvatarPart),
+            vec![1; 100],
+        );
+        scheduler.enqueue(
+            urgent,
+            Channel::Reliable,
+            &message(MessageKind::Authority),
+            vec![2; 100],
+        );
+        let settings = tight(100.0 * 60.0);
+        let mut sent = vec![];
+        for _ in 0..3 {
+            sent.extend(scheduler.take_due(&settings, 1.0 / 60.0, &[slow, urgent]));
+        }
+        let order = sent
+            .iter()
+            .map(|outgoing| outgoing.packet[0])
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn reliable_state_replaced_at_the_back() {
+        let peer = PeerId(Uuid::from_u128(1));
+        let mut scheduler = SendScheduler::default();
+        let update = sample_messages()
+            .into_iter()
+            .find(|message| {
+                matches!(
+                    message,
+                    Message::Replication(ReplicationMessage {
+                        action: ReplicationAction::Update(_),
+                        ..
+                    })
+                )
+            })
+            .unwrap();
+        scheduler.enqueue(peer, Channel::Reliable, &update, vec![0; 10]);
+        scheduler.enqueue(
+            peer,
+            Channel::Reliable,
+            &message(MessageKind::DeleteProp),
+            vec![1; 10],
+        );
+        scheduler.enqueue(peer, Channel::Reliable, &update, vec![2; 10]);
+        let due = scheduler.take_due(&tight(1_000_000.0), 1.0, &[peer]);
+        let order = due
+            .iter()
+            .map(|outgoing| outgoing.packet[0])
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![1, 2]);
+    }
+
+    #[test]
+    fn stale_unreliable_messages_are_dropped() {
+        let peer = PeerId(Uuid::from_u128(1));
+        let mut scheduler = SendScheduler::default();
+        // nothing fits, like a connection that can't keep up
+        let settings = tight(0.0);
+        for _ in 0..100 {
+            scheduler.enqueue(
+                peer,
+                Channel::Unreliable,
+                &message(MessageKind::VoiceChat),
+                vec![0; 100],
+            );
+            assert!(scheduler
+                .take_due(&settings, 1.0 / 60.0, &[peer])
+                .is_empty());
+        }
+        let max_frames = (settings.max_unreliable_wait * 60.0).ceil() as usize + 1;
+        assert!(scheduler.backlog(MessageKind::VoiceChat) <= max_frames * 100);
+
+        // the reliable ones wait however long it takes
+        scheduler.enqueue(
+            peer,
+            Channel::Reliable,
+            &message(MessageKind::Rpc),
+            vec![0; 100],
+        );
+        for _ in 0..100 {
+            scheduler.take_due(&settings, 1.0 / 60.0, &[peer]);
+        }
+        assert_eq!(scheduler.backlog(MessageKind::Rpc), 100);
+    }
+
+    #[test]
+    fn unreliable_messages_go_by_priority() {
+        let peer = PeerId(Uuid::from_u128(1));
+        let mut scheduler = SendScheduler::default();
+        let kinds = [MessageKind::PlayerPosition, MessageKind::VoiceChat];
+        for (i, kind) in kinds.iter().enumerate() {
+            scheduler.enqueue(
+                peer,
+                Channel::Unreliable,
+                &message(*kind),
+                vec![i as u8; 100],
+            );
+        }
+        let due = scheduler.take_due(&tight(60.0 * 100.0), 1.0 / 60.0, &[peer]);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].kind, MessageKind::VoiceChat);
+    }
+}
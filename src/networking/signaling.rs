@@ -0,0 +1,64 @@
+//! A matchbox signaling server we can run ourselves, for LAN parties and for
+//! testing without the public server.
+
+use bevy::prelude::*;
+use bevy_matchbox::matchbox_signaling::SignalingServer;
+use bevy_matchbox::prelude::MatchboxServer;
+use std::net::{Ipv4Addr, SocketAddr};
+
+pub const DEFAULT_SIGNALING_PORT: u16 = 3536;
+
+/// Starts a full mesh signaling server inside the app, every room on it
+/// behaves like the rooms on the public server. If the port can't be bound
+/// the error is logged and nothing is started, there is no
+/// `LocalSignalingServer` then.
+pub struct SignalingServerPlugin {
+    /// 0 picks a free port, see `LocalSignalingServer` for which one.
+    pub port: u16,
+}
+
+impl Default for SignalingServerPlugin {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_SIGNALING_PORT,
+        }
+    }
+}
+
+impl Plugin for SignalingServerPlugin {
+    fn build(&self, app: &mut App) {
+        let mut server =
+            SignalingServer::full_mesh_builder((Ipv4Addr::UNSPECIFIED, self.port)).build();
+        let addr = match server.bind() {
+            Ok(addr) => addr,
+            Err(err) => {
+                // the game works without it, just not on this machine's server
+                error!(
+                    "couldn't start the signaling server on port {}: {}",
+                    self.port, err
+                );
+                return;
+            }
+        };
+        info!("signaling server listening on {addr}");
+        app.insert_resource(LocalSignalingServer { addr });
+        app.insert_resource(MatchboxServer::from(server));
+    }
+}
+
+/// Where the in-process signaling server ended up listening.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct LocalSignalingServer {
+    addr: SocketAddr,
+}
+
+impl LocalSignalingServer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// What to use as `ConnectionSettings::signaling_url` on this machine.
+    pub fn url(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.addr.port())
+    }
+}
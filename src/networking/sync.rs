@@ -0,0 +1,227 @@
+use crate::networking::codec::MessageKind;
+use crate::networking::Authority;
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use std::collections::HashMap;
+
+/// How often state messages are sent, in messages per second per type.
+/// Types without a rate are sent whenever their sender has something new.
+#[derive(Resource, Clone, Debug)]
+pub struct NetworkSyncSettings {
+    pub rates: HashMap<MessageKind, f32>,
+}
+
+impl Default for NetworkSyncSettings {
+    fn default() -> Self {
+        Self {
+            rates: HashMap::from([
+                (MessageKind::PlayerPosition, 30.0),
+                (MessageKind::Replication, 20.0),
+            ]),
+        }
+    }
+}
+
+impl NetworkSyncSettings {
+    pub fn rate(&self, kind: MessageKind) -> Option<f32> {
+        self.rates.get(&kind).copied()
+    }
+
+    pub fn set_rate(&mut self, kind: MessageKind, rate: f32) {
+        self.rates.insert(kind, rate);
+    }
+}
+
+/// When an owned prop is worth sending. Props are sent when they moved or
+/// turned further than the thresholds since we last sent them, or their
+/// velocity changed by more than `velocity_threshold`. Sleeping props are
+/// only sent once as they fall asleep. Every prop goes out at least once per
+/// `keyframe_interval` regardless, so peers that missed something catch up.
+#[derive(Resource, Clone, Debug)]
+pub struct PropSyncSettings {
+    /// Meters.
+    pub position_threshold: f32,
+    /// Radians.
+    pub rotation_threshold: f32,
+    /// Meters per second.
+    pub velocity_threshold: f32,
+    /// Seconds.
+    pub keyframe_interval: f32,
+}
+
+impl Default for PropSyncSettings {
+    fn default() -> Self {
+        Self {
+            position_threshold: 0.005,
+            rotation_threshold: 0.01,
+            velocity_threshold: 0.05,
+            keyframe_interval: 2.0,
+        }
+    }
+}
+
+/// What we last sent for a prop we own.
+#[derive(Clone, Debug)]
+pub struct PropSyncState {
+    pub authority: Authority,
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub linear_velocity: Vec3,
+    pub sleeping: bool,
+    pub time: f32,
+}
+
+impl PropSyncState {
+    /// Keyframes go to every interested peer no matter how far away.
+    pub fn keyframe_due(&self, settings: &PropSyncSettings, current: &PropSyncState) -> bool {
+        current.time - self.time >= settings.keyframe_interval
+            || self.authority != current.authority
+    }
+
+    /// Keyframes, and the prop falling asleep since it is the last update
+    /// until it wakes up again, go out without waiting for the rate limit.
+    pub fn urgent(&self, settings: &PropSyncSettings, current: &PropSyncState) -> bool {
+        self.keyframe_due(settings, current) || (current.sleeping && !self.sleeping)
+    }
+
+    /// Whether `current` is different enough from this to be worth sending.
+    pub fn needs_send(&self, settings: &PropSyncSettings, current: &PropSyncState) -> bool {
+        if self.keyframe_due(settings, current) {
+            return true;
+        }
+        if current.sleeping {
+            return !self.sleeping;
+        }
+        self.position.distance(current.position) > settings.position_threshold
+            || self.rotation.angle_between(current.rotation) > settings.rotation_threshold
+            || self.linear_velocity.distance(current.linear_velocity) > settings.velocity_threshold
+    }
+}
+
+/// What each peer was last sent about a prop we own, peers that were culled
+/// or rate limited keep the older state so they get caught up later.
+#[derive(Component, Clone, Debug, Default)]
+pub struct PeerSyncStates(pub HashMap<PeerId, PropSyncState>);
+
+/// Run condition for a sender of `kind`. Skipped runs don't advance the
+/// sender's change ticks, so `Changed` filters still see everything that
+/// happened since it last actually sent.
+pub fn sync_due(
+    kind: MessageKind,
+) -> impl FnMut(Res<Time>, Res<NetworkSyncSettings>, Local<Option<f32>>) -> bool {
+    move |time: Res<Time>, settings: Res<NetworkSyncSettings>, mut last_sent: Local<Option<f32>>| {
+        let now = time.elapsed_seconds();
+        let due = match (settings.rate(kind), *last_sent) {
+            (Some(rate), Some(last_sent)) if rate > 0.0 => now - last_sent >= 1.0 / rate,
+            _ => true,
+        };
+        if due {
+            *last_sent = Some(now);
+        }
+        due
+    }
+}
+
+/// The newest sequence number we applied from a remote player, anything
+/// older than this arrived out of order and gets dropped.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct RemoteSequence(pub Option<u64>);
+
+impl RemoteSequence {
+    /// Records `sequence` and returns whether it is newer than what we had.
+    pub fn accept(&mut self, sequence: u64) -> bool {
+        if self.0.is_some_and(|newest| sequence <= newest) {
+            return false;
+        }
+        self.0 = Some(sequence);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::ownership::AuthorityPriority;
+    use crate::networking::PlayerUuid;
+
+    fn state(time: f32) -> PropSyncState {
+        PropSyncState {
+            authority: Authority::new(PlayerUuid("alice".to_string())),
+            position: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            linear_velocity: Vec3::ZERO,
+            sleeping: false,
+            time,
+        }
+    }
+
+    #[test]
+    fn only_real_changes_need_sending() {
+        let settings = PropSyncSettings::default();
+        let sent = state(0.0);
+        assert!(!sent.needs_send(&settings, &state(0.1)));
+
+        let nudged = PropSyncState {
+            position: Vec3::X * settings.position_threshold / 2.0,
+            ..state(0.1)
+        };
+        assert!(!sent.needs_send(&settings, &nudged));
+        let moved = PropSyncState {
+            position: Vec3::X * settings.position_threshold * 2.0,
+            ..state(0.1)
+        };
+        assert!(sent.needs_send(&settings, &moved));
+        let turned = PropSyncState {
+            rotation: Quat::from_rotation_y(settings.rotation_threshold * 2.0),
+            ..state(0.1)
+        };
+        assert!(sent.needs_send(&settings, &turned));
+        let pushed = PropSyncState {
+            linear_velocity: Vec3::Y * settings.velocity_threshold * 2.0,
+            ..state(0.1)
+        };
+        assert!(sent.needs_send(&settings, &pushed));
+    }
+
+    #[test]
+    fn sleeping_props_go_out_once() {
+        let settings = PropSyncSettings::default();
+        let asleep = |time| PropSyncState {
+            sleeping: true,
+            position: Vec3::X,
+            ..state(time)
+        };
+        let awake = state(0.0);
+        assert!(awake.needs_send(&settings, &asleep(0.1)));
+        assert!(awake.urgent(&settings, &asleep(0.1)));
+        // settling in its sleep doesn't count as moving
+        let sent = asleep(0.1);
+        let settled = PropSyncState {
+            position: Vec3::X * 1.1,
+            ..asleep(0.2)
+        };
+        assert!(!sent.needs_send(&settings, &settled));
+        // waking up is a change like any other
+        assert!(sent.needs_send(&settings, &state(0.2)));
+        assert!(!sent.urgent(&settings, &state(0.2)));
+    }
+
+    #[test]
+    fn keyframes_come_with_time_or_a_new_holder() {
+        let settings = PropSyncSettings::default();
+        let sent = state(0.0);
+        assert!(!sent.keyframe_due(&settings, &state(settings.keyframe_interval - 0.1)));
+        assert!(sent.keyframe_due(&settings, &state(settings.keyframe_interval)));
+        assert!(sent.needs_send(&settings, &state(settings.keyframe_interval)));
+
+        let taken = PropSyncState {
+            authority: sent
+                .authority
+                .next(PlayerUuid("bob".to_string()), AuthorityPriority::Normal)
+                .unwrap(),
+            ..state(0.1)
+        };
+        assert!(sent.keyframe_due(&settings, &taken));
+        assert!(sent.urgent(&settings, &taken));
+    }
+}
@@ -0,0 +1,321 @@
+//! Headless `App`s talking to each other over a `LoopbackHub`, for tests.
+
+use crate::file_sharing::{AvatarPart, AvatarPartEnum, UploadId};
+use crate::networking::codec::{self, MessageKind, WireFormat, PROTOCOL_VERSION};
+use crate::networking::conditions::{ConditionedTransport, LinkConditions, NetworkConditions};
+use crate::networking::errors::BlockedPeers;
+use crate::networking::handshake::{Capabilities, Handshake, Hello};
+use crate::networking::heartbeat::Heartbeat;
+use crate::networking::message::{DeleteProp, PlayerPosition, SpawnProp};
+use crate::networking::ownership::{AuthorityMessage, AuthorityPriority};
+use crate::networking::recording::SessionRecorder;
+use crate::networking::replication::{ComponentData, ReplicationAction, ReplicationMessage};
+use crate::networking::rpc::{RequestId, Rpc, RpcError, RpcMessage};
+use crate::networking::transport::{
+    Channel, LoopbackHub, LoopbackTransport, NetworkSocket, NetworkTransport,
+};
+use crate::networking::world_state::GetWorldSnapshot;
+use crate::networking::{
+    Authority, DisplayName, ExternalPlayer, Message, NetworkingPlugin, PlayerUuid, PropUuid,
+};
+use crate::props::{PropDescription, PropKinds, PropSpec};
+use crate::voice_chat::VoiceMsg;
+use avian3d::prelude::{LinearVelocity, Position, Rotation};
+use bevy::gltf::Gltf;
+use bevy::prelude::*;
+use bevy::state::app::StatesPlugin;
+use bevy::time::TimeUpdateStrategy;
+use bevy_matchbox::prelude::PeerId;
+use bevy_vrm::loader::Vrm;
+use std::time::Duration;
+use unavi_player::LocalPlayer;
+use uuid::Uuid;
+
+/// Seconds every `App::update` moves time forward by, so tests don't depend
+/// on how fast they run.
+pub const FRAME: f32 = 1.0 / 60.0;
+
+/// Just the networking side of the game, no window, audio or physics.
+pub fn headless_app(hub: &LoopbackHub) -> App {
+    let mut app = networking_app();
+    insert_socket(&mut app, hub.connect());
+    app
+}
+
+/// Like `headless_app`, with every packet going through `link` on the way in
+/// and on the way out.
+pub fn conditioned_app(hub: &LoopbackHub, link: LinkConditions, seed: u64) -> App {
+    let mut app = networking_app();
+    let conditions = app.world().resource::<NetworkConditions>().clone();
+    conditions.set(link);
+    insert_socket(
+        &mut app,
+        ConditionedTransport::with_seed(hub.connect(), conditions, seed),
+    );
+    app
+}
+
+fn networking_app() -> App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, AssetPlugin::default(), StatesPlugin))
+        // external players and props load these, nothing ever reads them
+        .init_asset::<Gltf>()
+        .init_asset::<AnimationClip>()
+        .init_asset::<Vrm>()
+        .init_asset::<Mesh>()
+        .init_asset::<StandardMaterial>()
+        .init_resource::<PropKinds>()
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+            FRAME,
+        )))
+        .add_plugins(NetworkingPlugin);
+    app
+}
+
+fn insert_socket(app: &mut App, transport: impl NetworkTransport) {
+    let socket = NetworkSocket::new(transport)
+        .with_recorder(app.world().resource::<SessionRecorder>().clone())
+        .with_blocked(app.world().resource::<BlockedPeers>().clone());
+    app.insert_resource(socket);
+}
+
+pub fn spawn_local_player(app: &mut App, name: &str) -> PlayerUuid {
+    let uuid = PlayerUuid(Uuid::new_v4().to_string());
+    app.world_mut().spawn((
+        LocalPlayer::default(),
+        uuid.clone(),
+        DisplayName(name.to_string()),
+        Position::default(),
+        Rotation::default(),
+        LinearVelocity::default(),
+    ));
+    uuid
+}
+
+/// Steps every app once per frame, in order.
+pub fn update_all(apps: &mut [App], frames: usize) {
+    for _ in 0..frames {
+        for app in apps.iter_mut() {
+            app.update();
+        }
+    }
+}
+
+/// The players an app sees of other peers.
+pub fn external_players(app: &mut App) -> Vec<PlayerUuid> {
+    let mut players = app
+        .world_mut()
+        .query_filtered::<&PlayerUuid, With<ExternalPlayer>>()
+        .iter(app.world())
+        .cloned()
+        .collect::<Vec<_>>();
+    players.sort();
+    players
+}
+
+pub fn peer_id(app: &mut App) -> PeerId {
+    app.world_mut()
+        .resource_mut::<NetworkSocket>()
+        .id()
+        .expect("loopback transports know their id right away")
+}
+
+/// A peer without an app, that says hello to `app` as `player_uuid` and
+/// from then on only sends whatever packets the test hands it.
+pub fn raw_peer(hub: &LoopbackHub, app: &mut App, player_uuid: &str) -> LoopbackTransport {
+    let mut peer = hub.connect();
+    say_hello(&mut peer, app, player_uuid);
+    peer
+}
+
+/// Completes our half of the handshake with `app`, a raw peer has to do this
+/// with every app it wants to talk to.
+pub fn say_hello(peer: &mut LoopbackTransport, app: &mut App, player_uuid: &str) {
+    let target = peer_id(app);
+    update_all(std::slice::from_mut(app), 2);
+    peer.update_peers();
+    send_raw(
+        peer,
+        target,
+        Channel::Reliable,
+        &Message::Handshake(Handshake::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            player_uuid: PlayerUuid(player_uuid.to_string()),
+            display_name: player_uuid.to_string(),
+            capabilities: Capabilities::local(),
+        })),
+    );
+    update_all(std::slice::from_mut(app), 2);
+}
+
+pub fn send_raw(peer: &mut LoopbackTransport, to: PeerId, channel: Channel, message: &Message) {
+    peer.send(
+        channel,
+        codec::encode(message, WireFormat::Binary).into(),
+        to,
+    );
+}
+
+/// Everything a raw peer got on `channel` since the last call, batches split
+/// up and anything undecodable left out.
+pub fn received(peer: &mut LoopbackTransport, channel: Channel) -> Vec<(PeerId, Message)> {
+    let mut messages = Vec::new();
+    for (from, packet) in peer.receive(channel) {
+        let packets = match codec::peek_kind(&packet) {
+            Some(MessageKind::Batch) => codec::split_batch(&packet).unwrap_or_default(),
+            _ => vec![&packet[..]],
+        };
+        messages.extend(
+            packets
+                .into_iter()
+                .filter_map(|packet| codec::decode(packet).ok())
+                .map(|message| (from, message)),
+        );
+    }
+    messages
+}
+
+/// xorshift64*, for input that is random but the same on every run.
+pub struct TestRng(u64);
+
+impl TestRng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck on zero
+        Self(seed.max(1))
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `0..n`, close enough for tests.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+/// At least one of every variant, with values that don't survive a
+/// sloppy encoding.
+pub fn sample_messages() -> Vec<Message> {
+    let player = PlayerUuid("player".to_string());
+    let prop_uuid = PropUuid("prop".to_string());
+    let authority = Authority::new(player.clone());
+    let stolen = authority
+        .next(PlayerUuid("thief".to_string()), AuthorityPriority::High)
+        .unwrap();
+    let id = RequestId::new(7);
+    let upload = UploadId::new(u64::MAX);
+    let replication = |action| {
+        Message::Replication(ReplicationMessage {
+            sender: player.clone(),
+            prop_uuid: prop_uuid.clone(),
+            authority: authority.clone(),
+            sent_at: 12.125,
+            action,
+        })
+    };
+    let components = vec![ComponentData {
+        name: "position".to_string(),
+        data: vec![0, 1, 2, 255],
+    }];
+    vec![
+        Message::SpawnProp(SpawnProp {
+            authority: authority.clone(),
+            prop_uuid: prop_uuid.clone(),
+            prop: PropSpec::Custom(PropDescription::cube(0.25, [0.1, 0.2, 0.3])),
+            position: Position::new(Vec3::new(1.0, -2.5, 1e-7)),
+            rotation: Rotation(Quat::from_rotation_y(0.3)),
+        }),
+        Message::SpawnProp(SpawnProp {
+            authority: Authority::unowned(),
+            prop_uuid: prop_uuid.clone(),
+            prop: PropSpec::Kind("red_cube".to_string()),
+            position: Position::default(),
+            rotation: Rotation::default(),
+        }),
+        replication(ReplicationAction::Spawn(components.clone())),
+        replication(ReplicationAction::Update(components)),
+        replication(ReplicationAction::Despawn),
+        Message::DeleteProp(DeleteProp {
+            authority: stolen.clone(),
+            prop_uuid: prop_uuid.clone(),
+        }),
+        Message::PlayerPosition(PlayerPosition {
+            player_uuid: player.clone(),
+            peer_id: PeerId(Uuid::from_u128(42)),
+            sequence: u64::MAX,
+            position: Position::new(Vec3::new(3.0, 4.0, 5.0)),
+            rotation: Rotation::default(),
+            linear_velocity: LinearVelocity(Vec3::new(-1.0, 0.0, 0.5)),
+            sent_at: 1.0 / 3.0,
+        }),
+        Message::VoiceChat(VoiceMsg::new(vec![0xfc, 0xff, 0xfe], player.clone(), 2, 0.5)),
+        Message::AvatarPart(AvatarPartEnum::Len(player.clone(), upload, 123_456)),
+        Message::AvatarPart(AvatarPartEnum::AvatarPart(AvatarPart::new(
+            player.clone(),
+            upload,
+            "avatar.vrm".to_string(),
+            vec![9; 300],
+        ))),
+        Message::AvatarPart(AvatarPartEnum::Done(player.clone(), upload)),
+        Message::Handshake(Handshake::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            player_uuid: player.clone(),
+            display_name: "Zoë \"quoted\"".to_string(),
+            capabilities: Capabilities::local(),
+        })),
+        Message::Handshake(Handshake::Welcome {
+            features: vec!["a".to_string()],
+        }),
+        Message::Handshake(Handshake::Reject {
+            reason: "no".to_string(),
+        }),
+        Message::Rpc(RpcMessage::Request {
+            id,
+            name: GetWorldSnapshot::NAME.to_string(),
+            data: vec![1, 2, 3],
+        }),
+        Message::Rpc(RpcMessage::Response {
+            id,
+            result: Ok(vec![4, 5]),
+        }),
+        Message::Rpc(RpcMessage::Response {
+            id,
+            result: Err(RpcError::Unsupported("something".to_string())),
+        }),
+        Message::Authority(AuthorityMessage::Request {
+            prop_uuid: prop_uuid.clone(),
+            requester: player.clone(),
+            priority: AuthorityPriority::Normal,
+        }),
+        Message::Authority(AuthorityMessage::Grant {
+            prop_uuid: prop_uuid.clone(),
+            from: authority.clone(),
+            to: stolen.clone(),
+        }),
+        Message::Authority(AuthorityMessage::Release {
+            prop_uuid: prop_uuid.clone(),
+            authority: authority.clone(),
+        }),
+        Message::Authority(AuthorityMessage::Steal {
+            prop_uuid,
+            authority: stolen,
+        }),
+        Message::Heartbeat(Heartbeat::Ping {
+            sequence: 1,
+            sent_at: 100.75,
+        }),
+        Message::Heartbeat(Heartbeat::Pong {
+            sequence: 1,
+            sent_at: 100.75,
+            replied_at: 1234.5678,
+        }),
+    ]
+}
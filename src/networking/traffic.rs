@@ -0,0 +1,171 @@
+use crate::networking::codec::MessageKind;
+use crate::networking::transport::NetworkSocket;
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrafficCount {
+    pub messages: u64,
+    /// Whole packets, header included.
+    pub bytes: u64,
+}
+
+impl TrafficCount {
+    fn record(&mut self, bytes: usize) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+/// Everything that went through a `NetworkSocket` since it was made, per
+/// message type. A message sent to three peers counts three times.
+#[derive(Clone, Debug, Default)]
+pub struct Traffic {
+    sent: HashMap<MessageKind, TrafficCount>,
+    received: HashMap<MessageKind, TrafficCount>,
+}
+
+impl Traffic {
+    pub fn record_sent(&mut self, kind: MessageKind, bytes: usize) {
+        self.sent.entry(kind).or_default().record(bytes);
+    }
+
+    pub fn record_received(&mut self, kind: MessageKind, bytes: usize) {
+        self.received.entry(kind).or_default().record(bytes);
+    }
+
+    pub fn sent(&self, kind: MessageKind) -> TrafficCount {
+        self.sent.get(&kind).copied().unwrap_or_default()
+    }
+
+    pub fn received(&self, kind: MessageKind) -> TrafficCount {
+        self.received.get(&kind).copied().unwrap_or_default()
+    }
+}
+
+/// Bytes per second one way and the other.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TrafficRate {
+    pub up: f32,
+    pub down: f32,
+}
+
+/// Bytes per second per message type between two readings of the same
+/// socket's `Traffic`, `elapsed` seconds apart. Types that saw nothing are
+/// left out. `None` when the counts went down, which only happens when the
+/// socket was swapped for a new one in between that started from zero.
+pub fn traffic_rates(
+    previous: &Traffic,
+    current: &Traffic,
+    elapsed: f32,
+) -> Option<HashMap<MessageKind, TrafficRate>> {
+    let mut rates = HashMap::new();
+    for kind in MessageKind::ALL {
+        let sent = current
+            .sent(kind)
+            .bytes
+            .checked_sub(previous.sent(kind).bytes)?;
+        let received = current
+            .received(kind)
+            .bytes
+            .checked_sub(previous.received(kind).bytes)?;
+        if sent == 0 && received == 0 {
+            continue;
+        }
+        let rate = TrafficRate {
+            up: sent as f32 / elapsed,
+            down: received as f32 / elapsed,
+        };
+        rates.insert(kind, rate);
+    }
+    Some(rates)
+}
+
+/// How often `log_traffic` reports, in seconds. Zero turns it off.
+#[derive(Resource, Clone, Debug)]
+pub struct TrafficLogSettings {
+    pub interval: f32,
+}
+
+impl Default for TrafficLogSettings {
+    fn default() -> Self {
+        Self { interval: 10.0 }
+    }
+}
+
+/// Logs bytes per second per message type over the last interval.
+pub fn log_traffic(
+    time: Res<Time>,
+    settings: Res<TrafficLogSettings>,
+    socket: Res<NetworkSocket>,
+    mut last: Local<Option<(f32, Traffic)>>,
+) {
+    if settings.interval <= 0.0 {
+        return;
+    }
+    let now = time.elapsed_seconds();
+    let Some((since, previous)) = last.as_ref() else {
+        *last = Some((now, socket.traffic().clone()));
+        return;
+    };
+    let elapsed = now - since;
+    if elapsed < settings.interval {
+        return;
+    }
+    let traffic = socket.traffic();
+    // nothing to report across a socket swap, the next interval counts from
+    // the new one
+    for (kind, rate) in traffic_rates(previous, traffic, elapsed).unwrap_or_default() {
+        debug!("{kind:?}: {:.0} B/s up, {:.0} B/s down", rate.up, rate.down);
+    }
+    *last = Some((now, traffic.clone()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_a_socket_swap() {
+        let mut before = Traffic::default();
+        let mut traffic = before.clone();
+        traffic.record_sent(MessageKind::PlayerPosition, 1000);
+        traffic.record_received(MessageKind::VoiceChat, 500);
+        let rates = traffic_rates(&before, &traffic, 0.5).unwrap();
+        assert_eq!(rates.len(), 2);
+        assert_eq!(
+            rates[&MessageKind::PlayerPosition],
+            TrafficRate {
+                up: 2000.0,
+                down: 0.0
+            }
+        );
+        assert_eq!(
+            rates[&MessageKind::VoiceChat],
+            TrafficRate {
+                up: 0.0,
+                down: 1000.0
+            }
+        );
+
+        // the new one starts counting from zero
+        before = traffic;
+        let mut traffic = Traffic::default();
+        traffic.record_sent(MessageKind::PlayerPosition, 10);
+        assert_eq!(traffic_rates(&before, &traffic, 0.5), None);
+
+        before = traffic.clone();
+        traffic.record_sent(MessageKind::PlayerPosition, 100);
+        let rates = traffic_rates(&before, &traffic, 0.5).unwrap();
+        assert_eq!(
+            rates,
+            HashMap::from([(
+                MessageKind::PlayerPosition,
+                TrafficRate {
+                    up: 200.0,
+                    down: 0.0
+                }
+            )])
+        );
+    }
+}
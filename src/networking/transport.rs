@@ -0,0 +1,424 @@
+use crate::networking::codec::{self, WireFormat};
+use crate::networking::errors::BlockedPeers;
+use crate::networking::recording::{RecordedEvent, SessionRecorder};
+use crate::networking::scheduler::{self, SendScheduler, SendSchedulerSettings};
+use crate::networking::traffic::Traffic;
+use bevy::prelude::{Event, EventWriter, ResMut, Resource};
+use bevy_matchbox::matchbox_socket::{Packet, PeerState};
+use bevy_matchbox::prelude::{MultipleChannels, PeerId};
+use bevy_matchbox::MatchboxSocket;
+use futures::channel::mpsc::SendError;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// The two channels every transport has to provide, matching the order they
+/// are added to the matchbox socket in `start_socket`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Channel {
+    Reliable,
+    Unreliable,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 2] = [Channel::Reliable, Channel::Unreliable];
+
+    pub const fn index(self) -> usize {
+        match self {
+            Channel::Reliable => 0,
+            Channel::Unreliable => 1,
+        }
+    }
+}
+
+/// Anything that can move packets between peers. `SocketSendMessage` is
+/// implemented on top of this so the rest of the game never has to know
+/// whether it is talking to a real WebRTC socket or something in-process.
+pub trait NetworkTransport: Send + Sync + 'static {
+    fn id(&mut self) -> Option<PeerId>;
+    /// Returns the peers that connected or disconnected since the last call.
+    fn update_peers(&mut self) -> Vec<(PeerId, PeerState)>;
+    fn connected_peers(&self) -> Vec<PeerId>;
+    fn send(&mut self, channel: Channel, packet: Packet, peer: PeerId);
+    fn try_send(&mut self, channel: Channel, packet: Packet, peer: PeerId)
+        -> Result<(), SendError>;
+    fn receive(&mut self, channel: Channel) -> Vec<(PeerId, Packet)>;
+    /// The connection to the signaling server is gone for good, nothing more
+    /// is coming through this transport.
+    fn is_closed(&self) -> bool;
+}
+
+impl NetworkTransport for MatchboxSocket<MultipleChannels> {
+    fn id(&mut self) -> Option<PeerId> {
+        (**self).id()
+    }
+
+    fn update_peers(&mut self) -> Vec<(PeerId, PeerState)> {
+        (**self).update_peers()
+    }
+
+    fn connected_peers(&self) -> Vec<PeerId> {
+        (**self).connected_peers().collect()
+    }
+
+    fn send(&mut self, channel: Channel, packet: Packet, peer: PeerId) {
+        self.channel_mut(channel.index()).send(packet, peer);
+    }
+
+    fn try_send(
+        &mut self,
+        channel: Channel,
+        packet: Packet,
+        peer: PeerId,
+    ) -> Result<(), SendError> {
+        self.channel_mut(channel.index()).try_send(packet, peer)
+    }
+
+    fn receive(&mut self, channel: Channel) -> Vec<(PeerId, Packet)> {
+        self.channel_mut(channel.index()).receive()
+    }
+
+    fn is_closed(&self) -> bool {
+        (**self).any_channel_closed()
+    }
+}
+
+/// Stands in for the socket between losing the connection and the next
+/// attempt. It has no id and no peers so senders leave it alone, and drops
+/// anything sent anyway.
+pub struct OfflineTransport;
+
+impl NetworkTransport for OfflineTransport {
+    fn id(&mut self) -> Option<PeerId> {
+        None
+    }
+
+    fn update_peers(&mut self) -> Vec<(PeerId, PeerState)> {
+        vec![]
+    }
+
+    fn connected_peers(&self) -> Vec<PeerId> {
+        vec![]
+    }
+
+    fn send(&mut self, _channel: Channel, _packet: Packet, _peer: PeerId) {}
+
+    fn try_send(
+        &mut self,
+        _channel: Channel,
+        _packet: Packet,
+        _peer: PeerId,
+    ) -> Result<(), SendError> {
+        Ok(())
+    }
+
+    fn receive(&mut self, _channel: Channel) -> Vec<(PeerId, Packet)> {
+        vec![]
+    }
+
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+/// The socket resource every networked system talks to.
+#[derive(Resource)]
+pub struct NetworkSocket {
+    transport: Box<dyn NetworkTransport>,
+    format: WireFormat,
+    blocked: BlockedPeers,
+    traffic: Traffic,
+    scheduler: SendScheduler,
+    recorder: SessionRecorder,
+}
+
+impl NetworkSocket {
+    pub fn new(transport: impl NetworkTransport) -> Self {
+        Self {
+            transport: Box::new(transport),
+            format: WireFormat::default(),
+            blocked: BlockedPeers::default(),
+            traffic: Traffic::default(),
+            scheduler: SendScheduler::default(),
+            recorder: SessionRecorder::default(),
+        }
+    }
+
+    /// Carries on over another transport. The wire format, the recorder and
+    /// the blocked peers stay, whatever was queued for the old one is dropped.
+    pub fn replace_transport(&mut self, transport: impl NetworkTransport) {
+        *self = Self::new(transport)
+            .with_format(self.format)
+            .with_recorder(self.recorder.clone())
+            .with_blocked(self.blocked.clone());
+    }
+
+    pub fn with_blocked(mut self, blocked: BlockedPeers) -> Self {
+        self.blocked = blocked;
+        self
+    }
+
+    pub fn is_blocked(&self, peer: PeerId) -> bool {
+        self.blocked.contains(peer)
+    }
+
+    /// Everyone the transport is connected to but blocked peers, who we
+    /// treat as gone.
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        self.transport
+            .connected_peers()
+            .into_iter()
+            .filter(|peer| !self.blocked.contains(*peer))
+            .collect()
+    }
+
+    pub fn with_recorder(mut self, recorder: SessionRecorder) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    pub fn recorder(&self) -> &SessionRecorder {
+        &self.recorder
+    }
+
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// The format outgoing messages are encoded with, incoming messages are
+    /// decoded with whatever format their header says.
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    pub fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
+
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    pub(crate) fn traffic_mut(&mut self) -> &mut Traffic {
+        &mut self.traffic
+    }
+
+    pub fn scheduler(&self) -> &SendScheduler {
+        &self.scheduler
+    }
+
+    pub(crate) fn scheduler_mut(&mut self) -> &mut SendScheduler {
+        &mut self.scheduler
+    }
+
+    /// Sends what the budget allows of everything queued through
+    /// `SocketSendMessage`.
+    pub fn flush(&mut self, settings: &SendSchedulerSettings, dt: f32) {
+        let connected = self.connected_peers();
+        let due = self.scheduler.take_due(settings, dt, &connected);
+        for (peer, channel, batch) in scheduler::batch(due, settings.max_packet_size) {
+            for outgoing in batch.iter() {
+                self.traffic.record_sent(outgoing.kind, outgoing.packet.len());
+            }
+            let packet = match <[_; 1]>::try_from(batch) {
+                Ok([single]) => single.packet,
+                Err(batch) => codec::encode_batch(batch.iter().map(|o| o.packet.as_slice())),
+            };
+            self.transport.send(channel, packet.into(), peer);
+        }
+    }
+}
+
+impl Deref for NetworkSocket {
+    type Target = dyn NetworkTransport;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.transport
+    }
+}
+
+impl DerefMut for NetworkSocket {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut *self.transport
+    }
+}
+
+#[derive(Event, Clone, Copy, Debug)]
+pub struct PeerConnected(pub PeerId);
+
+#[derive(Event, Clone, Copy, Debug)]
+pub struct PeerDisconnected(pub PeerId);
+
+/// The only place `update_peers` should be called from, otherwise peer changes
+/// get eaten by whichever system happened to run first.
+pub fn update_peers(
+    mut socket: ResMut<NetworkSocket>,
+    mut connected: EventWriter<PeerConnected>,
+    mut disconnected: EventWriter<PeerDisconnected>,
+) {
+    for (peer, state) in socket.update_peers() {
+        match state {
+            PeerState::Connected => {
+                socket.recorder().record(peer, RecordedEvent::Connected);
+                connected.send(PeerConnected(peer));
+            }
+            PeerState::Disconnected => {
+                socket.recorder().record(peer, RecordedEvent::Disconnected);
+                disconnected.send(PeerDisconnected(peer));
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct LoopbackPeer {
+    inbox: [VecDeque<(PeerId, Packet)>; 2],
+    peer_changes: Vec<(PeerId, PeerState)>,
+}
+
+/// An in-process stand-in for the signaling server, every transport handed out
+/// by [`LoopbackHub::connect`] is fully meshed with every other one. Used to run
+/// several `App`s against each other without touching the network.
+#[derive(Clone, Default)]
+pub struct LoopbackHub(Arc<Mutex<HashMap<PeerId, LoopbackPeer>>>);
+
+impl LoopbackHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn connect(&self) -> LoopbackTransport {
+        let id = PeerId(Uuid::new_v4());
+        let mut peers = self.0.lock().unwrap();
+        let mut new_peer = LoopbackPeer::default();
+        for (other_id, other) in peers.iter_mut() {
+            other.peer_changes.push((id, PeerState::Connected));
+            new_peer.peer_changes.push((*other_id, PeerState::Connected));
+        }
+        peers.insert(id, new_peer);
+        LoopbackTransport {
+            id,
+            hub: self.clone(),
+            connected: HashSet::new(),
+        }
+    }
+
+    /// Drops a peer from the hub, the remaining peers see it as disconnected.
+    pub fn disconnect(&self, id: PeerId) {
+        let mut peers = self.0.lock().unwrap();
+        if peers.remove(&id).is_none() {
+            return;
+        }
+        for other in peers.values_mut() {
+            other.peer_changes.push((id, PeerState::Disconnected));
+        }
+    }
+}
+
+pub struct LoopbackTransport {
+    id: PeerId,
+    hub: LoopbackHub,
+    connected: HashSet<PeerId>,
+}
+
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        self.hub.disconnect(self.id);
+    }
+}
+
+impl NetworkTransport for LoopbackTransport {
+    fn id(&mut self) -> Option<PeerId> {
+        Some(self.id)
+    }
+
+    fn update_peers(&mut self) -> Vec<(PeerId, PeerState)> {
+        let mut peers = self.hub.0.lock().unwrap();
+        let Some(this) = peers.get_mut(&self.id) else {
+            return vec![];
+        };
+        let changes = std::mem::take(&mut this.peer_changes);
+        for (peer, state) in changes.iter() {
+            match state {
+                PeerState::Connected => self.connected.insert(*peer),
+                PeerState::Disconnected => self.connected.remove(peer),
+            };
+        }
+        changes
+    }
+
+    fn connected_peers(&self) -> Vec<PeerId> {
+        self.connected.iter().copied().collect()
+    }
+
+    fn send(&mut self, channel: Channel, packet: Packet, peer: PeerId) {
+        let mut peers = self.hub.0.lock().unwrap();
+        if let Some(other) = peers.get_mut(&peer) {
+            other.inbox[channel.index()].push_back((self.id, packet));
+        }
+    }
+
+    fn try_send(
+        &mut self,
+        channel: Channel,
+        packet: Packet,
+        peer: PeerId,
+    ) -> Result<(), SendError> {
+        self.send(channel, packet, peer);
+        Ok(())
+    }
+
+    fn receive(&mut self, channel: Channel) -> Vec<(PeerId, Packet)> {
+        let mut peers = self.hub.0.lock().unwrap();
+        match peers.get_mut(&self.id) {
+            Some(this) => this.inbox[channel.index()].drain(..).collect(),
+            None => vec![],
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        !self.hub.0.lock().unwrap().contains_key(&self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::test_app::{
+        external_players, headless_app, spawn_local_player, update_all,
+    };
+
+    #[test]
+    fn loopback_apps_see_each_other() {
+        let hub = LoopbackHub::new();
+        let mut apps = [headless_app(&hub), headless_app(&hub), headless_app(&hub)];
+        let uuids = ["a", "b", "c"]
+            .into_iter()
+            .zip(apps.iter_mut())
+            .map(|(name, app)| spawn_local_player(app, name))
+            .collect::<Vec<_>>();
+        update_all(&mut apps, 10);
+        for (i, app) in apps.iter_mut().enumerate() {
+            let mut others = uuids.clone();
+            others.remove(i);
+            others.sort();
+            assert_eq!(external_players(app), others);
+        }
+    }
+
+    #[test]
+    fn dropped_transport_disconnects() {
+        let hub = LoopbackHub::new();
+        let mut a = hub.connect();
+        let b = hub.connect();
+        a.update_peers();
+        assert_eq!(a.connected_peers(), vec![b.id]);
+        a.send(Channel::Reliable, vec![1, 2, 3].into(), b.id);
+        drop(b);
+        assert_eq!(a.update_peers().len(), 1);
+        assert!(a.connected_peers().is_empty());
+    }
+}
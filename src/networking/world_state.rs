@@ -0,0 +1,539 @@
+use crate::networking::clock::NetworkTime;
+use crate::networking::connection::ConnectionEvent;
+use crate::networking::index::PropIndex;
+use crate::networking::interpolation::{InterpolationSettings, Snapshot, SnapshotBuffer};
+use crate::networking::ownership::{apply_claim, AuthorityChanged};
+use crate::networking::registry::PeerRegistry;
+use crate::networking::rpc::{RequestId, Rpc, RpcRequest, RpcResult, Rpcs};
+use crate::networking::{Authority, PropUuid};
+use crate::props::{PropDescription, PropSpawner};
+use avian3d::prelude::{AngularVelocity, LinearVelocity, Position, Rotation};
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Props per page of a `WorldSnapshot`, keeps single packets well under what
+/// a data channel will take.
+const PROPS_PER_SNAPSHOT: usize = 64;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PropState {
+    pub prop_uuid: PropUuid,
+    pub authority: Authority,
+    pub description: PropDescription,
+    pub position: Position,
+    pub rotation: Rotation,
+    pub linear_velocity: LinearVelocity,
+    pub angular_velocity: AngularVelocity,
+}
+
+/// Asks a peer for every networked prop it knows about, a page of
+/// `PROPS_PER_SNAPSHOT` at a time in `PropUuid` order. Each page starts after
+/// the last prop of the one before, so props spawned or deleted in between
+/// don't shift the rest into the wrong page.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct GetWorldSnapshot {
+    pub after: Option<PropUuid>,
+}
+
+impl Rpc for GetWorldSnapshot {
+    type Response = WorldSnapshot;
+    const NAME: &'static str = "get_world_snapshot";
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct WorldSnapshot {
+    pub props: Vec<PropState>,
+    /// `NetworkTime` the states are from.
+    pub sent_at: f64,
+    pub last_page: bool,
+}
+
+/// Whether we caught up with the world the other peers are in. After
+/// connecting we ask the peer with the lowest player uuid, and the next
+/// lowest if they can't tell us.
+#[derive(Resource, Clone, Debug, Default, PartialEq)]
+pub enum WorldSync {
+    #[default]
+    Unsynced,
+    Requesting {
+        peer: PeerId,
+        id: RequestId,
+    },
+    Synced,
+}
+
+pub fn request_world_snapshot(
+    mut rpcs: Rpcs,
+    mut sync: ResMut<WorldSync>,
+    registry: Res<PeerRegistry>,
+    mut connection: EventReader<ConnectionEvent>,
+    mut results: EventReader<RpcResult<GetWorldSnapshot>>,
+    mut failed: Local<HashSet<PeerId>>,
+) {
+    // a new socket is a new session, the world may have moved on since
+    if connection
+        .read()
+        .any(|event| matches!(event, ConnectionEvent::Connecting { .. }))
+    {
+        *sync = WorldSync::Unsynced;
+        failed.clear();
+    }
+    for RpcResult { peer, id, result } in results.read() {
+        let WorldSync::Requesting {
+            peer: asked,
+            id: request,
+        } = *sync
+        else {
+            continue;
+        };
+        if asked != *peer || request != *id {
+            continue;
+        }
+        *sync = match result {
+            // an empty page that isn't the last would have us ask for the
+            // first one all over again
+            Ok(snapshot) if snapshot.last_page || snapshot.props.is_empty() => {
+                info!("caught up with the world from {}", peer);
+                WorldSync::Synced
+            }
+            Ok(snapshot) => {
+                let after = snapshot.props.last().map(|prop| prop.prop_uuid.clone());
+                WorldSync::Requesting {
+                    peer: *peer,
+                    id: rpcs.request(*peer, &GetWorldSnapshot { after }),
+                }
+            }
+            Err(err) => {
+                warn!("couldn't get the world from {}: {}", peer, err);
+                failed.insert(*peer);
+                WorldSync::Unsynced
+            }
+        };
+    }
+    if *sync != WorldSync::Unsynced {
+        return;
+    }
+    // the same peer the others would pick, so who answers new peers doesn't
+    // depend on whose handshake happened to go through first
+    let Some(peer) = registry
+        .iter()
+        .filter(|(peer, _)| !failed.contains(*peer))
+        .min_by_key(|(_, uuid)| *uuid)
+        .map(|(peer, _)| *peer)
+    else {
+        return;
+    };
+    *sync = WorldSync::Requesting {
+        peer,
+        id: rpcs.request(peer, &GetWorldSnapshot { after: None }),
+    };
+}
+
+pub fn answer_world_snapshot_requests(
+    mut rpcs: Rpcs,
+    network_time: Res<NetworkTime>,
+    mut requests: EventReader<RpcRequest<GetWorldSnapshot>>,
+    props: Query<(
+        &PropUuid,
+        &Authority,
+        &PropDescription,
+        &Position,
+        &Rotation,
+        &LinearVelocity,
+        &AngularVelocity,
+    )>,
+) {
+    for RpcRequest { peer, id, request } in requests.read() {
+        let mut states = props
+            .iter()
+            .filter(|(prop_uuid, ..)| {
+                request
+                    .after
+                    .as_ref()
+                    .map_or(true, |after| *prop_uuid > after)
+            })
+            .map(
+                |(prop_uuid, authority, description, position, rotation, linear, angular)| {
+                    PropState {
+                        prop_uuid: prop_uuid.clone(),
+                        authority: authority.clone(),
+                        description: description.clone(),
+                        position: *position,
+                        rotation: *rotation,
+                        linear_velocity: *linear,
+                        angular_velocity: *angular,
+                    }
+                },
+            )
+            .collect::<Vec<_>>();
+        // sorted so the last prop of a page says where the next one starts
+        states.sort_by(|a, b| a.prop_uuid.cmp(&b.prop_uuid));
+        if request.after.is_none() {
+            info!("sending {} props to new peer {}", states.len(), peer);
+        }
+        let last_page = states.len() <= PROPS_PER_SNAPSHOT;
+        states.truncate(PROPS_PER_SNAPSHOT);
+        rpcs.respond::<GetWorldSnapshot>(
+            *peer,
+            *id,
+            Ok(WorldSnapshot {
+                props: states,
+                sent_at: network_time.now(),
+                last_page,
+            }),
+        );
+    }
+}
+
+/// Applies the answers to what `request_world_snapshot` asked, which has to
+/// run after this since it moves on to the next page as soon as one arrives.
+pub fn handle_world_snapshot(
+    mut commands: Commands,
+    mut spawner: PropSpawner,
+    sync: Res<WorldSync>,
+    time: Res<Time>,
+    network_time: Res<NetworkTime>,
+    settings: Res<InterpolationSettings>,
+    prop_index: Res<PropIndex>,
+    mut results: EventReader<RpcResult<GetWorldSnapshot>>,
+    mut changed: EventWriter<AuthorityChanged>,
+    mut existing: Query<(&mut SnapshotBuffer, &mut Authority)>,
+) {
+    for RpcResult { peer, id, result } in results.read() {
+        // answers to a request we already gave up on, or that someone made up
+        let WorldSync::Requesting {
+            peer: asked,
+            id: request,
+        } = *sync
+        else {
+            continue;
+        };
+        if asked != *peer || request != *id {
+            continue;
+        }
+        let Ok(snapshot) = result else {
+            continue;
+        };
+        let sent_at = network_time.local_time_of(snapshot.sent_at, time.elapsed_seconds_f64());
+        for prop in snapshot.props.iter() {
+            let state = Snapshot {
+                time: sent_at,
+                position: prop.position.0,
+                rotation: prop.rotation.0,
+                linear_velocity: prop.linear_velocity.0,
+                angular_velocity: prop.angular_velocity.0,
+            };
+            match prop_index.get(&prop.prop_uuid) {
+                // props every peer spawns on its own, like the one in the scene
+                Some(entity) => {
+                    let Ok((mut buffer, mut authority)) = existing.get_mut(entity) else {
+                        continue;
+                    };
+                    if !authority.within_reach(&prop.authority) {
+                        warn!(
+                            "ignoring snapshot of prop {} at counter {}, we are at {}",
+                            prop.prop_uuid.0, prop.authority.counter, authority.counter
+                        );
+                        continue;
+                    }
+                    apply_claim(
+                        &mut commands,
+                        &mut changed,
+                        entity,
+                        &prop.prop_uuid,
+                        &mut authority,
+                        &prop.authority,
+                    );
+                    if *authority != prop.authority {
+                        continue;
+                    }
+                    buffer.push(state, settings.buffer_len);
+                }
+                None => {
+                    if let Err(err) = spawner.kinds().check(&prop.description) {
+                        warn!("can't spawn prop {}: {}", prop.prop_uuid.0, err);
+                        continue;
+                    }
+                    let entity = spawner.spawn(
+                        &prop.description,
+                        prop.prop_uuid.clone(),
+                        prop.authority.clone(),
+                        Transform::from_translation(prop.position.0).with_rotation(prop.rotation.0),
+                    );
+                    let mut buffer = SnapshotBuffer::default();
+                    buffer.push(state, settings.buffer_len);
+                    commands.entity(entity).insert((
+                        buffer,
+                        prop.linear_velocity,
+                        prop.angular_velocity,
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::codec::PROTOCOL_VERSION;
+    use crate::networking::handshake::{Capabilities, Handshake, Hello};
+    use crate::networking::rpc::{RpcError, RpcMessage};
+    use crate::networking::test_app::{
+        headless_app, peer_id, received, send_raw, spawn_local_player, update_all,
+    };
+    use crate::networking::transport::{Channel, LoopbackHub, LoopbackTransport};
+    use crate::networking::{Message, PlayerUuid};
+
+    /// The snapshot requests `peer` got since the last call.
+    fn snapshot_requests(peer: &mut LoopbackTransport) -> Vec<RequestId> {
+        received(peer, Channel::Reliable)
+            .into_iter()
+            .filter_map(|(_, message)| match message {
+                Message::Rpc(RpcMessage::Request { id, .. }) => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn prop_state(prop: &str, authority: Authority) -> PropState {
+        PropState {
+            prop_uuid: PropUuid(prop.to_string()),
+            authority,
+            description: PropDescription::cube(0.25, [1.0, 0.0, 0.0]),
+            position: Position::default(),
+            rotation: Rotation::default(),
+            linear_velocity: LinearVelocity::default(),
+            angular_velocity: AngularVelocity::default(),
+        }
+    }
+
+    fn answer(peer: &mut LoopbackTransport, to: PeerId, id: RequestId, props: Vec<PropState>) {
+        let snapshot = WorldSnapshot {
+            props,
+            sent_at: 0.0,
+            last_page: true,
+        };
+        let result = Ok(bincode::serialize(&snapshot).unwrap());
+        send_raw(
+            peer,
+            to,
+            Channel::Reliable,
+            &Message::Rpc(RpcMessage::Response { id, result }),
+        );
+    }
+
+    #[test]
+    fn late_joiners_get_every_prop() {
+        // more than fits on one page
+        const PROPS: usize = PROPS_PER_SNAPSHOT + 6;
+        let hub = LoopbackHub::new();
+        let mut host = headless_app(&hub);
+        let alice = spawn_local_player(&mut host, "alice");
+        for i in 0..PROPS {
+            host.world_mut().spawn((
+                PropUuid(format!("prop-{i:03}")),
+                Authority::new(alice.clone()),
+                PropDescription::cube(0.25, [1.0, 0.0, 0.0]),
+                Position::new(Vec3::new(i as f32, 1.0, 0.0)),
+                Rotation::default(),
+                LinearVelocity::default(),
+                AngularVelocity::default(),
+            ));
+        }
+        update_all(std::slice::from_mut(&mut host), 10);
+
+        let mut apps = vec![host, headless_app(&hub)];
+        spawn_local_player(&mut apps[1], "bob");
+        update_all(&mut apps, 30);
+
+        let joiner = apps[1].world_mut();
+        assert_eq!(*joiner.resource::<WorldSync>(), WorldSync::Synced);
+        assert_eq!(joiner.resource::<PropIndex>().len(), PROPS);
+        for i in 0..PROPS {
+            let entity = joiner
+                .resource::<PropIndex>()
+                .get(&PropUuid(format!("prop-{i:03}")))
+                .unwrap();
+            assert_eq!(joiner.get::<Authority>(entity), Some(&Authority::new(alice.clone())));
+            assert_eq!(
+                joiner.get::<Transform>(entity).unwrap().translation,
+                Vec3::new(i as f32, 1.0, 0.0)
+            );
+        }
+    }
+
+    #[test]
+    fn lowest_uuid_answers_and_nobody_else() {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        spawn_local_player(&mut app, "carol");
+        let target = peer_id(&mut app);
+        update_all(std::slice::from_mut(&mut app), 2);
+        // both handshakes land in the same frame, so the pick can't depend
+        // on which one came first
+        let mut second = hub.connect();
+        let mut first = hub.connect();
+        for (peer, uuid) in [(&mut second, "b-second"), (&mut first, "a-first")] {
+            peer.update_peers();
+            send_raw(
+                peer,
+                target,
+                Channel::Reliable,
+                &Message::Handshake(Handshake::Hello(Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    player_uuid: PlayerUuid(uuid.to_string()),
+                    display_name: uuid.to_string(),
+                    capabilities: Capabilities::local(),
+                })),
+            );
+        }
+        update_all(std::slice::from_mut(&mut app), 3);
+        let asked = snapshot_requests(&mut first);
+        assert_eq!(asked.len(), 1);
+        assert!(snapshot_requests(&mut second).is_empty());
+
+        // an answer from someone we didn't ask
+        let forged = vec![prop_state("forged", Authority::unowned())];
+        answer(&mut second, target, asked[0], forged);
+        update_all(std::slice::from_mut(&mut app), 3);
+        assert_eq!(app.world().resource::<PropIndex>().len(), 0);
+
+        // the next lowest gets asked once the lowest can't tell us
+        send_raw(
+            &mut first,
+            target,
+            Channel::Reliable,
+            &Message::Rpc(RpcMessage::Response {
+                id: asked[0],
+                result: Err(RpcError::Failed("busy".to_string())),
+            }),
+        );
+        update_all(std::slice::from_mut(&mut app), 3);
+        let asked = snapshot_requests(&mut second);
+        assert_eq!(asked.len(), 1);
+        let real = vec![prop_state("real", Authority::unowned())];
+        answer(&mut second, target, asked[0], real);
+        update_all(std::slice::from_mut(&mut app), 3);
+
+        let world = app.world();
+        assert_eq!(*world.resource::<WorldSync>(), WorldSync::Synced);
+        assert_eq!(world.resource::<PropIndex>().len(), 1);
+        assert!(world
+            .resource::<PropIndex>()
+            .get(&PropUuid("real".to_string()))
+            .is_some());
+    }
+
+    /// Asks `app` for the page after `after` as a raw peer.
+    fn ask_page(
+        peer: &mut LoopbackTransport,
+        app: &mut App,
+        id: u64,
+        after: Option<&str>,
+    ) -> WorldSnapshot {
+        let request = GetWorldSnapshot {
+            after: after.map(|prop| PropUuid(prop.to_string())),
+        };
+        send_raw(
+            peer,
+            peer_id(app),
+            Channel::Reliable,
+            &Message::Rpc(RpcMessage::Request {
+                id: RequestId::new(id),
+                name: GetWorldSnapshot::NAME.to_string(),
+                data: bincode::serialize(&request).unwrap(),
+            }),
+        );
+        update_all(std::slice::from_mut(app), 3);
+        received(peer, Channel::Reliable)
+            .into_iter()
+            .find_map(|(_, message)| match message {
+                Message::Rpc(RpcMessage::Response { id: answered, result })
+                    if answered == RequestId::new(id) =>
+                {
+                    Some(bincode::deserialize(&result.unwrap()).unwrap())
+                }
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn props_deleted_between_pages_dont_shift_the_rest() {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        let alice = spawn_local_player(&mut app, "alice");
+        let props = (0..PROPS_PER_SNAPSHOT + 6)
+            .map(|i| {
+                app.world_mut()
+                    .spawn((
+                        PropUuid(format!("prop-{i:03}")),
+                        Authority::new(alice.clone()),
+                        PropDescription::cube(0.25, [1.0, 0.0, 0.0]),
+                        Position::default(),
+                        Rotation::default(),
+                        LinearVelocity::default(),
+                        AngularVelocity::default(),
+                    ))
+                    .id()
+            })
+            .collect::<Vec<_>>();
+        let mut peer = raw_peer(&hub, &mut app, "zed");
+        received(&mut peer, Channel::Reliable);
+
+        let first = ask_page(&mut peer, &mut app, 1, None);
+        assert_eq!(first.props.len(), PROPS_PER_SNAPSHOT);
+        assert!(!first.last_page);
+        // the first few go away before the next page is asked for
+        for prop in &props[..3] {
+            app.world_mut().despawn(*prop);
+        }
+        let after = &first.props.last().unwrap().prop_uuid.0;
+        let second = ask_page(&mut peer, &mut app, 2, Some(after));
+        assert!(second.last_page);
+        let uuids = second
+            .props
+            .iter()
+            .map(|prop| prop.prop_uuid.0.clone())
+            .collect::<Vec<_>>();
+        let expected = (PROPS_PER_SNAPSHOT..PROPS_PER_SNAPSHOT + 6)
+            .map(|i| format!("prop-{i:03}"))
+            .collect::<Vec<_>>();
+        assert_eq!(uuids, expected);
+    }
+
+    #[test]
+    fn snapshots_cant_jump_claims_ahead() {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        spawn_local_player(&mut app, "carol");
+        // a prop every peer spawns on its own
+        let scene = app
+            .world_mut()
+            .spawn((
+                PropUuid("scene".to_string()),
+                Authority::unowned(),
+                SnapshotBuffer::default(),
+            ))
+            .id();
+        let mut peer = raw_peer(&hub, &mut app, "a-first");
+        update_all(std::slice::from_mut(&mut app), 2);
+        let asked = snapshot_requests(&mut peer);
+        assert_eq!(asked.len(), 1);
+
+        let far_ahead = Authority {
+            counter: 1_000,
+            ..Authority::new(PlayerUuid("a-first".to_string()))
+        };
+        let target = peer_id(&mut app);
+        answer(&mut peer, target, asked[0], vec![prop_state("scene", far_ahead)]);
+        update_all(std::slice::from_mut(&mut app), 3);
+
+        let world = app.world();
+        assert_eq!(*world.resource::<WorldSync>(), WorldSync::Synced);
+        assert_eq!(world.get::<Authority>(scene), Some(&Authority::unowned()));
+    }
+}
@@ -0,0 +1,428 @@
+use crate::networking::index::PropIndex;
+use crate::networking::interpolation::SnapshotBuffer;
+use crate::networking::message::SpawnProp;
+use crate::networking::{Authority, PropUuid};
+use avian3d::prelude::*;
+use avian_interpolation3d::{InterpolateTransformFields, InterpolationMode};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
+use std::fmt::{Display, Formatter};
+use unavi_player::layers::LAYER_PROPS;
+
+pub struct PropsPlugin;
+
+impl Plugin for PropsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PropKinds>();
+        app.add_systems(Update, handle_spawn_prop);
+    }
+}
+
+/// Dimensions are full lengths, not half extents.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PropShape {
+    Cuboid { size: Vec3 },
+    Sphere { radius: f32 },
+    Capsule { radius: f32, length: f32 },
+    Cylinder { radius: f32, height: f32 },
+}
+
+impl PropShape {
+    pub fn volume(&self) -> f32 {
+        match *self {
+            PropShape::Cuboid { size } => size.x * size.y * size.z,
+            PropShape::Sphere { radius } => 4.0 / 3.0 * PI * radius.powi(3),
+            PropShape::Capsule { radius, length } => {
+                PI * radius.powi(2) * length + 4.0 / 3.0 * PI * radius.powi(3)
+            }
+            PropShape::Cylinder { radius, height } => PI * radius.powi(2) * height,
+        }
+    }
+
+    /// Every dimension and the volume they make up are positive and finite,
+    /// anything else can't be turned into a collider or a mass.
+    pub fn is_valid(&self) -> bool {
+        let dimensions = match *self {
+            PropShape::Cuboid { size } => vec![size.x, size.y, size.z],
+            PropShape::Sphere { radius } => vec![radius],
+            PropShape::Capsule { radius, length } => vec![radius, length],
+            PropShape::Cylinder { radius, height } => vec![radius, height],
+        };
+        dimensions
+            .into_iter()
+            .chain([self.volume()])
+            .all(|value| value.is_finite() && value > 0.0)
+    }
+
+    fn mesh(&self) -> Mesh {
+        match *self {
+            PropShape::Cuboid { size } => Cuboid::from_size(size).into(),
+            PropShape::Sphere { radius } => Sphere::new(radius).into(),
+            PropShape::Capsule { radius, length } => Capsule3d::new(radius, length).into(),
+            PropShape::Cylinder { radius, height } => Cylinder::new(radius, height).into(),
+        }
+    }
+
+    fn collider(&self) -> Collider {
+        match *self {
+            PropShape::Cuboid { size } => Collider::from(Cuboid::from_size(size)),
+            PropShape::Sphere { radius } => Collider::from(Sphere::new(radius)),
+            PropShape::Capsule { radius, length } => Collider::from(Capsule3d::new(radius, length)),
+            PropShape::Cylinder { radius, height } => Collider::from(Cylinder::new(radius, height)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PropMaterial {
+    /// Linear rgb.
+    pub color: [f32; 3],
+    pub metallic: f32,
+    pub perceptual_roughness: f32,
+}
+
+impl PropMaterial {
+    pub fn color(color: [f32; 3]) -> Self {
+        Self {
+            color,
+            metallic: 0.0,
+            perceptual_roughness: 0.5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PropVisual {
+    /// A mesh of the collider shape.
+    Primitive(PropMaterial),
+    /// A scene from a gltf every peer has, like the ones in `assets`.
+    Gltf { path: String },
+}
+
+/// Everything a peer needs to build a prop it has never seen, kept on the
+/// prop so we can describe it to peers who join later.
+#[derive(Component, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PropDescription {
+    pub shape: PropShape,
+    pub visual: PropVisual,
+    /// Kilograms, if unset the mass comes from the collider at density 1.
+    pub mass: Option<f32>,
+}
+
+impl PropDescription {
+    pub fn cube(size: f32, color: [f32; 3]) -> Self {
+        Self {
+            shape: PropShape::Cuboid {
+                size: Vec3::splat(size),
+            },
+            visual: PropVisual::Primitive(PropMaterial::color(color)),
+            mass: None,
+        }
+    }
+}
+
+/// What a `SpawnProp` asks for, either a kind every peer has registered in
+/// `PropKinds` or a full description.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PropSpec {
+    Kind(String),
+    Custom(PropDescription),
+}
+
+/// Why a prop another peer described can't be spawned.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InvalidProp {
+    UnknownKind(String),
+    Shape(PropShape),
+    Mass(f32),
+    /// Not a gltf in `PropKinds::allow_gltf`.
+    Gltf(String),
+}
+
+impl Display for InvalidProp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidProp::UnknownKind(name) => write!(f, "unknown kind {name}"),
+            InvalidProp::Shape(shape) => write!(f, "degenerate shape {shape:?}"),
+            InvalidProp::Mass(mass) => write!(f, "mass {mass}"),
+            InvalidProp::Gltf(path) => write!(f, "gltf {path} is not allowed"),
+        }
+    }
+}
+
+pub const GREEN_CUBE: &str = "green_cube";
+pub const RED_CUBE: &str = "red_cube";
+
+/// Props that can be spawned by name, and the gltfs a `PropSpec::Custom` may
+/// load. Registering a kind allows its gltf.
+#[derive(Resource)]
+pub struct PropKinds {
+    kinds: HashMap<String, PropDescription>,
+    gltfs: HashSet<String>,
+}
+
+impl Default for PropKinds {
+    fn default() -> Self {
+        let mut kinds = Self {
+            kinds: HashMap::new(),
+            gltfs: HashSet::new(),
+        };
+        kinds.register(GREEN_CUBE, PropDescription::cube(0.5, [0.0, 1.0, 0.0]));
+        kinds.register(RED_CUBE, PropDescription::cube(0.5, [1.0, 0.0, 0.0]));
+        kinds
+    }
+}
+
+impl PropKinds {
+    pub fn register(&mut self, name: impl Into<String>, description: PropDescription) {
+        if let PropVisual::Gltf { path } = &description.visual {
+            self.gltfs.insert(path.clone());
+        }
+        self.kinds.insert(name.into(), description);
+    }
+
+    pub fn allow_gltf(&mut self, path: impl Into<String>) {
+        self.gltfs.insert(path.into());
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PropDescription> {
+        self.kinds.get(name)
+    }
+
+    /// The kind a description was registered as, for naming entities.
+    pub fn name_of(&self, description: &PropDescription) -> Option<&str> {
+        self.kinds
+            .iter()
+            .filter(|(_, kind)| *kind == description)
+            .map(|(name, _)| name.as_str())
+            .min()
+    }
+
+    /// Whether a description that came from another peer is safe to spawn.
+    pub fn check(&self, description: &PropDescription) -> Result<(), InvalidProp> {
+        if !description.shape.is_valid() {
+            return Err(InvalidProp::Shape(description.shape));
+        }
+        if let Some(mass) = description.mass {
+            if !mass.is_finite() || mass <= 0.0 {
+                return Err(InvalidProp::Mass(mass));
+            }
+        }
+        if let PropVisual::Gltf { path } = &description.visual {
+            if !self.gltfs.contains(path) {
+                return Err(InvalidProp::Gltf(path.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn resolve(&self, spec: &PropSpec) -> Result<PropDescription, InvalidProp> {
+        match spec {
+            PropSpec::Kind(name) => self
+                .get(name)
+                .cloned()
+                .ok_or_else(|| InvalidProp::UnknownKind(name.clone())),
+            PropSpec::Custom(description) => {
+                self.check(description)?;
+                Ok(description.clone())
+            }
+        }
+    }
+}
+
+#[derive(SystemParam)]
+pub struct PropSpawner<'w, 's> {
+    commands: Commands<'w, 's>,
+    meshes: ResMut<'w, Assets<Mesh>>,
+    materials: ResMut<'w, Assets<StandardMaterial>>,
+    asset_server: Res<'w, AssetServer>,
+    kinds: Res<'w, PropKinds>,
+}
+
+impl PropSpawner<'_, '_> {
+    pub fn kinds(&self) -> &PropKinds {
+        &self.kinds
+    }
+
+    pub fn spawn(
+        &mut self,
+        description: &PropDescription,
+        prop_uuid: PropUuid,
+        authority: Authority,
+        transform: Transform,
+    ) -> Entity {
+        let collider = description.shape.collider();
+        let density = match description.mass {
+            Some(mass) => mass / description.shape.volume(),
+            None => 1.0,
+        };
+        let mut prop = self.commands.spawn((
+            Name::new(
+                self.kinds
+                    .name_of(description)
+                    .unwrap_or("custom_prop")
+                    .to_string(),
+            ),
+            SpatialBundle::from_transform(transform),
+            description.clone(),
+            authority,
+            prop_uuid,
+            SnapshotBuffer::default(),
+            InterpolateTransformFields {
+                translation: InterpolationMode::Linear,
+                rotation: InterpolationMode::Linear,
+            },
+            // All `RigidBody::Dynamic` entities are able to be picked up.
+            RigidBody::Dynamic,
+            collider,
+            ColliderDensity(density),
+            CollisionLayers::new(LAYER_PROPS, LayerMask::ALL),
+        ));
+        match &description.visual {
+            PropVisual::Primitive(material) => {
+                let [r, g, b] = material.color;
+                prop.insert((
+                    self.meshes.add(description.shape.mesh()),
+                    self.materials.add(StandardMaterial {
+                        base_color: Color::linear_rgb(r, g, b),
+                        metallic: material.metallic,
+                        perceptual_roughness: material.perceptual_roughness,
+                        ..default()
+                    }),
+                ));
+            }
+            PropVisual::Gltf { path } => {
+                let scene = self
+                    .asset_server
+                    .load(GltfAssetLabel::Scene(0).from_asset(path.clone()));
+                prop.with_children(|parent| {
+                    parent.spawn(SceneBundle { scene, ..default() });
+                });
+            }
+        }
+        prop.id()
+    }
+}
+
+fn handle_spawn_prop(
+    mut spawner: PropSpawner,
+    prop_index: Res<PropIndex>,
+    mut event_reader: EventReader<SpawnProp>,
+) {
+    // the index only catches up once the commands are applied
+    let mut spawned = HashSet::new();
+    for spawn in event_reader.read() {
+        if prop_index.get(&spawn.prop_uuid).is_some() || !spawned.insert(&spawn.prop_uuid) {
+            continue;
+        }
+        let description = match spawner.kinds().resolve(&spawn.prop) {
+            Ok(description) => description,
+            Err(err) => {
+                warn!("can't spawn prop {}: {}", spawn.prop_uuid.0, err);
+                continue;
+            }
+        };
+        spawner.spawn(
+            &description,
+            spawn.prop_uuid.clone(),
+            spawn.authority.clone(),
+            Transform::from_translation(spawn.position.0).with_rotation(spawn.rotation.0),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::test_app::headless_app;
+    use crate::networking::transport::LoopbackHub;
+    use crate::networking::PlayerUuid;
+
+    fn custom(shape: PropShape, mass: Option<f32>) -> PropSpec {
+        PropSpec::Custom(PropDescription {
+            shape,
+            visual: PropVisual::Primitive(PropMaterial::color([1.0, 1.0, 1.0])),
+            mass,
+        })
+    }
+
+    #[test]
+    fn remote_descriptions_are_checked() {
+        let kinds = PropKinds::default();
+        let sphere = |radius| PropShape::Sphere { radius };
+        assert!(kinds.resolve(&custom(sphere(0.5), Some(2.0))).is_ok());
+        for radius in [0.0, -1.0, f32::NAN, f32::INFINITY, 1e20] {
+            assert!(
+                matches!(
+                    kinds.resolve(&custom(sphere(radius), None)),
+                    Err(InvalidProp::Shape(_))
+                ),
+                "radius {radius}"
+            );
+        }
+        let flat = PropShape::Cuboid {
+            size: Vec3::new(1.0, 0.0, 1.0),
+        };
+        assert!(kinds.resolve(&custom(flat, None)).is_err());
+        for mass in [0.0, -3.0, f32::NAN, f32::INFINITY] {
+            assert!(kinds.resolve(&custom(sphere(0.5), Some(mass))).is_err());
+        }
+        assert_eq!(
+            kinds.resolve(&PropSpec::Kind("blue_cube".to_string())),
+            Err(InvalidProp::UnknownKind("blue_cube".to_string()))
+        );
+    }
+
+    #[test]
+    fn only_allowed_gltfs_load() {
+        let mut kinds = PropKinds::default();
+        let gltf = |path: &str| {
+            PropSpec::Custom(PropDescription {
+                shape: PropShape::Sphere { radius: 0.5 },
+                visual: PropVisual::Gltf {
+                    path: path.to_string(),
+                },
+                mass: None,
+            })
+        };
+        assert!(kinds.resolve(&gltf("../../etc/passwd")).is_err());
+        assert!(kinds.resolve(&gltf("https://example.com/huge.glb")).is_err());
+
+        kinds.allow_gltf("ball.glb");
+        assert!(kinds.resolve(&gltf("ball.glb")).is_ok());
+        let PropSpec::Custom(chair) = gltf("chair.glb") else {
+            unreachable!()
+        };
+        kinds.register("chair", chair);
+        assert!(kinds.resolve(&gltf("chair.glb")).is_ok());
+    }
+
+    #[test]
+    fn spawns_each_uuid_once_named_after_its_kind() {
+        let hub = LoopbackHub::new();
+        let mut app = headless_app(&hub);
+        app.add_plugins(PropsPlugin);
+        let spawn = SpawnProp {
+            authority: Authority::new(PlayerUuid("alice".to_string())),
+            prop_uuid: PropUuid("prop".to_string()),
+            prop: PropSpec::Kind(RED_CUBE.to_string()),
+            position: Position::default(),
+            rotation: Rotation::default(),
+        };
+        app.world_mut().send_event(spawn.clone());
+        app.world_mut().send_event(spawn.clone());
+        app.update();
+        app.world_mut().send_event(spawn);
+        app.update();
+
+        let mut props = app
+            .world_mut()
+            .query_filtered::<&Name, With<PropUuid>>();
+        let names = props.iter(app.world()).collect::<Vec<_>>();
+        assert_eq!(names.len(), 1);
+        assert_eq!(names[0].as_str(), RED_CUBE);
+    }
+}
@@ -1,19 +1,29 @@
 use crate::custom_audio::microphone::MicrophoneAudio;
 use crate::custom_audio::spatial_audio::SpatialAudioSink;
+use crate::networking::clock::NetworkTime;
+use crate::networking::errors::{NetworkError, NetworkErrorKind};
+use crate::networking::index::PlayerIndex;
+use crate::networking::interest::{Interest, PeerPositions};
 use crate::networking::message::DeleteProp;
+use crate::networking::registry::PeerRegistry;
+use crate::networking::transport::NetworkSocket;
 use crate::networking::{
     Authority, ExternalPlayer, Message, PlayerUuid, PropUuid, SocketSendMessage,
 };
+use avian3d::prelude::Position;
 use bevy::app::App;
 use bevy::prelude::{
-    Event, EventReader, EventWriter, Local, NonSendMut, Query, ResMut, Resource, Update, With,
+    Event, EventReader, EventWriter, Local, NonSendMut, Query, Res, ResMut, Resource, Update,
+    With,
 };
-use bevy_matchbox::prelude::MultipleChannels;
-use bevy_matchbox::MatchboxSocket;
 use opus::{Application, Channels, Decoder, Encoder};
+use rodio::buffer::SamplesBuffer;
 use serde::{Deserialize, Serialize};
 use unavi_player::LocalPlayer;
 
+/// Frames older than this, in seconds, are dropped rather than played late.
+const MAX_VOICE_AGE: f64 = 0.5;
+
 pub struct VoiceChatPlugin;
 
 impl bevy::prelude::Plugin for VoiceChatPlugin {
@@ -32,12 +42,7 @@ impl bevy::prelude::Plugin for VoiceChatPlugin {
                     .expect("unable to create microphone audio compressing encoder"),
             ));
         }
-        app.insert_non_send_resource(MicrophoneDecoder {
-            channels_1_decoder: Decoder::new(48_000, Channels::Mono)
-                .expect("unable to create microphone audio compressing decoder"),
-            channels_2_decoder: Decoder::new(48_000, Channels::Stereo)
-                .expect("unable to create microphone audio compressing decoder"),
-        });
+        app.insert_non_send_resource(MicrophoneDecoder::new());
         app.add_systems(Update, send_voice_msg);
         app.add_systems(Update, rec_voice_msg);
         app.add_systems(Update, bad_jitter_buffer);
@@ -52,6 +57,24 @@ pub struct MicrophoneDecoder {
     pub channels_1_decoder: Decoder,
     pub channels_2_decoder: Decoder,
 }
+
+impl MicrophoneDecoder {
+    pub fn new() -> Self {
+        Self {
+            channels_1_decoder: Decoder::new(48_000, Channels::Mono)
+                .expect("unable to create microphone audio compressing decoder"),
+            channels_2_decoder: Decoder::new(48_000, Channels::Stereo)
+                .expect("unable to create microphone audio compressing decoder"),
+        }
+    }
+}
+
+impl Default for MicrophoneDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 unsafe impl Sync for MicrophoneEncoder {}
 unsafe impl Sync for MicrophoneDecoder {}
 
@@ -60,14 +83,34 @@ pub struct VoiceMsg {
     data: Vec<u8>,
     uuid: PlayerUuid,
     pub channels: u16,
+    /// `NetworkTime` the frame was recorded at.
+    sent_at: f64,
+}
+
+impl VoiceMsg {
+    pub fn new(data: Vec<u8>, uuid: PlayerUuid, channels: u16, sent_at: f64) -> Self {
+        Self {
+            data,
+            uuid,
+            channels,
+            sent_at,
+        }
+    }
+
+    pub fn uuid(&self) -> &PlayerUuid {
+        &self.uuid
+    }
 }
 
 fn send_voice_msg(
     mut encoder: NonSendMut<MicrophoneEncoder>,
     microphone: ResMut<MicrophoneAudio>,
-    mut voice_chat_socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut voice_chat_socket: ResMut<NetworkSocket>,
     mut local_size: Local<Vec<f32>>,
-    local_player: Query<&PlayerUuid, With<LocalPlayer>>,
+    network_time: Res<NetworkTime>,
+    interest: Res<Interest>,
+    peer_positions: Res<PeerPositions>,
+    local_player: Query<(&PlayerUuid, &Position), With<LocalPlayer>>,
 ) {
     #[allow(unused_mut)]
     let mut channels = 1;
@@ -82,7 +125,7 @@ fn send_voice_msg(
         return;
     }
 
-    let player_uuid = match local_player.get_single() {
+    let (player_uuid, position) = match local_player.get_single() {
         Ok(val) => val,
         Err(err) => {
             println!("there is not exactly one local player: {}", err);
@@ -90,71 +133,85 @@ fn send_voice_msg(
         }
     };
 
+    // nobody out of earshot gets our voice at all
+    let listeners = voice_chat_socket
+        .connected_peers()
+        .into_iter()
+        .filter(|peer| interest.audible(peer_positions.get(*peer), position.0))
+        .collect::<Vec<_>>();
+
     while local_size.len() > 2880 * channels {
-        voice_chat_socket.send_msg_all_unreliable(&Message::VoiceChat(VoiceMsg {
-            data: encoder
+        let message = Message::VoiceChat(VoiceMsg::new(
+            encoder
                 .0
                 .encode_vec_float(
                     local_size.drain(0..(2880 * channels)).as_ref(),
                     2880 * channels,
                 )
                 .expect("couldnt' encode audio"),
-            uuid: player_uuid.clone(),
-            channels: channels as u16,
-        }));
+            player_uuid.clone(),
+            channels as u16,
+            network_time.now(),
+        ));
+        for peer in listeners.iter() {
+            voice_chat_socket.send_msg_unreliable(*peer, &message);
+        }
     }
 }
 
+/// Decodes one frame of a peer's voice. Anything that isn't a frame we sent
+/// ourselves is an error rather than a panic, the data comes off the network.
+pub fn decode_voice(
+    decoder: &mut MicrophoneDecoder,
+    msg: &VoiceMsg,
+) -> Result<SamplesBuffer<f32>, NetworkErrorKind> {
+    let (decoder, mut output) = match msg.channels {
+        1 => (&mut decoder.channels_1_decoder, vec![0.0; 2880]),
+        2 => (&mut decoder.channels_2_decoder, vec![0.0; 2880 * 2]),
+        channels => return Err(NetworkErrorKind::VoiceChannels(channels)),
+    };
+    decoder
+        .decode_float(&msg.data, &mut output, false)
+        .map_err(NetworkErrorKind::Voice)?;
+    Ok(SamplesBuffer::new(msg.channels, 48_000, output))
+}
+
 fn rec_voice_msg(
     mut event_reader: EventReader<VoiceMsg>,
     mut microphone_decoder: NonSendMut<MicrophoneDecoder>,
-    mut players: Query<(&PlayerUuid, &mut SpatialAudioSink), With<ExternalPlayer>>,
+    network_time: Res<NetworkTime>,
+    player_index: Res<PlayerIndex>,
+    registry: Res<PeerRegistry>,
+    mut players: Query<&mut SpatialAudioSink, With<ExternalPlayer>>,
+    mut errors: EventWriter<NetworkError>,
 ) {
     for event in event_reader.read() {
-        let uuid = &event.uuid;
-        let audio = &event.data;
-        let channels = event.channels;
+        if network_time
+            .age(event.sent_at)
+            .is_some_and(|age| age > MAX_VOICE_AGE)
+        {
+            continue;
+        }
 
-        for (id, audio_sink) in players.iter_mut() {
-            println!("awa");
-            if *id != *uuid {
-                continue;
-            }
-            println!("adding audio");
-            let mut output1 = [0.0; 2880];
-            let mut output2 = [0.0; 2880 * 2];
-
-            match channels {
-                1 => {
-                    microphone_decoder
-                        .channels_1_decoder
-                        .decode_float(audio, &mut output1, false)
-                        .expect("unable to decode audio");
-                }
-                2 => {
-                    microphone_decoder
-                        .channels_2_decoder
-                        .decode_float(audio, &mut output2, false)
-                        .expect("unable to decode audio");
-                }
-                _ => panic!("wrong number of audio channels for decoding"),
-            };
-            match channels {
-                1 => {
-                    audio_sink
-                        .sink
-                        .append(rodio::buffer::SamplesBuffer::new(channels, 48_000, output1));
-                }
-                2 => {
-                    audio_sink
-                        .sink
-                        .append(rodio::buffer::SamplesBuffer::new(channels, 48_000, output2));
+        let Some(entity) = player_index.get(&event.uuid) else {
+            continue;
+        };
+        let Ok(audio_sink) = players.get_mut(entity) else {
+            continue;
+        };
+        let samples = match decode_voice(&mut microphone_decoder, event) {
+            Ok(samples) => samples,
+            Err(kind) => {
+                // counted and rate limited like any other bad packet
+                if let Some(peer) = registry.peer(&event.uuid) {
+                    errors.send(NetworkError { peer, kind });
                 }
-                _ => panic!("impossible"),
+                continue;
             }
-            audio_sink.sink.play();
-            audio_sink.sink.set_volume(1.0);
-        }
+        };
+        audio_sink.sink.append(samples);
+        audio_sink.sink.play();
+        audio_sink.sink.set_volume(1.0);
     }
 }
 
@@ -177,3 +234,39 @@ fn bad_jitter_buffer(mut spatial_audio_sinks: Query<&mut SpatialAudioSink, With<
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::test_app::TestRng;
+
+    #[test]
+    fn decodes_what_we_encode() {
+        let mut encoder = Encoder::new(48_000, Channels::Mono, Application::Voip).unwrap();
+        let data = encoder.encode_vec_float(&[0.25; 2880], 2880).unwrap();
+        let msg = VoiceMsg::new(data, PlayerUuid("player".to_string()), 1, 0.0);
+        let samples = decode_voice(&mut MicrophoneDecoder::new(), &msg).unwrap();
+        assert_eq!(samples.count(), 2880);
+    }
+
+    #[test]
+    fn random_voice_never_panics() {
+        let mut rng = TestRng::new(4);
+        let mut decoder = MicrophoneDecoder::new();
+        for _ in 0..2000 {
+            let len = rng.below(1500);
+            let channels = rng.below(4) as u16;
+            let msg = VoiceMsg::new(
+                rng.bytes(len),
+                PlayerUuid("player".to_string()),
+                channels,
+                0.0,
+            );
+            let decoded = decode_voice(&mut decoder, &msg);
+            // plenty of garbage happens to be valid opus, it just mustn't panic
+            if !matches!(channels, 1 | 2) {
+                assert!(matches!(decoded, Err(NetworkErrorKind::VoiceChannels(c)) if c == channels));
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! The signaling server on a random port, with two native peers finding each
+//! other through it.
+
+use bevy::prelude::*;
+use bevy_matchbox::prelude::{MatchboxSocket, PeerId, SingleChannel};
+use p2pvr::networking::signaling::{LocalSignalingServer, SignalingServerPlugin};
+use std::time::{Duration, Instant};
+
+fn server(port: u16) -> App {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, SignalingServerPlugin { port }));
+    app
+}
+
+/// Steps the server and both sockets until `done` or the test times out.
+fn run_until(
+    server: &mut App,
+    peers: &mut [MatchboxSocket<SingleChannel>],
+    what: &str,
+    mut done: impl FnMut(&mut [MatchboxSocket<SingleChannel>]) -> bool,
+) {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        server.update();
+        for peer in peers.iter_mut() {
+            peer.update_peers();
+        }
+        if done(peers) {
+            return;
+        }
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn two_native_peers_meet() {
+    let mut server = server(0);
+    let url = server.world().resource::<LocalSignalingServer>().url();
+    assert_ne!(
+        server
+            .world()
+            .resource::<LocalSignalingServer>()
+            .addr()
+            .port(),
+        0
+    );
+
+    let room = format!("{url}/signaling-test");
+    let mut peers = [
+        MatchboxSocket::new_reliable(&room),
+        MatchboxSocket::new_reliable(&room),
+    ];
+    run_until(&mut server, &mut peers, "the peers to connect", |peers| {
+        peers
+            .iter_mut()
+            .all(|peer| peer.id().is_some() && peer.connected_peers().count() == 1)
+    });
+    let ids = peers
+        .iter_mut()
+        .map(|peer| peer.id().unwrap())
+        .collect::<Vec<PeerId>>();
+    assert_eq!(peers[0].connected_peers().collect::<Vec<_>>(), vec![ids[1]]);
+    assert_eq!(peers[1].connected_peers().collect::<Vec<_>>(), vec![ids[0]]);
+
+    peers[0].send(b"hello".to_vec().into_boxed_slice(), ids[1]);
+    let mut received = vec![];
+    run_until(&mut server, &mut peers, "the packet", |peers| {
+        received.extend(peers[1].receive());
+        !received.is_empty()
+    });
+    assert_eq!(received[0].0, ids[0]);
+    assert_eq!(&*received[0].1, b"hello");
+}
+
+#[test]
+fn taken_port_disables_the_server() {
+    let first = server(0);
+    let port = first
+        .world()
+        .resource::<LocalSignalingServer>()
+        .addr()
+        .port();
+    let second = server(port);
+    assert!(!second.world().contains_resource::<LocalSignalingServer>());
+}